@@ -2,6 +2,8 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
@@ -13,7 +15,10 @@ use indicatif::{ProgressBar, ProgressStyle};
 use log::info;
 use vfs::{PhysicalFS, VfsPath};
 
-use mqpkg::{Config, Installer, InstallerError, PackageSpecifier, SolverError};
+use mqpkg::{
+    Config, Installer, InstallerBuilder, InstallerError, Lockfile, PackageName, PackageSpecifier,
+    SolverError, UpgradeStrategy,
+};
 
 use crate::progress::SuspendableBars;
 
@@ -32,6 +37,12 @@ struct Cli {
     #[clap(global = true, short, long)]
     target: Option<Utf8PathBuf>,
 
+    #[clap(global = true, short, long)]
+    profile: Option<String>,
+
+    #[clap(global = true, long)]
+    warnings_as_errors: bool,
+
     #[clap(subcommand)]
     command: Commands,
 }
@@ -39,11 +50,87 @@ struct Cli {
 #[derive(Debug, Subcommand)]
 enum Commands {
     Install {
+        #[clap(required_unless_present_any = &["locked", "archives"])]
+        packages: Vec<String>,
+
+        // Install a local package archive, which can be repeated.
+        #[clap(long = "archive", value_name = "PATH")]
+        archives: Vec<String>,
+
+        // Install exactly what the lockfile has locked, without resolving.
+        #[clap(
+            long,
+            conflicts_with_all = &["packages", "archives", "only_deps", "no_deps", "best_effort"]
+        )]
+        locked: bool,
+
+        #[clap(long)]
+        no_recommends: bool,
+
+        // Install only what the packages depend on, not the packages themselves.
+        #[clap(long)]
+        only_deps: bool,
+
+        // Install only the packages themselves, ignoring their dependencies.
+        #[clap(long, conflicts_with = "only_deps")]
+        no_deps: bool,
+
+        // Allow pre-releases, even where no constraint asks for one.
+        #[clap(long)]
+        pre: bool,
+
+        // Like --pre, but only for the given package, which can be repeated.
+        #[clap(long = "pre-for", value_name = "PACKAGE")]
+        pre_for: Vec<String>,
+
+        // Install whatever packages can be installed, rather than all or none.
+        #[clap(long, conflicts_with = "only_deps")]
+        best_effort: bool,
+
+        // Metadata to keep alongside the requested packages, as key=value.
+        #[clap(long = "meta")]
+        metadata: Vec<String>,
+    },
+    // Resolves what's requested, and locks exactly what it resolved to.
+    Lock {},
+    // Lists the requested packages, along with any metadata they have.
+    List {},
+    // Lists every transaction that has been committed, oldest first.
+    History {},
+    // Returns to how things were once the given transaction had committed.
+    Rollback {
+        id: u64,
+    },
+    // Reports anything about our configuration that looks like a mistake.
+    Doctor {},
+    // Lists every profile, or creates a new one.
+    Profiles {
+        #[clap(long)]
+        create: Option<String>,
+    },
+    // Checks every release in our repositories for problems.
+    RepoCheck {
+        // Also download every release, to check it's reachable and its digest.
+        #[clap(long)]
+        network: bool,
+    },
+    // Removes anything from our cache that would never be used again.
+    Gc {},
+    // Throws away a transaction that was interrupted while committing, instead
+    // of finishing it, for when finishing it keeps failing.
+    Abort {},
+    Uninstall {
         #[clap(required = true)]
-        packages: Vec<PackageSpecifier>,
+        packages: Vec<String>,
+    },
+    // Upgrades the given packages, or everything that's requested.
+    Upgrade {
+        packages: Vec<String>,
+
+        // Also upgrade everything the packages depend on, not just what has to be.
+        #[clap(long)]
+        eager: bool,
     },
-    Uninstall {},
-    Upgrade {},
 }
 
 fn main() -> Result<()> {
@@ -75,8 +162,17 @@ fn main() -> Result<()> {
     let fs: VfsPath = PhysicalFS::new(PathBuf::from(&root)).into();
     let config =
         Config::load(&fs).with_context(|| format!("invalid target directory '{}'", root))?;
-    let mut pkg = Installer::new(config, fs, root.as_str())
+    let mut pkg: Installer<_> = InstallerBuilder::new(config, fs, root.as_str())
+        .with_root(PathBuf::from(&root))
+        .build()
         .with_context(|| format!("could not initialize in '{}'", root))?;
+    if cli.warnings_as_errors {
+        pkg.with_warnings_as_errors(true);
+    }
+    if let Some(profile) = &cli.profile {
+        pkg.with_profile(profile)
+            .with_context(|| format!("could not use profile '{}'", profile))?;
+    }
 
     // Setup our console callback
     if !cli.verbose.is_silent() {
@@ -101,22 +197,263 @@ fn main() -> Result<()> {
 
     // Actually dispatch to our commands.
     match &cli.command {
-        Commands::Install { packages } => match pkg.install(packages) {
-            Ok(v) => Ok(v),
-            Err(InstallerError::ResolverError(SolverError::NoSolution(mut dt))) => {
-                dt.collapse_no_versions();
-                Err(SolverError::humanized(
-                    "unable to resolve packages to a set that satisfies all requirements",
-                    *dt,
-                )
-                .into())
+        Commands::Install {
+            packages,
+            archives,
+            locked,
+            no_recommends,
+            only_deps,
+            no_deps,
+            pre,
+            pre_for,
+            best_effort,
+            metadata,
+        } => {
+            if *locked {
+                let path = root.join(Lockfile::filename());
+                let file =
+                    File::open(&path).with_context(|| format!("could not read '{}'", path))?;
+                let lockfile =
+                    Lockfile::load(file).with_context(|| format!("invalid lockfile '{}'", path))?;
+                pkg.install_locked(&lockfile)?;
+                return Ok(());
+            }
+
+            pkg.with_recommends(!no_recommends);
+            pkg.with_no_deps(*no_deps);
+            if *pre {
+                pkg.with_prereleases(true);
+            }
+            pkg.with_prereleases_for(&names(pre_for)?);
+            let metadata = parse_metadata(metadata)?;
+            let mut packages = specifiers(packages)?;
+            for archive in archives {
+                packages.push(
+                    pkg.add_archive(archive)
+                        .with_context(|| format!("could not use package archive '{}'", archive))?,
+                );
+            }
+            let packages: Vec<PackageSpecifier> = packages
+                .into_iter()
+                .map(|package| package.with_metadata(metadata.clone()))
+                .collect();
+            if *best_effort {
+                let partial = pkg.install_best_effort(&packages)?;
+                for failure in partial.failures.iter() {
+                    term.write_line(&format!("could not install {}", failure))?;
+                }
+
+                return match partial.failures.is_empty() {
+                    true => Ok(()),
+                    false => Err(anyhow!(
+                        "{} packages could not be installed",
+                        partial.failures.len()
+                    )),
+                };
+            }
+
+            let result = match *only_deps {
+                true => pkg.install_dependencies(&packages),
+                false => pkg.install(&packages),
+            };
+            match result {
+                Ok(_) => Ok(()),
+                Err(InstallerError::ResolverError(SolverError::NoSolution(dt))) => {
+                    // Suggesting what to loosen is only a hint, so if we can't work
+                    // that out, we still want to report why resolving failed.
+                    for relaxation in pkg.relaxations(&packages).unwrap_or_default() {
+                        term.write_line(&format!(
+                            "resolution would succeed if you {}",
+                            relaxation
+                        ))?;
+                    }
+                    let err = SolverError::NoSolution(dt);
+                    if let Some(explanation) = err.explanation() {
+                        term.write_str(&explanation.to_string())?;
+                    }
+                    Err(anyhow!(
+                        "unable to resolve packages to a set that satisfies all requirements"
+                    ))
+                }
+                Err(err) => Err(err.into()),
             }
-            Err(err) => Err(err.into()),
-        },
+        }
+        Commands::Uninstall { packages } => {
+            pkg.uninstall(&names(packages)?)?;
+
+            Ok(())
+        }
+        Commands::Upgrade { packages, eager } => {
+            let packages = names(packages)?;
+            let strategy = match *eager {
+                true => UpgradeStrategy::Eager,
+                false => UpgradeStrategy::OnlyIfNeeded,
+            };
+            pkg.upgrade(&packages, strategy)?;
+
+            Ok(())
+        }
+        Commands::Lock {} => {
+            // Resolve before touching anything, and write to a temporary file that
+            // replaces the lockfile in one go, so a failure never leaves a
+            // truncated lockfile behind.
+            let lockfile = pkg.lock()?;
+            let path = root.join(Lockfile::filename());
+            let partial = root.join(format!("{}.partial", Lockfile::filename()));
+            let file =
+                File::create(&partial).with_context(|| format!("could not write '{}'", partial))?;
+            lockfile.save(file)?;
+            std::fs::rename(&partial, &path)
+                .with_context(|| format!("could not write '{}'", path))?;
+
+            Ok(())
+        }
+        Commands::List {} => {
+            for req in pkg.export()?.requests {
+                let mut line = format!("{} {}", req.name, req.version);
+                if let Some(repository) = &req.repository {
+                    line.push_str(&format!(" from {}", repository));
+                }
+                for (key, value) in req.metadata.iter() {
+                    line.push_str(&format!(" {}={}", key, value));
+                }
+                term.write_line(&line)?;
+            }
+
+            Ok(())
+        }
+        Commands::History {} => {
+            for entry in pkg.history()? {
+                let mut line = format!("{} {}", entry.id, entry.operation);
+                if !entry.packages.is_empty() {
+                    let packages: Vec<String> =
+                        entry.packages.iter().map(|p| p.to_string()).collect();
+                    line.push_str(&format!(" {}", packages.join(", ")));
+                }
+                let changes = &entry.changes;
+                for (sign, packages) in [("+", &changes.added), ("-", &changes.removed)] {
+                    for package in packages.iter() {
+                        line.push_str(&format!(" {}{} {}", sign, package.name, package.version));
+                    }
+                }
+                for change in changes
+                    .upgraded
+                    .iter()
+                    .chain(changes.downgraded.iter())
+                    .chain(changes.moved.iter())
+                {
+                    line.push_str(&format!(
+                        " ~{} {} -> {}",
+                        change.name, change.from.version, change.to.version
+                    ));
+                }
+                term.write_line(&line)?;
+            }
+
+            Ok(())
+        }
+        Commands::Rollback { id } => {
+            pkg.rollback(*id)?;
+
+            Ok(())
+        }
+        Commands::Profiles { create } => {
+            if let Some(profile) = create {
+                pkg.create_profile(profile)
+                    .with_context(|| format!("could not create profile '{}'", profile))?;
+                return Ok(());
+            }
+
+            for profile in pkg.profiles()? {
+                term.write_line(&profile)?;
+            }
+
+            Ok(())
+        }
+        Commands::Doctor {} => {
+            let shadowed = pkg.shadowed()?;
+            if !shadowed.is_empty() {
+                term.write_line("packages provided by more than one repository:")?;
+            }
+            for package in shadowed.iter() {
+                term.write_line(&format!("  {} (using {})", package.name, package.preferred))?;
+                for provider in package.providers.iter() {
+                    let versions: Vec<String> =
+                        provider.versions.iter().map(|v| v.to_string()).collect();
+                    term.write_line(&format!(
+                        "    {}: {}",
+                        provider.repository,
+                        versions.join(", ")
+                    ))?;
+                }
+            }
+
+            Ok(())
+        }
+        Commands::RepoCheck { network } => {
+            let issues = pkg.verify(*network)?;
+            for issue in issues.iter() {
+                term.write_line(&issue.to_string())?;
+            }
+
+            match issues.is_empty() {
+                true => Ok(()),
+                false => Err(anyhow!("found {} problems", issues.len())),
+            }
+        }
+        Commands::Gc {} => {
+            let cleanup = pkg.gc()?;
+            term.write_line(&format!(
+                "removed {} files, freeing {} bytes",
+                cleanup.files, cleanup.bytes
+            ))?;
+
+            Ok(())
+        }
+        Commands::Abort {} => {
+            match pkg.abort()? {
+                true => term.write_line("discarded an interrupted transaction")?,
+                false => term.write_line("no interrupted transaction to discard")?,
+            }
+
+            Ok(())
+        }
         _ => Err(anyhow!("command not implemented")),
     }
 }
 
+fn specifiers(packages: &[String]) -> Result<Vec<PackageSpecifier>> {
+    packages
+        .iter()
+        .map(|package| {
+            package
+                .parse()
+                .with_context(|| format!("invalid package specifier '{}'", package))
+        })
+        .collect()
+}
+
+fn names(packages: &[String]) -> Result<Vec<PackageName>> {
+    packages
+        .iter()
+        .map(|package| {
+            package
+                .parse()
+                .with_context(|| format!("invalid package name '{}'", package))
+        })
+        .collect()
+}
+
+fn parse_metadata(values: &[String]) -> Result<BTreeMap<String, String>> {
+    values
+        .iter()
+        .map(|value| match value.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(anyhow!("invalid metadata '{}', expected key=value", value)),
+        })
+        .collect()
+}
+
 fn canonicalize<P: AsRef<Path>>(path: P) -> Result<Utf8PathBuf> {
     Ok(Utf8PathBuf::try_from(dunce::canonicalize(path)?)?)
 }
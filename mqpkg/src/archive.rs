@@ -0,0 +1,440 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use log::{info, trace};
+use semver::Version;
+use serde::Deserialize;
+use tar::EntryType;
+use vfs::{PhysicalFS, VfsPath};
+
+use crate::errors::ArchiveError;
+use crate::pkgdb::{Installed, InstalledFile};
+use crate::resolver::{Candidate, StaticDependencies};
+use crate::types::{
+    deserialize_dependencies, Digest, DigestAlgorithm, PackageName, VersionConstraint,
+};
+
+const LOGNAME: &str = "mqpkg::archive";
+
+// The file, at the root of the archive, that describes the package.
+const METADATA_FILENAME: &str = "metadata.json";
+
+// The mode that a copied file gets, when what it was copied from wasn't a file
+// that we extracted ourselves.
+const DEFAULT_MODE: u32 = 0o644;
+
+type Result<T, E = ArchiveError> = core::result::Result<T, E>;
+
+// A package archive is a gzip compressed tarball, which contains the package's
+// files along with a metadata.json at the root of the archive, which looks
+// like:
+//
+//   {
+//     "name": "foo",
+//     "version": "1.2.0",
+//     "dependencies": {"bar": ">=1.0"}
+//   }
+//
+// The dependencies are resolved against our configured repositories, just like
+// the dependencies of any other package.
+#[derive(Deserialize, Debug, Clone)]
+struct Metadata {
+    name: PackageName,
+    version: Version,
+    #[serde(default, deserialize_with = "deserialize_dependencies")]
+    dependencies: HashMap<PackageName, VersionConstraint>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Archive {
+    pub(crate) name: PackageName,
+    pub(crate) version: Version,
+    dependencies: HashMap<PackageName, VersionConstraint>,
+    // Where the archive was read from, and what it hashed to then, so that it can
+    // be found again later on, and so we can tell if it's changed since.
+    pub(crate) path: PathBuf,
+    pub(crate) digest: Digest,
+}
+
+impl Archive {
+    pub(crate) fn open(path: &Path) -> Result<Archive> {
+        info!(target: LOGNAME, "reading package archive {:?}", path);
+
+        let display = path.display().to_string();
+        let io_error = |source| ArchiveError::IoError {
+            path: display.clone(),
+            source,
+        };
+        let path = path.canonicalize().map_err(io_error)?;
+        let mut writer = DigestAlgorithm::Sha256.writer(io::sink());
+        writer
+            .hash_existing(File::open(&path).map_err(io_error)?)
+            .map_err(io_error)?;
+        let digest = writer.finish().map_err(io_error)?;
+
+        let file = File::open(&path).map_err(io_error)?;
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+        let entries = archive
+            .entries()
+            .map_err(|source| ArchiveError::Malformed {
+                path: display.clone(),
+                source,
+            })?;
+
+        for entry in entries {
+            let mut entry = entry.map_err(|source| ArchiveError::Malformed {
+                path: display.clone(),
+                source,
+            })?;
+            let is_metadata = entry
+                .path()
+                .map(|p| p.as_os_str() == METADATA_FILENAME)
+                .unwrap_or(false);
+
+            if is_metadata {
+                let mut content = Vec::new();
+                entry
+                    .read_to_end(&mut content)
+                    .map_err(|source| ArchiveError::Malformed {
+                        path: display.clone(),
+                        source,
+                    })?;
+
+                let metadata: Metadata = serde_json::from_slice(&content).map_err(|source| {
+                    ArchiveError::InvalidMetadata {
+                        path: display,
+                        source,
+                    }
+                })?;
+
+                return Ok(Archive {
+                    name: metadata.name,
+                    version: metadata.version,
+                    dependencies: metadata.dependencies,
+                    path,
+                    digest,
+                });
+            }
+        }
+
+        Err(ArchiveError::NoMetadata { path: display })
+    }
+
+    // The archive itself, for extracting it just like one we'd downloaded.
+    pub(crate) fn file(&self) -> Result<VfsPath> {
+        let filename = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("/"));
+        let fs: VfsPath = PhysicalFS::new(dir.to_path_buf()).into();
+
+        Ok(fs.join(&filename)?)
+    }
+
+    pub(crate) fn candidate(&self, id: u64) -> Candidate {
+        Candidate::internal(
+            &self.version,
+            id,
+            Box::new(StaticDependencies::new(self.dependencies.clone())),
+        )
+    }
+}
+
+// Unpacks a package archive into target, returning every file that it installed,
+// along with every directory that those were installed into. Archives come from
+// our repositories, so they aren't trusted, and any entry that would end up
+// outside of target, whether through an absolute path, a .. component, or a
+// link pointing out of it, fails the whole extraction. Links can't be
+// represented in target, so each one that stays inside of it is extracted as a
+// copy of whatever it links to instead.
+pub(crate) fn extract<R: Read>(reader: R, display: &str, target: &VfsPath) -> Result<Installed> {
+    let malformed = |source| ArchiveError::Malformed {
+        path: display.to_string(),
+        source,
+    };
+
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut files = Vec::new();
+    let mut directories = BTreeSet::new();
+    let mut links = Vec::new();
+    for entry in archive.entries().map_err(malformed)? {
+        let mut entry = entry.map_err(malformed)?;
+        let path = entry.path().map_err(malformed)?.into_owned();
+        let name = path.display().to_string();
+        let unsafe_path = || ArchiveError::UnsafePath {
+            path: display.to_string(),
+            entry: name.clone(),
+        };
+
+        let parts = relative_parts(&path).ok_or_else(unsafe_path)?;
+        if parts.is_empty() || parts == [METADATA_FILENAME] {
+            continue;
+        }
+
+        match entry.header().entry_type() {
+            EntryType::Directory => {
+                target.join(&parts.join("/"))?.create_dir_all()?;
+                directories.extend(ancestors(&parts, parts.len()));
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                let relative = parts.join("/");
+                let dest = target.join(&relative)?;
+                if let Some((parent, _)) = relative.rsplit_once('/') {
+                    target.join(parent)?.create_dir_all()?;
+                }
+                directories.extend(ancestors(&parts, parts.len() - 1));
+
+                trace!(target: LOGNAME, "extracting {} from {}", relative, display);
+                let mut writer = DigestAlgorithm::Sha256.writer(dest.create_file()?);
+                io::copy(&mut entry, &mut writer).map_err(malformed)?;
+                files.push(InstalledFile {
+                    path: relative,
+                    digest: writer.finish().map_err(malformed)?,
+                    mode: entry.header().mode().map_err(malformed)?,
+                });
+            }
+            EntryType::Symlink | EntryType::Link => {
+                let link = entry
+                    .link_name()
+                    .map_err(malformed)?
+                    .ok_or_else(unsafe_path)?
+                    .into_owned();
+
+                // Symlinks are relative to the directory that they're in, but hard
+                // links are relative to the root of the archive.
+                let base = match entry.header().entry_type() {
+                    EntryType::Symlink => &parts[..parts.len() - 1],
+                    _ => &[][..],
+                };
+                let resolved = resolve_link(base, &link).ok_or_else(unsafe_path)?;
+
+                // A link to a directory that it's inside of would have to be a copy
+                // of itself, over and over again.
+                if resolved.is_empty() || parts.starts_with(&resolved) {
+                    return Err(ArchiveError::UnsupportedEntry {
+                        path: display.to_string(),
+                        entry: name,
+                    });
+                }
+
+                directories.extend(ancestors(&parts, parts.len() - 1));
+                links.push((parts.join("/"), resolved.join("/"), name));
+            }
+            _ => {
+                return Err(ArchiveError::UnsupportedEntry {
+                    path: display.to_string(),
+                    entry: name,
+                })
+            }
+        }
+    }
+
+    // A link can come before whatever it links to, or link to another link, so
+    // they're only copied once everything else has been extracted, and each
+    // only once what it links to is complete.
+    while !links.is_empty() {
+        let (ready, pending): (Vec<_>, Vec<_>) =
+            links.iter().cloned().partition(|(_, linked, _)| {
+                !links
+                    .iter()
+                    .any(|(path, _, _)| path == linked || path.starts_with(&format!("{linked}/")))
+            });
+        if ready.is_empty() {
+            let (_, _, name) = links.swap_remove(0);
+            return Err(ArchiveError::UnsupportedEntry {
+                path: display.to_string(),
+                entry: name,
+            });
+        }
+
+        for (path, linked, name) in ready {
+            let source = target.join(&linked)?;
+            if !source.exists()? {
+                return Err(ArchiveError::BrokenLink {
+                    path: display.to_string(),
+                    entry: name,
+                });
+            }
+
+            trace!(target: LOGNAME, "copying {} to {} from {}", linked, path, display);
+            copy(
+                target,
+                display,
+                &linked,
+                &path,
+                &mut files,
+                &mut directories,
+            )?;
+        }
+        links = pending;
+    }
+
+    Ok(Installed {
+        files,
+        directories: directories.into_iter().collect(),
+    })
+}
+
+// Copies from, whether it's a file or a directory, to somewhere else within
+// target, recording everything that gets copied, with the same mode as what it
+// was copied from.
+fn copy(
+    target: &VfsPath,
+    display: &str,
+    from: &str,
+    to: &str,
+    files: &mut Vec<InstalledFile>,
+    directories: &mut BTreeSet<String>,
+) -> Result<()> {
+    let source = target.join(from)?;
+    let dest = target.join(to)?;
+    if source.is_dir()? {
+        dest.create_dir_all()?;
+        directories.insert(to.to_string());
+        for child in source.read_dir()? {
+            let name = child.filename();
+            let (from, to) = (format!("{from}/{name}"), format!("{to}/{name}"));
+            copy(target, display, &from, &to, files, directories)?;
+        }
+        return Ok(());
+    }
+
+    let io_error = |source| ArchiveError::IoError {
+        path: display.to_string(),
+        source,
+    };
+    let mode = files
+        .iter()
+        .find(|f| f.path == from)
+        .map(|f| f.mode)
+        .unwrap_or(DEFAULT_MODE);
+    if let Some((parent, _)) = to.rsplit_once('/') {
+        target.join(parent)?.create_dir_all()?;
+    }
+    let mut writer = DigestAlgorithm::Sha256.writer(dest.create_file()?);
+    io::copy(&mut source.open_file()?, &mut writer).map_err(io_error)?;
+    files.push(InstalledFile {
+        path: to.to_string(),
+        digest: writer.finish().map_err(io_error)?,
+        mode,
+    });
+
+    Ok(())
+}
+
+// Every directory that leads up to, and includes, the first len of parts.
+fn ancestors(parts: &[String], len: usize) -> Vec<String> {
+    (1..=len).map(|n| parts[..n].join("/")).collect()
+}
+
+// The parts of path, as long as it's relative and never steps outside of where
+// it starts from.
+fn relative_parts(path: &Path) -> Option<Vec<String>> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?.to_string()),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(parts)
+}
+
+// Where link ends up, starting from base, as long as that's still within the
+// archive. Unlike a path, a link can step back out of a directory it went into.
+fn resolve_link(base: &[String], link: &Path) -> Option<Vec<String>> {
+    let mut parts = base.to_vec();
+    for component in link.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?.to_string()),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                parts.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::Header;
+    use vfs::MemoryFS;
+
+    use super::*;
+
+    fn header(entry_type: EntryType, mode: u32) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header
+    }
+
+    // A package archive with an executable, and links to it, and to where it is.
+    fn archive(link: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let mut tool = header(EntryType::Regular, 0o755);
+        tool.set_size(4);
+        builder
+            .append_data(&mut tool, "bin/tool", &b"tool"[..])
+            .unwrap();
+        builder
+            .append_link(&mut header(EntryType::Symlink, 0o777), "tool", link)
+            .unwrap();
+        builder
+            .append_link(&mut header(EntryType::Link, 0o644), "copy", "bin/tool")
+            .unwrap();
+        builder
+            .append_link(&mut header(EntryType::Symlink, 0o777), "lib/bin", "../bin")
+            .unwrap();
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn extracts_links_as_copies() {
+        let target = VfsPath::new(MemoryFS::new());
+        let installed = extract(&archive("bin/tool")[..], "test", &target).unwrap();
+
+        let mut files: Vec<(&str, u32)> = installed
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.mode))
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                ("bin/tool", 0o755),
+                ("copy", 0o755),
+                ("lib/bin/tool", 0o755),
+                ("tool", 0o755)
+            ]
+        );
+        assert_eq!(installed.directories, vec!["bin", "lib", "lib/bin"]);
+    }
+
+    #[test]
+    fn rejects_links_outside_of_target() {
+        let target = VfsPath::new(MemoryFS::new());
+        assert!(matches!(
+            extract(&archive("../tool")[..], "test", &target),
+            Err(ArchiveError::UnsafePath { .. })
+        ));
+        assert!(matches!(
+            extract(&archive("missing")[..], "test", &target),
+            Err(ArchiveError::BrokenLink { .. })
+        ));
+    }
+}
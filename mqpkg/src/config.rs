@@ -2,27 +2,79 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
+use std::collections::HashMap;
+use std::io::Read;
 use std::str::FromStr;
+use std::time::Duration;
 
 use camino::Utf8PathBuf;
 use log::info;
+use semver::Version;
 use serde::Deserialize;
 use serde_with::{serde_as, DisplayFromStr, PickFirst};
 use url::Url;
 use vfs::VfsPath;
 
 use crate::errors::ConfigError;
+use crate::types::{PackageName, VersionConstraint};
+use crate::yaml;
 
 const LOGNAME: &str = "mqpkg::config";
 
 const CONFIG_FILENAME: &str = "mqpkg.yml";
 
+const DEFAULT_MAX_INDEX_SIZE: u64 = 256 * 1024 * 1024;
+const DEFAULT_TIMEOUT: u64 = 30;
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_MAX_RESOLVE_DECISIONS: u64 = 1_000_000;
+const DEFAULT_MAX_ALIAS_EXPANSION: u64 = 100_000;
+const DEFAULT_ALLOWED_SCHEMES: &[&str] = &["https", "file", "git+ssh"];
+
 type Result<T, E = ConfigError> = core::result::Result<T, E>;
 
 #[derive(Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
 pub(crate) struct Repository {
     pub(crate) name: String,
     pub(crate) url: Url,
+    #[serde(default)]
+    pub(crate) mirrors: Vec<Url>,
+    #[serde(default)]
+    pub(crate) public_key: Option<String>,
+    // Only used for git repositories, where it's the path to the index file
+    // within the git repository.
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+    // Parse each package's releases only when they're needed, for huge indices.
+    #[serde(default)]
+    pub(crate) lazy: bool,
+    // Only used for http repositories, where we fetch just what's changed since
+    // our cached copy of the index, verified against the index's digest.
+    #[serde(default)]
+    pub(crate) delta: bool,
+    // Only used for http repositories, where if fetching fails, we use whatever
+    // copy of the index we have cached instead, however old it is.
+    #[serde(default)]
+    pub(crate) allow_stale_on_error: bool,
+    // Glob patterns that limit which of this repository's packages we use, so
+    // that a project can use a curated view of a large shared repository.
+    #[serde(default)]
+    pub(crate) include: Vec<String>,
+    #[serde(default)]
+    pub(crate) exclude: Vec<String>,
+}
+
+impl Repository {
+    // Whether we use package from this repository, which we do if it matches
+    // any include pattern (or there aren't any), and no exclude pattern. Names
+    // are case insensitive, so the patterns are too.
+    pub(crate) fn exposes(&self, package: &PackageName) -> bool {
+        let name = package.to_string().to_ascii_lowercase();
+        let matches = |pattern: &String| glob_matches(&pattern.to_ascii_lowercase(), &name);
+
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
 }
 
 impl FromStr for Repository {
@@ -32,8 +84,71 @@ impl FromStr for Repository {
         let name = s.to_string();
         let url = Url::from_str(s).map_err(|source| ConfigError::InvalidURL { source })?;
 
-        Ok(Repository { name, url })
+        Ok(Repository {
+            name,
+            url,
+            mirrors: Vec::new(),
+            public_key: None,
+            path: None,
+            lazy: false,
+            delta: false,
+            allow_stale_on_error: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        })
+    }
+}
+
+// A minimal glob, where * matches any run of characters, and ? matches any
+// single character, which is all that package names need.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Where the last * was in pattern, and where in name it started matching, so
+    // that when we fail, we can backtrack and have it match one more character.
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
     }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Level {
+    #[default]
+    Warn,
+    Error,
+}
+
+// What to do when more than one repository provides the same version of a
+// package, but they disagree about its dependencies.
+#[derive(Deserialize, Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ConflictStrategy {
+    #[default]
+    PreferPriority,
+    Strict,
 }
 
 #[serde_with::serde_as]
@@ -41,6 +156,74 @@ impl FromStr for Repository {
 pub struct Config {
     #[serde_as(as = "Vec<PickFirst<(_, DisplayFromStr)>>")]
     repositories: Vec<Repository>,
+
+    // The "house" repository, which wins whenever more than one repository provides
+    // the same version, and a request isn't pinned to one, even if it was defined
+    // later. Otherwise, repositories are preferred in the order they're defined.
+    #[serde(default)]
+    default_repository: Option<String>,
+
+    // The url schemes that repositories (and their mirrors) are allowed to use,
+    // which by default doesn't include anything that isn't encrypted.
+    #[serde(default = "default_allowed_schemes")]
+    allowed_schemes: Vec<String>,
+
+    #[serde(default = "default_max_index_size")]
+    max_index_size: u64,
+
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+
+    #[serde(default = "default_max_redirects")]
+    max_redirects: usize,
+
+    #[serde(default = "default_retries")]
+    retries: u32,
+
+    // Setting this to null removes the limit entirely.
+    #[serde(default = "default_max_resolve_decisions")]
+    max_resolve_decisions: Option<u64>,
+
+    #[serde(default)]
+    exclude_versions: HashMap<PackageName, Vec<Version>>,
+
+    #[serde(default)]
+    empty_repositories: Level,
+
+    // What to do when uninstalling a package that something else that's still
+    // requested depends on, which leaves it installed regardless.
+    #[serde(default = "default_still_required")]
+    still_required: Level,
+
+    #[serde(default)]
+    overrides: HashMap<PackageName, VersionConstraint>,
+
+    // Pre-releases are only ever picked when a constraint explicitly mentions one,
+    // unless they're allowed for every package, or for the packages listed.
+    #[serde(default)]
+    prereleases: bool,
+
+    #[serde(default)]
+    prerelease_packages: Vec<PackageName>,
+
+    #[serde(default)]
+    assertions: HashMap<PackageName, VersionConstraint>,
+
+    #[serde(default)]
+    conflict_strategy: ConflictStrategy,
+
+    #[serde(default)]
+    warnings_as_errors: bool,
+
+    // Paths, relative to the target directory, of read only state files that are
+    // layered underneath our own state, from lowest to highest precedence.
+    #[serde(default)]
+    state_layers: Vec<String>,
+
+    // Where cached data lives, either absolute or relative to the target directory,
+    // by default this is within the pkgdb.
+    #[serde(default)]
+    cache_dir: Option<String>,
 }
 
 impl Config {
@@ -49,6 +232,12 @@ impl Config {
     }
 
     pub fn load(root: &VfsPath) -> Result<Config> {
+        Config::load_with_alias_limit(root, DEFAULT_MAX_ALIAS_EXPANSION)
+    }
+
+    // Loads the config, refusing any config whose aliases would expand to more
+    // than limit nodes once they've all been resolved.
+    pub fn load_with_alias_limit(root: &VfsPath, limit: u64) -> Result<Config> {
         let filename = root
             .join(CONFIG_FILENAME)
             .map_err(|source| ConfigError::NoConfig { source })?;
@@ -57,11 +246,25 @@ impl Config {
             "loading config from {:?}",
             filename.as_str()
         );
-        let file = filename
+        let mut file = filename
             .open_file()
             .map_err(|source| ConfigError::NoConfig { source })?;
-        let config: Config = serde_yaml::from_reader(file)
-            .map_err(|source| ConfigError::InvalidConfig { source })?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .map_err(|source| ConfigError::ReadError { source })?;
+
+        if yaml::alias_expansion(&content) > limit {
+            return Err(ConfigError::TooManyAliases { limit });
+        }
+
+        let mut config: Config =
+            serde_yaml::from_str(&content).map_err(|source| ConfigError::InvalidConfig {
+                filename: CONFIG_FILENAME.to_string(),
+                location: source.location().map(|l| (l.line(), l.column())),
+                source,
+            })?;
+        config.check_schemes()?;
+        config.check_default_repository()?;
 
         Ok(config)
     }
@@ -88,7 +291,182 @@ impl Config {
 }
 
 impl Config {
+    fn check_schemes(&self) -> Result<()> {
+        for repo in self.repositories.iter() {
+            for url in std::iter::once(&repo.url).chain(repo.mirrors.iter()) {
+                if !self.allowed_schemes.iter().any(|s| s == url.scheme()) {
+                    return Err(ConfigError::DisallowedScheme {
+                        repository: repo.name.clone(),
+                        scheme: url.scheme().to_string(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_default_repository(&self) -> Result<()> {
+        if let Some(name) = &self.default_repository {
+            if !self.repositories.iter().any(|repo| &repo.name == name) {
+                return Err(ConfigError::UnknownDefaultRepository { name: name.clone() });
+            }
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn repositories(&self) -> &[Repository] {
         &self.repositories
     }
+
+    pub(crate) fn allowed_schemes(&self) -> &[String] {
+        &self.allowed_schemes
+    }
+
+    pub(crate) fn default_repository(&self) -> Option<&str> {
+        self.default_repository.as_deref()
+    }
+
+    pub(crate) fn max_index_size(&self) -> u64 {
+        self.max_index_size
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    pub(crate) fn max_redirects(&self) -> usize {
+        self.max_redirects
+    }
+
+    pub(crate) fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub(crate) fn max_resolve_decisions(&self) -> Option<u64> {
+        self.max_resolve_decisions
+    }
+
+    pub(crate) fn empty_repositories(&self) -> Level {
+        self.empty_repositories
+    }
+
+    pub(crate) fn still_required(&self) -> Level {
+        self.still_required
+    }
+
+    pub(crate) fn exclude_versions(&self) -> &HashMap<PackageName, Vec<Version>> {
+        &self.exclude_versions
+    }
+
+    pub(crate) fn warnings_as_errors(&self) -> bool {
+        self.warnings_as_errors
+    }
+
+    pub(crate) fn conflict_strategy(&self) -> ConflictStrategy {
+        self.conflict_strategy
+    }
+
+    pub(crate) fn overrides(&self) -> &HashMap<PackageName, VersionConstraint> {
+        &self.overrides
+    }
+
+    pub(crate) fn prereleases(&self) -> bool {
+        self.prereleases
+    }
+
+    pub(crate) fn prerelease_packages(&self) -> &[PackageName] {
+        &self.prerelease_packages
+    }
+
+    pub(crate) fn state_layers(&self) -> &[String] {
+        &self.state_layers
+    }
+
+    pub(crate) fn cache_dir(&self) -> Option<&str> {
+        self.cache_dir.as_deref()
+    }
+
+    pub(crate) fn assertions(&self) -> &HashMap<PackageName, VersionConstraint> {
+        &self.assertions
+    }
+}
+
+fn default_allowed_schemes() -> Vec<String> {
+    DEFAULT_ALLOWED_SCHEMES
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn default_max_index_size() -> u64 {
+    DEFAULT_MAX_INDEX_SIZE
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+fn default_max_redirects() -> usize {
+    DEFAULT_MAX_REDIRECTS
+}
+
+fn default_retries() -> u32 {
+    DEFAULT_RETRIES
+}
+
+fn default_max_resolve_decisions() -> Option<u64> {
+    Some(DEFAULT_MAX_RESOLVE_DECISIONS)
+}
+
+fn default_still_required() -> Level {
+    Level::Error
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use vfs::MemoryFS;
+
+    use super::*;
+
+    const ANCHORED: &str = "
+repositories:
+  - https://example.com/index.json
+exclude_versions:
+  foo: &bad [\"1.0.0\", \"1.0.1\"]
+  bar: *bad
+";
+
+    fn root(content: &str) -> VfsPath {
+        let root = VfsPath::new(MemoryFS::new());
+        root.join(CONFIG_FILENAME)
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+        root
+    }
+
+    #[test]
+    fn aliases_resolve() {
+        let config = Config::load(&root(ANCHORED)).unwrap();
+        let expected = vec![Version::new(1, 0, 0), Version::new(1, 0, 1)];
+        for name in ["foo", "bar"] {
+            let name = PackageName::new(name).unwrap();
+            assert_eq!(config.exclude_versions().get(&name), Some(&expected));
+        }
+    }
+
+    #[test]
+    fn alias_limit() {
+        assert!(Config::load_with_alias_limit(&root(ANCHORED), 3).is_ok());
+        assert!(matches!(
+            Config::load_with_alias_limit(&root(ANCHORED), 2),
+            Err(ConfigError::TooManyAliases { limit: 2 })
+        ));
+    }
 }
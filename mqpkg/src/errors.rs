@@ -5,7 +5,7 @@
 use thiserror::Error;
 
 use crate::resolver::{Candidate, DerivedResult};
-use crate::types::PackageName;
+use crate::types::{AssertionFailure, Digest, DigestAlgorithm, PackageName, VersionConstraint};
 
 #[derive(Error, Debug)]
 pub enum InstallerError {
@@ -15,8 +15,32 @@ pub enum InstallerError {
     #[error(transparent)]
     RepositoryError(#[from] RepositoryError),
 
+    #[error(transparent)]
+    ArchiveError(#[from] ArchiveError),
+
+    #[error("warnings were treated as errors: {}", .0.join("; "))]
+    Warnings(Vec<String>),
+
+    #[error("the deadline for this operation was exceeded")]
+    DeadlineExceeded,
+
     #[error("error attempting to resolve dependencies")]
     ResolverError(#[from] SolverError),
+
+    #[error(transparent)]
+    LockfileError(#[from] LockfileError),
+
+    #[error(
+        "{package} is still required by {}",
+        .dependents.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    StillRequired {
+        package: PackageName,
+        dependents: Vec<PackageName>,
+    },
+
+    #[error("no transaction {id} in our history")]
+    UnknownTransaction { id: u64 },
 }
 
 #[derive(Error, Debug)]
@@ -29,6 +53,9 @@ pub enum PackageNameError {
 
     #[error("names must contain only alphanumeric characters")]
     InvalidCharacter { name: String, character: String },
+
+    #[error("scoped names must look like @scope/name")]
+    InvalidScope { name: String },
 }
 
 #[derive(Error, Debug)]
@@ -36,11 +63,61 @@ pub enum PackageSpecifierError {
     #[error("specifier must have a package name")]
     NoPackageName,
 
+    #[error("specifier {specifier:?} must name the repository it is from")]
+    NoRepositoryName { specifier: String },
+
     #[error(transparent)]
     InvalidPackageName(#[from] PackageNameError),
 
+    #[error(
+        "invalid version {pin:?}, expected a version, a constraint, \"latest\", or \"stable\""
+    )]
+    InvalidPin {
+        pin: String,
+        source: VersionConstraintError,
+    },
+
     #[error(transparent)]
-    InvalidVersionRequirement(#[from] semver::Error),
+    InvalidVersionRequirement(#[from] VersionConstraintError),
+}
+
+#[derive(Error, Debug)]
+pub enum MarkerError {
+    #[error("unterminated string in marker {marker:?}")]
+    UnterminatedString { marker: String },
+
+    #[error("unexpected {token} in marker {marker:?}")]
+    UnexpectedToken { marker: String, token: String },
+
+    #[error("marker {marker:?} ended unexpectedly")]
+    UnexpectedEnd { marker: String },
+}
+
+#[derive(Error, Debug)]
+pub enum VersionConstraintError {
+    #[error("constraint {constraint:?} has an empty alternative")]
+    EmptyAlternative { constraint: String },
+
+    #[error("constraint {constraint:?} has an unknown token {token:?}")]
+    UnknownToken { constraint: String, token: String },
+
+    #[error("constraint {constraint:?} is not a valid hyphen range")]
+    InvalidHyphenRange { constraint: String },
+
+    #[error("constraint {constraint:?} has an operator {operator:?} without a version")]
+    DanglingOperator {
+        constraint: String,
+        operator: String,
+    },
+
+    #[error("constraint {constraint:?} must exclude a complete version")]
+    InvalidExclusion {
+        constraint: String,
+        source: semver::Error,
+    },
+
+    #[error(transparent)]
+    InvalidRequirement(#[from] semver::Error),
 }
 
 #[derive(Error, Debug)]
@@ -48,8 +125,24 @@ pub enum ConfigError {
     #[error("no configuration file")]
     NoConfig { source: vfs::VfsError },
 
-    #[error("invalid configuration")]
-    InvalidConfig { source: serde_yaml::Error },
+    #[error("could not read configuration")]
+    ReadError { source: std::io::Error },
+
+    #[error("error in {filename}{}", at(.location))]
+    InvalidConfig {
+        filename: String,
+        location: Option<(usize, usize)>,
+        source: serde_yaml::Error,
+    },
+
+    #[error("configuration expands to too many nodes through aliases (limit: {limit})")]
+    TooManyAliases { limit: u64 },
+
+    #[error("repository {repository} uses the {scheme} scheme, which is not allowed")]
+    DisallowedScheme { repository: String, scheme: String },
+
+    #[error("default repository {name} is not a configured repository")]
+    UnknownDefaultRepository { name: String },
 
     #[error("invalid url")]
     InvalidURL { source: url::ParseError },
@@ -65,6 +158,15 @@ pub enum ConfigError {
 pub enum TransactionError {
     #[error(transparent)]
     LockError(#[from] named_lock::Error),
+
+    #[error("could not access the journal")]
+    JournalUnavailable(#[from] vfs::VfsError),
+
+    #[error("could not read the journal")]
+    JournalIoError(#[from] std::io::Error),
+
+    #[error("could not parse the journal")]
+    InvalidJournal { source: serde_json::Error },
 }
 
 #[derive(Error, Debug)]
@@ -75,11 +177,61 @@ pub enum DBError {
     #[error("could not parse state.yml")]
     InvalidState { source: serde_yaml::Error },
 
-    #[error("could not initiate transaction")]
+    #[error("could not parse history")]
+    InvalidHistory { source: serde_json::Error },
+
+    #[error("could not access history")]
+    HistoryUnavailable(#[from] std::io::Error),
+
+    #[error("could not complete transaction")]
     TransactionError(#[from] TransactionError),
 
     #[error("no transaction")]
     NoTransaction,
+
+    #[error("a transaction is already in progress")]
+    NestedTransaction,
+
+    #[error("invalid profile name {profile:?}")]
+    InvalidProfile { profile: String },
+
+    #[error("profile {profile:?} already exists")]
+    ProfileExists { profile: String },
+
+    #[error("invalid snapshot name {name:?}")]
+    InvalidSnapshot { name: String },
+
+    #[error("no snapshot named {name:?}")]
+    UnknownSnapshot { name: String },
+
+    #[error("state layer {layer} does not exist")]
+    MissingLayer { layer: String },
+
+    #[error("{package} has not been requested")]
+    NotRequested { package: PackageName },
+
+    #[error("could not read {path}")]
+    FileUnavailable {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("could not set the permissions of {path}")]
+    PermissionsUnavailable {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error(
+        "{path} from {package} is already installed by {owner} in the {} profile",
+        .profile.as_deref().unwrap_or("default")
+    )]
+    FileConflict {
+        path: String,
+        package: PackageName,
+        owner: PackageName,
+        profile: Option<String>,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -90,8 +242,222 @@ pub enum RepositoryError {
     #[error("could not parse JSON data")]
     Deserialize(#[from] serde_json::Error),
 
+    #[error("error in index for repository {repository}{}", at(.location))]
+    InvalidIndex {
+        repository: String,
+        location: Option<(usize, usize)>,
+        source: serde_json::Error,
+    },
+
     #[error("could not access local file")]
     IoError(#[from] std::io::Error),
+
+    #[error("invalid public key for repository {repository}")]
+    InvalidPublicKey { repository: String },
+
+    #[error("invalid signature for repository {repository}")]
+    SignatureInvalid { repository: String },
+
+    #[error("unknown repository {repository}")]
+    UnknownRepository { repository: String },
+
+    #[error("no repositories configured")]
+    NoRepositories,
+
+    #[error(
+        "repository {repository} uses index schema version {found}, but only versions {}-{} are supported",
+        .supported.start(),
+        .supported.end()
+    )]
+    UnsupportedIndexSchema {
+        repository: String,
+        found: u32,
+        supported: std::ops::RangeInclusive<u32>,
+    },
+
+    #[error("{package} {version} has an invalid dependency")]
+    InvalidDependency {
+        package: PackageName,
+        version: semver::Version,
+        source: VersionConstraintError,
+    },
+
+    #[error("too many repositories configured")]
+    TooManyRepositories,
+
+    #[error("could not parse releases for {package}")]
+    InvalidPackage {
+        package: PackageName,
+        source: serde_json::Error,
+    },
+
+    #[error("invalid digest for the index shard of {package}")]
+    InvalidShardDigest {
+        package: PackageName,
+        source: DigestError,
+    },
+
+    #[error("repository index exceeded the size limit of {limit} bytes")]
+    IndexTooLarge { limit: u64 },
+
+    #[error("every mirror of repository {repository} failed: {}", .attempts.join("; "))]
+    MirrorsFailed {
+        repository: String,
+        attempts: Vec<String>,
+        // Whether every mirror failed only because it couldn't be reached.
+        transient: bool,
+    },
+
+    #[error("too many redirects while fetching repository {repository}")]
+    TooManyRedirects { repository: String },
+
+    #[error("could not fetch git repository {repository}: {message}")]
+    GitError { repository: String, message: String },
+
+    #[error("could not access the cache")]
+    CacheUnavailable(#[from] vfs::VfsError),
+
+    #[error("{url} uses the {scheme} scheme, which is not allowed")]
+    DisallowedScheme { url: String, scheme: String },
+
+    #[error("{url} is not a local file")]
+    NotLocalFile { url: String },
+
+    #[error("{url} is a local file, which only local repositories can use, not {repository}")]
+    LocalFileForbidden { url: String, repository: String },
+
+    #[error("no release of {package} {version}")]
+    UnknownRelease {
+        package: PackageName,
+        version: semver::Version,
+    },
+
+    #[error("contents of {url} do not match {digest}")]
+    DigestMismatch { url: String, digest: Digest },
+
+    #[error("could not download {package} {version}: {}", .attempts.join("; "))]
+    DownloadFailed {
+        package: PackageName,
+        version: semver::Version,
+        attempts: Vec<String>,
+    },
+
+    #[error(
+        "{package} {version} has different dependencies in {first} and {second}: {differences}"
+    )]
+    DivergentMetadata {
+        package: PackageName,
+        version: semver::Version,
+        first: String,
+        second: String,
+        differences: String,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("could not open package archive {path}")]
+    IoError {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("package archive {path} is not a valid archive")]
+    Malformed {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("package archive {path} does not contain metadata.json")]
+    NoMetadata { path: String },
+
+    #[error("package archive {path} has changed since it was added")]
+    Changed { path: String },
+
+    #[error("package archive {path} has invalid metadata")]
+    InvalidMetadata {
+        path: String,
+        source: serde_json::Error,
+    },
+
+    #[error(
+        "package archive {path} has {entry:?}, which would be extracted outside of the target"
+    )]
+    UnsafePath { path: String, entry: String },
+
+    #[error("package archive {path} has {entry:?}, which is not a file, directory, or link")]
+    UnsupportedEntry { path: String, entry: String },
+
+    #[error("package archive {path} has {entry:?}, which links to something it doesn't contain")]
+    BrokenLink { path: String, entry: String },
+
+    #[error("could not extract package archive")]
+    TargetUnavailable(#[from] vfs::VfsError),
+}
+
+#[derive(Error, Debug)]
+pub enum DigestError {
+    #[error("{hex} is not a valid {algorithm} digest")]
+    InvalidDigest {
+        algorithm: DigestAlgorithm,
+        hex: String,
+    },
+
+    #[error("could not determine the algorithm for digest {hex} from its length")]
+    UnrecognizedLength { hex: String },
+
+    #[error("no supported digest algorithm in: {}", .algorithms.join(", "))]
+    UnsupportedAlgorithms { algorithms: Vec<String> },
+
+    #[error("release has no digest")]
+    NoDigest,
+}
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("invalid manifest")]
+    InvalidManifest { source: serde_yaml::Error },
+
+    #[error("unsupported manifest version {version}")]
+    UnsupportedVersion { version: u32 },
+
+    #[error("invalid lockfile")]
+    InvalidLockfile { source: serde_yaml::Error },
+
+    #[error("invalid requirement {line:?}, expected name==version")]
+    InvalidRequirement { line: String },
+
+    #[error("could not read or write requirements")]
+    IoError(#[from] std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum LockfileError {
+    #[error("{package} {version} doesn't come from a repository, so it can't be locked")]
+    NotLockable {
+        package: PackageName,
+        version: semver::Version,
+    },
+
+    #[error("{package} {version} is locked to {repository}, which no longer provides it")]
+    Unavailable {
+        package: PackageName,
+        version: semver::Version,
+        repository: String,
+    },
+
+    #[error("{package} {version} from {repository} no longer matches its locked digest")]
+    DigestChanged {
+        package: PackageName,
+        version: semver::Version,
+        repository: String,
+    },
+
+    #[error("nothing locked satisfies {package} {constraint}, the lockfile needs regenerating")]
+    Stale {
+        package: PackageName,
+        constraint: VersionConstraint,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -109,6 +475,77 @@ pub enum SolverError {
         dependent: PackageName,
     },
 
+    #[error(
+        "{package} was not found in any repository (searched: {}){}",
+        .searched_repositories.join(", "),
+        did_you_mean(.suggestions)
+    )]
+    PackageNotFound {
+        /// Package that was requested.
+        package: PackageName,
+        /// The repositories that we looked in.
+        searched_repositories: Vec<String>,
+        /// Packages with similar names, that might have been meant instead.
+        suggestions: Vec<PackageName>,
+    },
+
+    #[error("{package} exists but has no available versions")]
+    NoAvailableVersions {
+        /// Package that was requested.
+        package: PackageName,
+    },
+
+    #[error("every version of {package} matching the request has been excluded")]
+    ExcludedVersion {
+        /// Package that was requested.
+        package: PackageName,
+        /// The versions that would have satisfied the request, if not excluded.
+        versions: Vec<semver::Version>,
+    },
+
+    #[error("{package} is pinned to repository {repository}, which has no matching version")]
+    PinnedUnavailable {
+        /// Package that was requested.
+        package: PackageName,
+        /// The repository that the request was pinned to.
+        repository: String,
+    },
+
+    #[error(
+        "{package} was requested as {requested}, which conflicts with its override of {constraint}"
+    )]
+    OverrideConflict {
+        /// Package that was requested.
+        package: PackageName,
+        /// The constraint it was requested with.
+        requested: VersionConstraint,
+        /// The constraint it has been overridden to.
+        constraint: VersionConstraint,
+    },
+
+    #[error(
+        "gave up resolving after {limit} decisions, the most contended packages were: {}",
+        .contended.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    SearchExhausted {
+        /// The number of decisions we were allowed to make.
+        limit: u64,
+        /// The packages that were decided on most often, most contended first.
+        contended: Vec<PackageName>,
+    },
+
+    #[error(
+        "resolution violated configured assertions: {}",
+        .failures.iter().map(|f| f.to_string()).collect::<Vec<_>>().join("; ")
+    )]
+    AssertionFailed {
+        /// Every assertion that the resolved packages failed.
+        failures: Vec<AssertionFailure>,
+    },
+
+    #[error("the deadline was exceeded while resolving")]
+    DeadlineExceeded,
+
     #[error("{package} {version} depends on itself")]
     SelfDependency {
         /// Package whose dependencies we want.
@@ -117,6 +554,9 @@ pub enum SolverError {
         version: Box<Candidate>,
     },
 
+    #[error(transparent)]
+    RepositoryError(#[from] RepositoryError),
+
     // PubGrubError has a Failure error, and I'm not sure where it would actually
     // be used at, so we're going to just replicate it ourselves.
     #[error("{0}")]
@@ -127,3 +567,19 @@ pub enum SolverError {
     #[error("impossible error")]
     Impossible,
 }
+
+fn at(location: &Option<(usize, usize)>) -> String {
+    match location {
+        Some((line, column)) => format!(" at line {}, column {}", line, column),
+        None => "".to_string(),
+    }
+}
+
+fn did_you_mean(suggestions: &[PackageName]) -> String {
+    if suggestions.is_empty() {
+        return "".to_string();
+    }
+
+    let names: Vec<String> = suggestions.iter().map(|s| s.to_string()).collect();
+    format!(", did you mean: {}?", names.join(", "))
+}
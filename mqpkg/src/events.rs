@@ -0,0 +1,27 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use crate::types::{PackageName, VersionConstraint};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Reason {
+    Requested,
+    // Requested because something else depends on it.
+    Dependency,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Event {
+    PackageAdded {
+        name: PackageName,
+        version: VersionConstraint,
+        reason: Reason,
+    },
+    PackageRemoved {
+        name: PackageName,
+        version: VersionConstraint,
+        reason: Reason,
+    },
+    TransactionCommitted,
+}
@@ -2,63 +2,325 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
+use std::cell::{Cell, Ref, RefCell};
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Instant;
 
 use console::{style, Emoji};
-use semver::VersionReq;
+use indexmap::IndexMap;
+use log::warn;
+use strsim::levenshtein;
 use vfs::VfsPath;
 
-use crate::pkgdb::transaction;
+use crate::archive::Archive;
+use crate::config::Level;
+use crate::pkgdb::{transaction, Installed, LocalArchive};
 use crate::progress::Progress;
-use crate::repository::Repository;
-use crate::resolver::Solver;
-use crate::types::{PackageName, Packages};
+use crate::repository::{Repository, RepositoryBuilder, UrlRewrite};
+use crate::resolver::{Chooser, Solver};
+use crate::types::{resolution, Package, Packages, WithSource};
+
+// These are part of our public API, so we re-export them to make sure that
+// callers are always using the same versions that we are.
+pub use semver::{Version, VersionReq};
+pub use url::Url;
 
 pub use crate::config::Config;
-pub use crate::errors::{InstallerError, SolverError};
-pub use crate::types::PackageSpecifier;
+pub use crate::errors::{
+    ArchiveError, DigestError, InstallerError, LockfileError, ManifestError, MarkerError,
+    PackageNameError, PackageSpecifierError, RepositoryError, SolverError, VersionConstraintError,
+};
+pub use crate::events::{Event, Reason};
+pub use crate::manifest::{LockedPackage, Lockfile, Manifest, ManifestRequest, Pin, Requirements};
+pub use crate::types::{
+    AssertionFailure, AvailableVersion, CacheCleanup, Choice, Clock, Digest, DigestAlgorithm,
+    EffectiveConstraint, Explanation, ExplanationFact, ExplanationStep, ExplanationTerm,
+    FetchSummary, FixedClock, HistoryEntry, InstallFailure, PackageChange, PackageName,
+    PackageSpecifier, PackageState, PartialInstall, Provider, Relaxation, ReleaseIssue,
+    RepositoryFetch, RepositoryStatus, Resolution, ResolutionDiff, ResolveProgress,
+    ShadowedPackage, Solutions, Status, SystemClock, Target, TargetFailure, TargetResolution,
+    TargetedPackage, UpgradeStrategy, VersionConstraint,
+};
 
 pub(crate) mod progress;
 pub(crate) mod types;
 
+mod archive;
 mod config;
 mod errors;
+mod events;
+mod manifest;
+mod marker;
 mod pkgdb;
 mod repository;
 mod resolver;
+mod yaml;
+
+const LOGNAME: &str = "mqpkg::installer";
+
+const MAX_SUGGESTIONS: usize = 3;
+const MAX_SUGGESTION_DISTANCE: usize = 2;
 
 static OFFICE_PAPER: Emoji<'_, '_> = Emoji("📄 ", "");
 static LOOKING_GLASS: Emoji<'_, '_> = Emoji("🔍 ", "");
+static TRUCK: Emoji<'_, '_> = Emoji("🚚 ", "");
+static PACKAGE: Emoji<'_, '_> = Emoji("📦 ", "");
 
 type Result<T, E = InstallerError> = core::result::Result<T, E>;
 
 pub struct Installer<'p, T> {
     config: config::Config,
     db: pkgdb::Database,
+    cache: VfsPath,
+    cache_dir: Option<PathBuf>,
     progress: Progress<'p, T>,
     console: Option<Box<dyn Fn(&str) + 'p>>,
+    events: Option<Box<dyn Fn(&Event) + 'p>>,
+    resolve_progress: Option<Box<dyn Fn(&ResolveProgress) + 'p>>,
+    chooser: Option<Box<dyn Fn(&PackageName, &[Choice]) -> usize + 'p>>,
+    // What our chooser has decided so far, by package and version.
+    chosen: RefCell<HashMap<(PackageName, Version), String>>,
+    // While searching for several solutions, the decisions that every resolve
+    // still has left between them, rather than each getting the whole limit.
+    budget: Cell<Option<u64>>,
+    warnings_as_errors: bool,
+    recommends: bool,
+    prereleases: bool,
+    prerelease_packages: HashSet<PackageName>,
+    no_deps: bool,
+    // What was requested before the install we're in added to it, since no_deps
+    // only applies to what's newly requested.
+    requested_before: Option<HashMap<PackageName, VersionConstraint>>,
+    deadline: Option<Instant>,
+    clock: Rc<dyn Clock>,
+    rewrite: Option<Rc<UrlRewrite>>,
+    environment: HashMap<String, String>,
+    // Overrides environment while resolving for a single target.
+    target: Option<HashMap<String, String>>,
+    // Versions to keep where possible while upgrading.
+    preferred: HashMap<PackageName, Version>,
+    // What's being upgraded instead of kept, and how far that reaches.
+    upgrading: HashSet<PackageName>,
+    strategy: UpgradeStrategy,
+    repository: RefCell<Option<Repository>>,
+    fetches: RefCell<HashMap<String, RepositoryFetch>>,
+    // Archives that have been added, but that are only recorded in our state once
+    // an install that requests them has been committed.
+    archives: IndexMap<PackageName, Archive>,
 }
 
-impl<'p, T> Installer<'p, T> {
-    pub fn new(config: config::Config, fs: VfsPath, rid: &str) -> Result<Installer<T>> {
+// Constructs an Installer, optionally with repository indices supplied up front,
+// in which case those are used instead of ever fetching our repositories.
+pub struct InstallerBuilder {
+    config: config::Config,
+    fs: VfsPath,
+    root: Option<PathBuf>,
+    rid: String,
+    indices: Vec<(String, Vec<u8>)>,
+}
+
+impl InstallerBuilder {
+    pub fn new(config: config::Config, fs: VfsPath, rid: &str) -> InstallerBuilder {
+        InstallerBuilder {
+            config,
+            fs,
+            root: None,
+            rid: rid.to_string(),
+            indices: Vec::new(),
+        }
+    }
+
+    // Where fs is on disk, when it is, which lets us use what vfs can't tell us,
+    // like when a cached artifact was last modified.
+    pub fn with_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    // The index for the named repository, which must be one that's configured.
+    pub fn with_index<I: Into<Vec<u8>>>(mut self, repository: &str, index: I) -> Self {
+        self.indices.push((repository.to_string(), index.into()));
+        self
+    }
+
+    pub fn build<'p, T>(self) -> Result<Installer<'p, T>> {
         // We're using MD5 here because it's short and fast, we're not using
         // this in a security sensitive aspect.
-        let id = format!("{:x}", md5::compute(rid));
-        let db = pkgdb::Database::new(fs, id)?;
+        let id = format!("{:x}", md5::compute(&self.rid));
+        let mut db = pkgdb::Database::new(self.fs, id)?;
+        db.with_root(self.root);
+        db.with_layers(self.config.state_layers())?;
+        let cache = db.cache(self.config.cache_dir())?;
+        let cache_dir = db.cache_dir(self.config.cache_dir());
 
-        Ok(Installer {
-            config,
+        let repository = match self.indices.is_empty() {
+            true => None,
+            false => {
+                let mut indices = Vec::new();
+                for (name, index) in self.indices.iter() {
+                    let repo = self
+                        .config
+                        .repositories()
+                        .iter()
+                        .find(|r| &r.name == name)
+                        .ok_or_else(|| RepositoryError::UnknownRepository {
+                            repository: name.clone(),
+                        })?;
+                    indices.push((repo, &index[..]));
+                }
+
+                let mut builder = RepositoryBuilder::new(&self.config, cache.clone())?;
+                builder.with_cache_dir(cache_dir.clone());
+                Some(builder.preload(&indices)?)
+            }
+        };
+
+        let mut installer = Installer {
+            warnings_as_errors: self.config.warnings_as_errors(),
+            recommends: true,
+            prereleases: self.config.prereleases(),
+            prerelease_packages: self.config.prerelease_packages().iter().cloned().collect(),
+            no_deps: false,
+            requested_before: None,
+            deadline: None,
+            clock: Rc::new(SystemClock),
+            rewrite: None,
+            environment: HashMap::new(),
+            target: None,
+            preferred: HashMap::new(),
+            upgrading: HashSet::new(),
+            strategy: UpgradeStrategy::default(),
+            config: self.config,
             db,
+            cache,
+            cache_dir,
             progress: Progress::new(),
             console: None,
-        })
+            events: None,
+            resolve_progress: None,
+            chooser: None,
+            chosen: RefCell::new(HashMap::new()),
+            budget: Cell::new(None),
+            repository: RefCell::new(repository),
+            fetches: RefCell::new(HashMap::new()),
+            archives: IndexMap::new(),
+        };
+
+        if let Some(mut repository) = installer.repository.get_mut().take() {
+            installer.add_archives(&mut repository)?;
+            *installer.repository.get_mut() = Some(repository);
+        }
+
+        Ok(installer)
+    }
+}
+
+impl<'p, T> Installer<'p, T> {
+    pub fn new(config: config::Config, fs: VfsPath, rid: &str) -> Result<Installer<'p, T>> {
+        InstallerBuilder::new(config, fs, rid).build()
+    }
+
+    // Selects which profile's state we operate on, rather than the default one.
+    pub fn with_profile(&mut self, profile: &str) -> Result<()> {
+        Ok(self.db.with_profile(profile)?)
+    }
+
+    // Creates a new, empty profile, without selecting it.
+    pub fn create_profile(&self, profile: &str) -> Result<()> {
+        Ok(self.db.create_profile(profile)?)
+    }
+
+    // Every profile other than the default one, which always exists.
+    pub fn profiles(&self) -> Result<Vec<String>> {
+        Ok(self.db.profiles()?)
+    }
+
+    // Overrides whatever the config says about treating warnings as errors.
+    pub fn with_warnings_as_errors(&mut self, enabled: bool) {
+        self.warnings_as_errors = enabled
+    }
+
+    // Controls whether recommended packages are pulled in, which they are unless
+    // this is turned off.
+    pub fn with_recommends(&mut self, enabled: bool) {
+        self.recommends = enabled
+    }
+
+    // Lets pre-releases of every package be picked, even when no constraint
+    // mentions one, which is off unless it's turned on here or in our config.
+    pub fn with_prereleases(&mut self, enabled: bool) {
+        self.prereleases = enabled
+    }
+
+    // Like with_prereleases, but only for packages, on top of any that our config
+    // already allows pre-releases for.
+    pub fn with_prereleases_for(&mut self, packages: &[PackageName]) {
+        self.prerelease_packages.extend(packages.iter().cloned())
+    }
+
+    // Resolves what's newly requested without its dependencies (or any package's
+    // recommendations), for when they're already provided some other way, or to
+    // reproduce a minimal failure. Whatever was already requested still gets its
+    // dependencies, just as it did before. This is off unless it's turned on.
+    pub fn with_no_deps(&mut self, enabled: bool) {
+        self.no_deps = enabled
+    }
+
+    // Installs give up once this has passed, without committing anything.
+    pub fn with_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline)
+    }
+
+    // Replaces the clock that we use whenever something depends on the time.
+    pub fn with_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Rc::new(clock);
+        if let Some(repository) = self.repository.get_mut() {
+            repository.with_clock(self.clock.clone());
+        }
+    }
+
+    // Rewrites the url of every request to our repositories just before it's made,
+    // such as to transparently send them through a mirror or proxy.
+    pub fn with_url_rewrite(&mut self, cb: impl Fn(&Url) -> Url + 'static) {
+        self.rewrite = Some(Rc::new(cb));
+        if let Some(repository) = self.repository.get_mut() {
+            repository.with_rewrite(self.rewrite.clone());
+        }
+    }
+
+    // The values that dependency markers, like `python_version >= "3.8"`, are
+    // evaluated against. Dependencies whose marker doesn't match are left out of
+    // resolving entirely, and any variable that isn't given here is empty.
+    pub fn with_environment(&mut self, environment: HashMap<String, String>) {
+        self.environment = environment;
+        if let Some(repository) = self.repository.get_mut() {
+            repository.with_environment(self.environment.clone());
+        }
     }
 
     pub fn with_console(&mut self, cb: impl Fn(&str) + 'p) {
         self.console = Some(Box::new(cb))
     }
 
+    pub fn with_events(&mut self, cb: impl Fn(&Event) + 'p) {
+        self.events = Some(Box::new(cb))
+    }
+
+    // Called periodically while resolving, and once more when resolving is done.
+    pub fn with_resolve_progress(&mut self, cb: impl Fn(&ResolveProgress) + 'p) {
+        self.resolve_progress = Some(Box::new(cb))
+    }
+
+    // Called to pick between equally good candidates for a package, such as the
+    // same version from more than one repository, returning the index of the
+    // option to use. Without this, we pick by repository order.
+    pub fn with_chooser(&mut self, cb: impl Fn(&PackageName, &[Choice]) -> usize + 'p) {
+        self.chooser = Some(Box::new(cb))
+    }
+
     pub fn with_progress_start(&mut self, cb: impl FnMut(u64) -> T + 'p) {
         self.progress.with_progress_start(Box::new(cb))
     }
@@ -77,66 +339,2125 @@ impl<'p, T> Installer<'p, T> {
 }
 
 impl<'p, T> Installer<'p, T> {
-    pub fn install(&mut self, packages: &[PackageSpecifier]) -> Result<()> {
-        transaction!(self.db, {
+    pub fn install(&mut self, packages: &[PackageSpecifier]) -> Result<Resolution> {
+        self.requested_before = Some(
+            self.db
+                .read()?
+                .requested
+                .into_values()
+                .map(|req| (req.name, req.version))
+                .collect(),
+        );
+        let result = self.install_requests(packages);
+        self.requested_before = None;
+
+        result
+    }
+
+    fn install_requests(&mut self, packages: &[PackageSpecifier]) -> Result<Resolution> {
+        // Events are only emitted once our transaction has been committed, so
+        // that nobody ever sees an event for a change that didn't happen.
+        let mut events = Vec::new();
+
+        let (solution, changed) = transaction!(self.db, {
+            self.db
+                .describe("install", names(packages), self.clock.now());
+
             // Add all of the packages being requested to the set of all requested packages.
             for package in packages {
-                self.db.add(package)?;
+                self.add_requested(package)?;
+                events.push(Event::PackageAdded {
+                    name: package.name.clone(),
+                    version: package.version.clone(),
+                    reason: Reason::Requested,
+                });
             }
 
             // Get all of the requested packages, we need this to ensure that this install
             // doesn't invalidate any of the version requirements of the already requested
             // packages.
-            let mut requested = HashMap::new();
-            for req in self.db.requested()?.values() {
-                requested.insert(req.name.clone(), req.version.clone());
+            let (requested, pinned) = self.requested_with(&[])?;
+            let solution = self.install_requested(requested, pinned)?;
+
+            (solution, self.db.changed())
+        });
+
+        // If everything was already requested and resolved exactly like this, then
+        // nothing happened, so there's nothing to tell anyone about either.
+        if !changed {
+            self.console("Already satisfied, no changes");
+        }
+        self.committed(changed, events);
+
+        Ok(resolution(&solution))
+    }
+
+    // Unlike install, which is all or nothing, this installs as many of packages as
+    // it can, resolving each of them in turn alongside everything that's already
+    // requested and that came before it, and reports the ones that it couldn't.
+    // Since that means packages aren't resolved together, it has to be opted into.
+    pub fn install_best_effort(&mut self, packages: &[PackageSpecifier]) -> Result<PartialInstall> {
+        let mut events = Vec::new();
+
+        let (solution, failures, changed) = transaction!(self.db, {
+            self.db
+                .describe("install best effort", names(packages), self.clock.now());
+
+            let (mut requested, mut pinned) = self.requested_with(&[])?;
+            let mut accepted = Vec::new();
+            let mut failures = Vec::new();
+            let solution =
+                self.install_solved("Resolved dependencies", |installer, repository| {
+                    // Whatever was already requested has to resolve on its own, otherwise
+                    // there's nothing for any of our packages to be installed alongside.
+                    let mut solution =
+                        installer.resolve(repository, requested.clone(), pinned.clone())?;
+                    for package in packages {
+                        let mut attempt = requested.clone();
+                        let mut attempt_pinned = pinned.clone();
+                        attempt.insert(package.name.clone(), package.version.clone());
+                        if let Some(repository) = &package.repository {
+                            attempt_pinned.insert(package.name.clone(), repository.clone());
+                        }
+
+                        match installer.resolve(repository, attempt.clone(), attempt_pinned.clone())
+                        {
+                            Ok(result) => {
+                                requested = attempt;
+                                pinned = attempt_pinned;
+                                solution = result;
+                                accepted.push(package);
+                            }
+                            Err(InstallerError::DeadlineExceeded) => {
+                                return Err(InstallerError::DeadlineExceeded)
+                            }
+                            Err(err) => failures.push(InstallFailure {
+                                package: package.clone(),
+                                error: err.to_string(),
+                            }),
+                        }
+                    }
+
+                    Ok(solution)
+                })?;
+
+            for package in accepted {
+                self.add_requested(package)?;
+                events.push(Event::PackageAdded {
+                    name: package.name.clone(),
+                    version: package.version.clone(),
+                    reason: Reason::Requested,
+                });
+            }
+
+            (solution, failures, self.db.changed())
+        });
+
+        self.committed(changed, events);
+
+        Ok(PartialInstall {
+            resolution: resolution(&solution),
+            failures,
+        })
+    }
+
+    // Installs everything that packages depend on, but not the packages themselves,
+    // which are still used to drive resolving, but are otherwise left out. Their
+    // direct dependencies get requested, so that they stay installed from then on.
+    pub fn install_dependencies(&mut self, packages: &[PackageSpecifier]) -> Result<Resolution> {
+        let mut events = Vec::new();
+
+        let (solution, changed) = transaction!(self.db, {
+            self.db
+                .describe("install dependencies", names(packages), self.clock.now());
+            let existing = self.db.requested()?;
+            let (requested, pinned) = self.requested_with(packages)?;
+            let mut direct = BTreeSet::new();
+            let solution =
+                self.install_solved("Resolved dependencies", |installer, repository| {
+                    let mut solution = installer.resolve(repository, requested, pinned)?;
+
+                    // Only what the packages directly depend on gets requested, everything
+                    // else that they need is installed as a dependency of those, so that it
+                    // goes away again once nothing needs it.
+                    for package in packages {
+                        if let Some(resolved) = solution.get(&package.name) {
+                            direct.extend(resolved.dependency_names());
+                        }
+                    }
+
+                    // Anything that was already requested stays, even if it was given.
+                    for package in packages {
+                        if !existing.contains_key(&package.name) {
+                            solution.remove(&package.name);
+                            direct.remove(&package.name);
+                        }
+                    }
+
+                    Ok(solution)
+                })?;
+
+            for name in direct.iter() {
+                if !existing.contains_key(name) {
+                    let specifier =
+                        PackageSpecifier::new(name.clone(), VersionConstraint::any(), None);
+                    self.db.add(&specifier)?;
+                    events.push(Event::PackageAdded {
+                        name: specifier.name,
+                        version: specifier.version,
+                        reason: Reason::Dependency,
+                    });
+                }
+            }
+
+            (solution, self.db.changed())
+        });
+
+        self.committed(changed, events);
+
+        Ok(resolution(&solution))
+    }
+
+    // Removes packages from what's been requested, and resolves again, so that
+    // anything only installed for them goes too. A package that something still
+    // requested depends on stays installed either way, so depending on our config,
+    // uninstalling it is either refused, or only warned about.
+    pub fn uninstall(&mut self, packages: &[PackageName]) -> Result<Resolution> {
+        let mut events = Vec::new();
+
+        let (solution, changed) = transaction!(self.db, {
+            self.db
+                .describe("uninstall", packages.to_vec(), self.clock.now());
+
+            for name in packages {
+                let removed = self.db.remove(name)?;
+                events.push(Event::PackageRemoved {
+                    name: removed.name,
+                    version: removed.version,
+                    reason: Reason::Requested,
+                });
+            }
+
+            let (requested, pinned) = self.requested_with(&[])?;
+            let previous = self.db.read()?.resolved;
+            let solution =
+                self.install_solved("Resolved dependencies", |installer, repository| {
+                    let solution = installer.resolve(repository, requested.clone(), pinned)?;
+                    for name in packages.iter().filter(|n| solution.contains_key(*n)) {
+                        let dependents = dependents(&solution, &requested, name);
+                        match installer.config.still_required() {
+                            Level::Warn => installer.warn(format!(
+                                "{} is still required by {}, so it will stay installed",
+                                name,
+                                dependents
+                                    .iter()
+                                    .map(|p| p.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ))?,
+                            _ => {
+                                return Err(InstallerError::StillRequired {
+                                    package: name.clone(),
+                                    dependents,
+                                })
+                            }
+                        }
+                    }
+
+                    Ok(solution)
+                })?;
+
+            // Anything that was only installed as a dependency, and that nothing
+            // needs any more, goes as well.
+            for (name, state) in previous {
+                if !solution.contains_key(&name) && !packages.contains(&name) {
+                    events.push(Event::PackageRemoved {
+                        name,
+                        version: VersionConstraint::exact(&state.version),
+                        reason: Reason::Dependency,
+                    });
+                }
             }
 
-            // Grab our repository, and pre-emptively fetch all of the data
-            let repository = self.repository()?;
-            self.console(step(1, 2, OFFICE_PAPER, "Fetched package metadata"));
+            (solution, self.db.changed())
+        });
+
+        self.committed(changed, events);
+
+        Ok(resolution(&solution))
+    }
+
+    // Upgrades packages, or everything that's requested when packages is empty,
+    // to the newest versions that still satisfy what's requested. How far that
+    // reaches into what they depend on is up to strategy, anything else is kept
+    // at the version it's at now whenever it can be.
+    pub fn upgrade(
+        &mut self,
+        packages: &[PackageName],
+        strategy: UpgradeStrategy,
+    ) -> Result<Resolution> {
+        self.strategy = strategy;
+        let result = self.reresolve("upgrade", packages);
+        self.preferred.clear();
+        self.upgrading.clear();
+        self.strategy = UpgradeStrategy::default();
+
+        result
+    }
+
+    // Resolves everything that's requested again, keeping everything at the
+    // version it's at now, other than packages, or everything that's requested
+    // when packages is empty.
+    fn reresolve(&mut self, operation: &str, packages: &[PackageName]) -> Result<Resolution> {
+        let (solution, changed) = transaction!(self.db, {
+            let state = self.db.read()?;
+            let targets: Vec<PackageName> = match packages.is_empty() {
+                true => state.requested.keys().cloned().collect(),
+                false => packages.to_vec(),
+            };
+            self.db
+                .describe(operation, targets.clone(), self.clock.now());
+            self.preferred = state
+                .resolved
+                .into_iter()
+                .map(|(name, package)| (name, package.version))
+                .collect();
+            self.upgrading = targets.into_iter().collect();
+
+            let (requested, pinned) = self.requested_with(&[])?;
+            let solution = self.install_requested(requested, pinned)?;
+
+            (solution, self.db.changed())
+        });
+
+        if !changed {
+            self.console("Already up to date, no changes");
+        }
+        self.committed(changed, Vec::new());
+
+        Ok(resolution(&solution))
+    }
+
+    // Installs everything a manifest asks for, resolving fresh against our own
+    // repositories, alongside anything that's already been requested here.
+    pub fn import(&mut self, manifest: &Manifest) -> Result<Resolution> {
+        self.install(&manifest.specifiers())
+    }
+
+    // Installs exactly the packages that requirements pin, as requests.
+    pub fn import_requirements(&mut self, requirements: &Requirements) -> Result<Resolution> {
+        self.install(&requirements.specifiers())
+    }
+
+    // Installs exactly what lockfile has locked, without resolving, replacing
+    // whatever is requested and resolved now. Every package has to still be
+    // available from the repository that it was locked to, with the same digest,
+    // and every request has to be satisfied by what's locked, otherwise nothing
+    // is installed.
+    pub fn install_locked(&mut self, lockfile: &Lockfile) -> Result<Resolution> {
+        let specifiers = lockfile.specifiers();
+        let (resolved, changed) = transaction!(self.db, {
+            self.db
+                .describe("install locked", names(&specifiers), self.clock.now());
+
+            // Whatever was installed before and isn't locked is removed, just like
+            // anything else that's no longer part of our solution.
+            let solution = self.install_solved("Verified locked packages", |_, repository| {
+                let mut solution = Packages::new();
+                for locked in lockfile.packages.iter() {
+                    let candidate = repository
+                        .pinned_candidates(&locked.name, &locked.repository)?
+                        .into_iter()
+                        .find(|c| Version::from(c) == locked.version)
+                        .ok_or_else(|| LockfileError::Unavailable {
+                            package: locked.name.clone(),
+                            version: locked.version.clone(),
+                            repository: locked.repository.clone(),
+                        })?;
+
+                    let digest =
+                        repository.digest(&locked.name, &locked.version, &locked.repository)?;
+                    if digest.is_none_or(|d| locked.digests.get(d.algorithm.name()) != Some(&d.hex))
+                    {
+                        return Err(LockfileError::DigestChanged {
+                            package: locked.name.clone(),
+                            version: locked.version.clone(),
+                            repository: locked.repository.clone(),
+                        }
+                        .into());
+                    }
+
+                    solution.insert(
+                        locked.name.clone(),
+                        Package::new(locked.name.clone(), candidate),
+                    );
+                }
+
+                let resolved = resolution(&solution);
+                for request in lockfile.requests.iter() {
+                    if !resolved
+                        .get(&request.name)
+                        .is_some_and(|p| request.version.matches(&p.version))
+                    {
+                        return Err(LockfileError::Stale {
+                            package: request.name.clone(),
+                            constraint: request.version.clone(),
+                        }
+                        .into());
+                    }
+                }
+
+                Ok(solution)
+            })?;
+            self.db.set_requested(&specifiers)?;
 
-            // Resolve all of our requirements to a full set of packages that we should install
-            let _solution = self.resolve(repository, requested)?;
-            self.console(step(2, 2, LOOKING_GLASS, "Resolved dependencies"));
+            (resolution(&solution), self.db.changed())
         });
 
+        self.committed(changed, Vec::new());
+        Ok(resolved)
+    }
+
+    // Saves what's currently requested and resolved as a named snapshot, as a
+    // checkpoint that can be restored back to, across any number of operations.
+    pub fn snapshot(&mut self, name: &str) -> Result<()> {
+        transaction!(self.db, { self.db.snapshot(name)? });
+        Ok(())
+    }
+
+    // Restores a snapshot, replacing whatever is requested and resolved now, with
+    // the target following along. Like a rollback, what the snapshot resolved is
+    // kept wherever it's still available.
+    pub fn restore(&mut self, name: &str) -> Result<()> {
+        let result = self.restore_to(name);
+        self.preferred.clear();
+
+        self.committed(result?, Vec::new());
+        Ok(())
+    }
+
+    // Returns whether restoring changed anything.
+    fn restore_to(&mut self, name: &str) -> Result<bool> {
+        let changed = transaction!(self.db, {
+            self.db.describe("restore", Vec::new(), self.clock.now());
+            self.preferred = self
+                .db
+                .restore(name)?
+                .into_iter()
+                .map(|(name, package)| (name, package.version))
+                .collect();
+
+            let (requested, pinned) = self.requested_with(&[])?;
+            self.install_requested(requested, pinned)?;
+
+            self.db.changed()
+        });
+
+        Ok(changed)
+    }
+
+    // Every operation that has changed our state, oldest first.
+    pub fn history(&self) -> Result<Vec<HistoryEntry>> {
+        Ok(self.db.history()?)
+    }
+
+    // Returns to how things were once the transaction with id had committed,
+    // requesting exactly what was requested then, and resolving back to the same
+    // versions wherever they're still available, with the target following along.
+    pub fn rollback(&mut self, id: u64) -> Result<Resolution> {
+        let entry = self
+            .db
+            .history()?
+            .into_iter()
+            .find(|e| e.id == id)
+            .ok_or(InstallerError::UnknownTransaction { id })?;
+
+        self.preferred = entry
+            .resolved
+            .iter()
+            .map(|(name, package)| (name.clone(), package.version.clone()))
+            .collect();
+        let result = self.rollback_to(&entry);
+        self.preferred.clear();
+
+        result
+    }
+
+    fn rollback_to(&mut self, entry: &HistoryEntry) -> Result<Resolution> {
+        let (solution, changed) = transaction!(self.db, {
+            let packages = entry.requested.iter().map(|r| r.name.clone()).collect();
+            self.db.describe("rollback", packages, self.clock.now());
+            self.db.set_requested(&entry.requested)?;
+
+            let (requested, pinned) = self.requested_with(&[])?;
+            let solution =
+                self.install_solved("Resolved dependencies", |installer, repository| {
+                    let solution = installer.resolve(repository, requested, pinned)?;
+
+                    // Anything that's since disappeared from our repositories can't come
+                    // back, so that's resolved to whatever it can be, rather than failing
+                    // outright.
+                    if resolution(&solution) != entry.resolved {
+                        installer.warn(format!(
+                            "could not roll back to exactly what transaction {} resolved",
+                            entry.id
+                        ))?;
+                    }
+
+                    Ok(solution)
+                })?;
+
+            (solution, self.db.changed())
+        });
+
+        self.committed(changed, Vec::new());
+        Ok(resolution(&solution))
+    }
+
+    pub fn snapshots(&self) -> Result<Vec<String>> {
+        Ok(self.db.snapshots()?)
+    }
+
+    pub fn remove_snapshot(&mut self, name: &str) -> Result<()> {
+        transaction!(self.db, { self.db.remove_snapshot(name)? });
+        Ok(())
+    }
+
+    // Throws away a transaction that was interrupted while it was committing,
+    // rather than finishing it, returning whether there was one. Normally the
+    // next transaction finishes it, so this is only for when that keeps failing.
+    pub fn abort(&mut self) -> Result<bool> {
+        Ok(self.db.discard()?)
+    }
+
+    // Resets the environment, forgetting everything that has been requested or
+    // resolved, while leaving the pkgdb itself in place.
+    pub fn reset(&mut self) -> Result<()> {
+        let (cleared, changed) = transaction!(self.db, {
+            self.db.describe("reset", Vec::new(), self.clock.now());
+            (self.db.clear()?, self.db.changed())
+        });
+
+        let events = cleared
+            .into_iter()
+            .map(|req| Event::PackageRemoved {
+                name: req.name,
+                version: req.version,
+                reason: Reason::Requested,
+            })
+            .collect();
+        self.committed(changed, events);
+
         Ok(())
     }
 }
 
 impl<'p, T> Installer<'p, T> {
-    fn console<S: AsRef<str>>(&self, msg: S) {
-        if let Some(cb) = &self.console {
-            (cb)(msg.as_ref());
+    // Lists every version of package available from our repositories, and whether
+    // each one satisfies what has been requested for that package, if anything.
+    // This doesn't modify anything, so it doesn't need a transaction.
+    pub fn available(&self, package: &PackageName) -> Result<Vec<AvailableVersion>> {
+        let requested = self.db.read()?.requested;
+        let constraint = requested.get(package).map(|r| &r.version);
+        let repository = self.repository()?;
+        let candidates = repository.candidates(package)?;
+        let prereleases = self.allows_prereleases(package);
+
+        // Whether a version satisfies what's requested is up to the same rules
+        // that resolving uses, which only our candidates know how to apply.
+        Ok(repository
+            .available(package)?
+            .into_iter()
+            .map(|(version, repo)| AvailableVersion {
+                satisfies: constraint.is_none_or(|c| {
+                    candidates.iter().any(|candidate| {
+                        Version::from(candidate) == version
+                            && candidate.satisfies_with(c, prereleases)
+                    })
+                }),
+                repository: repo.name.clone(),
+                version,
+            })
+            .collect())
+    }
+
+    // Downloads the artifact for a specific release, or reuses the copy in our
+    // cache, and returns the path to it. See Repository::download.
+    pub fn download(&self, package: &PackageName, version: &Version) -> Result<String> {
+        let repository = self.repository()?;
+        let path = repository.download(package, version)?;
+
+        Ok(path.as_str().to_string())
+    }
+}
+
+impl<'p, T> Installer<'p, T> {
+    // Whether pre-releases of package can be picked, even when no constraint
+    // mentions one.
+    fn allows_prereleases(&self, package: &PackageName) -> bool {
+        self.prereleases || self.prerelease_packages.contains(package)
+    }
+
+    // Every package that's provided by more than one of our repositories, in name
+    // order, to help track down a repository unexpectedly shadowing another.
+    pub fn shadowed(&self) -> Result<Vec<ShadowedPackage>> {
+        let repository = self.repository()?;
+        let prereleases = |name: &PackageName| self.allows_prereleases(name);
+
+        Ok(repository
+            .shadowed(prereleases)?
+            .into_iter()
+            .map(|(name, preferred, providers)| ShadowedPackage {
+                name: name.clone(),
+                preferred: preferred.unwrap_or_default(),
+                providers: providers
+                    .into_iter()
+                    .map(|(repository, versions)| Provider {
+                        repository,
+                        versions,
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+}
+
+impl<'p, T> Installer<'p, T> {
+    // Exports what has been requested, so it can be imported elsewhere.
+    pub fn export(&self) -> Result<Manifest> {
+        let requested = self.db.read()?.requested;
+
+        Ok(Manifest::new(
+            requested
+                .into_values()
+                .map(|req| ManifestRequest {
+                    name: req.name,
+                    version: req.version,
+                    repository: req.repository,
+                    metadata: req.metadata,
+                })
+                .collect(),
+        ))
+    }
+
+    // Exports what has been resolved as a flat list of pins, see Requirements.
+    pub fn freeze(&self, sources: bool) -> Result<Requirements> {
+        Ok(Requirements::new(&self.db.read()?.resolved, sources))
+    }
+
+    // Resolves what's requested, and locks exactly what that resolved to, see
+    // Lockfile. Nothing is installed, and only packages that come from one of our
+    // repositories can be locked.
+    pub fn lock(&self) -> Result<Lockfile> {
+        let (requested, pinned) = self.requested_with(&[])?;
+        let repository = self.repository()?;
+        let solution = self.resolve(&repository, requested, pinned)?;
+
+        let mut packages = Vec::new();
+        for (name, package) in solution.iter() {
+            let locked = package.source().repository().and_then(|repo| {
+                repository
+                    .digest(name, package.version(), repo)
+                    .transpose()
+                    .map(|digest| (repo, digest))
+            });
+            let (repo, digest) = locked.ok_or_else(|| LockfileError::NotLockable {
+                package: name.clone(),
+                version: package.version().clone(),
+            })?;
+            let digest = digest?;
+
+            packages.push(LockedPackage {
+                name: name.clone(),
+                version: package.version().clone(),
+                repository: repo.to_string(),
+                digests: BTreeMap::from([(
+                    digest.algorithm.name().to_string(),
+                    digest.hex.clone(),
+                )]),
+            });
         }
+
+        Ok(Lockfile::new(self.export()?.requests, packages))
     }
 
-    fn repository(&self) -> Result<Repository> {
-        let bar = self
-            .progress
-            .bar(self.config.repositories().len().try_into().unwrap());
-        let repository = Repository::new()?.fetch(self.config.repositories(), || bar.update(1))?;
-        bar.finish();
+    // Calls cb with every version of every package that our repositories have,
+    // one package at a time, without ever collecting all of them up front.
+    pub fn each_candidate(&self, mut cb: impl FnMut(&PackageState)) -> Result<()> {
+        let repository = self.repository()?;
+        for result in repository.all_candidates() {
+            let (name, candidate) = result?;
+            cb(&PackageState {
+                name: name.clone(),
+                version: Version::from(&candidate),
+                source: candidate.source().to_string(),
+            });
+        }
 
-        Ok(repository)
+        Ok(())
     }
 
-    fn resolve(
+    // Finds the packages in our repositories with the given tag and category,
+    // leaving either out matches packages regardless of that field.
+    pub fn search(&self, tag: Option<&str>, category: Option<&str>) -> Result<Vec<PackageName>> {
+        let repository = self.repository()?;
+        let names = repository.search(tag, category)?;
+
+        Ok(names.into_iter().cloned().collect())
+    }
+
+    // Finds up to limit distinct solutions for packages, alongside everything that
+    // has already been requested, without changing anything. After the first
+    // solution, each further one is found by excluding a version that an earlier
+    // solution chose, searching breadth first and in name order for determinism.
+    //
+    // Every solution has to pass the same checks as one we'd install, and the
+    // configured decision limit applies to the whole search, not to each resolve.
+    pub fn solutions(&self, packages: &[PackageSpecifier], limit: usize) -> Result<Solutions> {
+        self.budget.set(self.config.max_resolve_decisions());
+        let result = self.search_solutions(packages, limit);
+        self.budget.set(None);
+        result
+    }
+
+    fn search_solutions(&self, packages: &[PackageSpecifier], limit: usize) -> Result<Solutions> {
+        let (requested, pinned) = self.requested_with(packages)?;
+        let repository = self.repository()?;
+        let mut found: Vec<Resolution> = Vec::new();
+        let mut tried = HashSet::new();
+        let mut pending = VecDeque::from([BTreeSet::<(PackageName, Version)>::new()]);
+        let mut exhausted = false;
+
+        // We look for one more than we were asked for, so that we know whether
+        // there were more solutions than we're returning.
+        while let Some(extra) = pending.pop_front() {
+            if found.len() > limit {
+                break;
+            }
+            self.check_deadline()?;
+
+            let mut excluded = self.config.exclude_versions().clone();
+            for (name, version) in extra.iter() {
+                excluded
+                    .entry(name.clone())
+                    .or_default()
+                    .push(version.clone());
+            }
+
+            let solution = match self.resolve_excluding(
+                &repository,
+                requested.clone(),
+                pinned.clone(),
+                excluded,
+            ) {
+                Ok(solution) => resolution(&solution),
+                // Once we've run out of decisions, whatever we've found so far is
+                // all we can offer, though there may well have been more.
+                Err(InstallerError::ResolverError(SolverError::SearchExhausted { .. }))
+                    if !found.is_empty() =>
+                {
+                    exhausted = true;
+                    break;
+                }
+                // An alternative that can't be resolved, or that fails any of our
+                // checks, just isn't a solution.
+                Err(InstallerError::ResolverError(_)) if !extra.is_empty() => continue,
+                Err(err) => return Err(err),
+            };
+
+            for (name, package) in solution.iter() {
+                let mut next = extra.clone();
+                next.insert((name.clone(), package.version.clone()));
+                if tried.insert(next.clone()) {
+                    pending.push_back(next);
+                }
+            }
+
+            if !found.contains(&solution) {
+                found.push(solution);
+            }
+        }
+
+        let more = exhausted || found.len() > limit;
+        found.truncate(limit);
+
+        Ok(Solutions {
+            solutions: found,
+            more,
+        })
+    }
+
+    // Resolves packages, alongside what's already requested, once for each of
+    // targets, and combines the results, so that everything any of them needs can
+    // be gathered in a single pass. Our repositories are only fetched once, and a
+    // target that can't be resolved is reported without failing the others.
+    pub fn resolve_targets(
+        &mut self,
+        packages: &[PackageSpecifier],
+        targets: &[Target],
+    ) -> Result<TargetResolution> {
+        let (requested, pinned) = self.requested_with(packages)?;
+        self.repository()?;
+
+        let mut combined = TargetResolution::default();
+        for target in targets {
+            self.target = Some(target.environment.clone());
+            let result = self.repository().and_then(|repository| {
+                self.resolve(&repository, requested.clone(), pinned.clone())
+            });
+            self.target = None;
+
+            match result {
+                Ok(solution) => combined.add(target, resolution(&solution)),
+                Err(InstallerError::DeadlineExceeded) => {
+                    return Err(InstallerError::DeadlineExceeded)
+                }
+                Err(err) => combined.failures.push(TargetFailure {
+                    target: target.name.clone(),
+                    error: err.to_string(),
+                }),
+            }
+        }
+
+        Ok(combined)
+    }
+
+    // When packages can't be resolved alongside what's already requested, finds
+    // a set of requests that could be loosened so that they could be. We loosen
+    // requests one at a time until resolving succeeds, and then tighten back up
+    // any that turned out not to matter, so the set is minimal, though it's not
+    // necessarily the smallest one. If resolving already succeeds, or no amount
+    // of loosening helps, there's nothing to suggest.
+    pub fn relaxations(&self, packages: &[PackageSpecifier]) -> Result<Vec<Relaxation>> {
+        let (requested, pinned) = self.requested_with(packages)?;
+        let repository = self.repository()?;
+        let attempt = |requested: &HashMap<PackageName, VersionConstraint>| match self.solve(
+            &repository,
+            requested.clone(),
+            pinned.clone(),
+        ) {
+            Ok(solution) => Ok(Some(solution)),
+            Err(InstallerError::ResolverError(SolverError::NoSolution(_))) => Ok(None),
+            Err(err) => Err(err),
+        };
+
+        if attempt(&requested)?.is_some() {
+            return Ok(Vec::new());
+        }
+
+        let mut names: Vec<&PackageName> = requested.keys().collect();
+        names.sort();
+
+        let mut relaxed = requested.clone();
+        let mut loosened = Vec::new();
+        let mut solution = None;
+        for name in names {
+            relaxed.insert(name.clone(), VersionConstraint::any());
+            loosened.push(name);
+            solution = attempt(&relaxed)?;
+            if solution.is_some() {
+                break;
+            }
+        }
+        let mut solution = match solution {
+            Some(solution) => solution,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut needed = Vec::new();
+        for name in loosened {
+            relaxed.insert(name.clone(), requested[name].clone());
+            match attempt(&relaxed)? {
+                Some(tightened) => solution = tightened,
+                None => {
+                    relaxed.insert(name.clone(), VersionConstraint::any());
+                    needed.push(name);
+                }
+            }
+        }
+
+        Ok(needed
+            .into_iter()
+            .filter_map(|name| {
+                Some(Relaxation {
+                    package: name.clone(),
+                    from: requested[name].clone(),
+                    version: solution.get(name)?.version().clone(),
+                })
+            })
+            .collect())
+    }
+
+    // Explains what is constraining the version of package that we'll choose,
+    // using only our local state and config, so this never hits the network.
+    pub fn effective_constraint(
         &self,
-        repository: Repository,
-        requested: HashMap<PackageName, VersionReq>,
-    ) -> Result<Packages> {
-        let spinner = self.progress.spinner("Resolving dependencies");
-        let solver = Solver::new(repository);
-        let solution = solver.resolve(requested, || spinner.update(1))?;
-        spinner.finish();
+        package: &PackageName,
+    ) -> Result<Option<EffectiveConstraint>> {
+        let requested = self
+            .db
+            .read()?
+            .requested
+            .get(package)
+            .map(|r| r.version.clone());
+        let overridden = self.config.overrides().get(package).cloned();
+        let excluded = self
+            .config
+            .exclude_versions()
+            .get(package)
+            .cloned()
+            .unwrap_or_default();
 
-        Ok(solution)
+        if requested.is_none() && overridden.is_none() && excluded.is_empty() {
+            return Ok(None);
+        }
+
+        let mut constraint = match (&requested, &overridden) {
+            (Some(r), Some(o)) => r.intersection(o),
+            (Some(c), None) | (None, Some(c)) => c.clone(),
+            (None, None) => VersionConstraint::any(),
+        };
+        for version in excluded.iter() {
+            constraint = constraint.intersection(&VersionConstraint::excluding(version));
+        }
+
+        Ok(Some(EffectiveConstraint {
+            constraint,
+            requested,
+            overridden,
+            excluded,
+        }))
     }
-}
 
-fn step(n: u8, t: u8, emoji: Emoji, msg: &str) -> String {
-    let prefix = style(format!("[{n}/{t}]")).bold().dim();
-    format!("{prefix} {emoji}{msg}")
+    // Reports the outcome of the last attempt to fetch each of our repositories,
+    // in the order they're configured. Repositories that we haven't attempted to
+    // fetch yet are omitted.
+    pub fn fetch_summary(&self) -> FetchSummary {
+        let fetches = self.fetches.borrow();
+
+        FetchSummary {
+            repositories: self
+                .config
+                .repositories()
+                .iter()
+                .filter_map(|repo| fetches.get(&repo.name).cloned())
+                .collect(),
+        }
+    }
+
+    // Re-fetches the metadata for a single repository, leaving the rest of what
+    // we've already fetched alone.
+    pub fn refresh(&mut self, name: &str) -> Result<()> {
+        let repo = self
+            .config
+            .repositories()
+            .iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| RepositoryError::UnknownRepository {
+                repository: name.to_string(),
+            })?;
+
+        // If we haven't fetched anything yet, then fetching everything will
+        // get us the latest data for this repository anyways.
+        if self.repository.borrow().is_none() {
+            self.repository()?;
+            return Ok(());
+        }
+
+        let mut cached = self.repository.borrow_mut();
+        let repository = cached.as_mut().unwrap();
+        let result = repository.refresh(repo);
+        self.record_fetches(repository.fetches());
+
+        Ok(result?)
+    }
+
+    // Checks that each of our repositories is reachable, using the same client
+    // settings as fetching them, but without downloading their indices.
+    pub fn check(&self) -> Result<Vec<(String, Result<(), RepositoryError>)>> {
+        let mut builder = RepositoryBuilder::new(&self.config, self.cache.clone())?;
+        builder.with_rewrite(self.rewrite.clone());
+
+        Ok(builder
+            .check(self.config.repositories())
+            .into_iter()
+            .map(|(repo, result)| (repo.name, result))
+            .collect())
+    }
+
+    // Removes whatever our cache has that we'd never use again, such as the indices
+    // of repositories that we no longer have configured.
+    pub fn gc(&self) -> Result<CacheCleanup> {
+        let builder = RepositoryBuilder::new(&self.config, self.cache.clone())?;
+        Ok(builder.prune_cache(self.config.repositories())?)
+    }
+
+    // Audits every release in our repositories, see Repository::verify.
+    pub fn verify(&self, network: bool) -> Result<Vec<ReleaseIssue>> {
+        Ok(self.repository()?.verify(network))
+    }
+
+    // Reads a local package archive and makes it available to install, as if it
+    // came from one of our repositories, returning a specifier that requests
+    // exactly that archive. Its dependencies still come from our repositories.
+    // Once an install that requests it has been committed, the archive is recorded
+    // in our state, so that it's used from then on, as long as it hasn't changed.
+    pub fn add_archive<P: AsRef<Path>>(&mut self, path: P) -> Result<PackageSpecifier> {
+        let archive = Archive::open(path.as_ref())?;
+        let specifier = PackageSpecifier::new(
+            archive.name.clone(),
+            VersionConstraint::exact(&archive.version),
+            None,
+        );
+
+        // Anything we haven't fetched yet picks our archive up once we do, so
+        // there's no reason to fetch anything now.
+        if let Some(repository) = self.repository.get_mut() {
+            repository.add_archive(archive.clone());
+        }
+        self.archives.insert(archive.name.clone(), archive);
+
+        Ok(specifier)
+    }
+
+    // Summarizes the current environment, using only what we already know, so
+    // this never hits the network, and repositories we haven't tried to fetch
+    // yet are reported as having an unknown reachability.
+    pub fn status(&self) -> Result<Status> {
+        let state = self.db.read()?;
+        let fetches = self.fetches.borrow();
+
+        Ok(Status {
+            requested: state.requested.len(),
+            dependencies: state
+                .resolved
+                .keys()
+                .filter(|name| !state.requested.contains_key(*name))
+                .count(),
+            repositories: self
+                .config
+                .repositories()
+                .iter()
+                .map(|repo| RepositoryStatus {
+                    name: repo.name.clone(),
+                    url: repo.url.to_string(),
+                    reachable: fetches.get(&repo.name).map(|f| f.error.is_none()),
+                })
+                .collect(),
+            pkgdb: self.db.location()?,
+            state_version: state.version,
+        })
+    }
+}
+
+impl<'p, T> Installer<'p, T> {
+    fn console<S: AsRef<str>>(&self, msg: S) {
+        if let Some(cb) = &self.console {
+            (cb)(msg.as_ref());
+        }
+    }
+
+    fn check_deadline(&self) -> Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(InstallerError::DeadlineExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    fn emit(&self, events: &[Event]) {
+        if let Some(cb) = &self.events {
+            for event in events {
+                (cb)(event);
+            }
+        }
+    }
+
+    // Emits events once our transaction has been committed, followed by saying
+    // that it was, unless it didn't change anything, in which case nothing really
+    // happened, so there's nothing to tell anyone about.
+    fn committed(&self, changed: bool, mut events: Vec<Event>) {
+        if changed {
+            events.push(Event::TransactionCommitted);
+            self.emit(&events);
+        }
+    }
+
+    // Returns our repository, fetching all of the data for it if we haven't
+    // already done so.
+    fn repository(&self) -> Result<Ref<Repository>> {
+        if self.repository.borrow().is_none() {
+            let repository = self.fetch()?;
+            *self.repository.borrow_mut() = Some(repository);
+        }
+
+        Ok(Ref::map(self.repository.borrow(), |r| r.as_ref().unwrap()))
+    }
+
+    fn record_fetches(&self, fetches: &IndexMap<config::Repository, RepositoryFetch>) {
+        self.fetches.borrow_mut().extend(
+            fetches
+                .iter()
+                .map(|(repo, fetch)| (repo.name.clone(), fetch.clone())),
+        );
+    }
+
+    fn fetch(&self) -> Result<Repository> {
+        // Without any repositories, nothing can ever be resolved, and the errors
+        // from the resolver won't make it obvious why.
+        if self.config.repositories().is_empty() {
+            match self.config.empty_repositories() {
+                Level::Warn => self.warn("no repositories configured".to_string())?,
+                _ => return Err(RepositoryError::NoRepositories.into()),
+            }
+        }
+
+        let bar = self
+            .progress
+            .bar(self.config.repositories().len().try_into().unwrap());
+        let mut builder = RepositoryBuilder::new(&self.config, self.cache.clone())?;
+        builder.with_cache_dir(self.cache_dir.clone());
+        builder.with_clock(self.clock.clone());
+        builder.with_rewrite(self.rewrite.clone());
+        builder.with_environment(self.environment.clone());
+        let result = builder.fetch(self.config.repositories(), || bar.update(1));
+        bar.finish();
+
+        self.record_fetches(builder.fetches());
+        let mut repository = result?;
+        self.add_archives(&mut repository)?;
+
+        if self.warnings_as_errors && !repository.warnings().is_empty() {
+            return Err(InstallerError::Warnings(repository.warnings()));
+        }
+
+        Ok(repository)
+    }
+
+    // Warns about message, unless warnings are being treated as errors, in which
+    // case it's returned as one instead.
+    fn warn(&self, message: String) -> Result<()> {
+        if self.warnings_as_errors {
+            return Err(InstallerError::Warnings(vec![message]));
+        }
+
+        warn!(target: LOGNAME, "{}", message);
+        Ok(())
+    }
+
+    // Adds every archive that our state has recorded, as long as it's still the
+    // same archive that was added, along with any that have been added since.
+    fn add_archives(&self, repository: &mut Repository) -> Result<()> {
+        for (name, local) in self.db.read()?.archives {
+            let archive = Archive::open(Path::new(&local.path))?;
+            if archive.name != name || archive.digest != local.digest {
+                return Err(ArchiveError::Changed { path: local.path }.into());
+            }
+            repository.add_archive(archive);
+        }
+        for archive in self.archives.values() {
+            repository.add_archive(archive.clone());
+        }
+
+        Ok(())
+    }
+
+    // Requests package, recording the archive that it was added from, if it was,
+    // as part of whatever transaction we're in.
+    fn add_requested(&mut self, package: &PackageSpecifier) -> Result<()> {
+        self.db.add(package)?;
+        if let Some(archive) = self.archives.get(&package.name) {
+            self.db.add_archive(
+                &archive.name,
+                LocalArchive {
+                    path: archive.path.display().to_string(),
+                    digest: archive.digest.clone(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Everything that's already requested, with packages requested on top.
+    fn requested_with(
+        &self,
+        packages: &[PackageSpecifier],
+    ) -> Result<(
+        HashMap<PackageName, VersionConstraint>,
+        HashMap<PackageName, String>,
+    )> {
+        let mut requested = HashMap::new();
+        let mut pinned = HashMap::new();
+        for req in self.db.read()?.requested.into_values() {
+            requested.insert(req.name.clone(), req.version);
+            if let Some(repository) = req.repository {
+                pinned.insert(req.name, repository);
+            }
+        }
+        for package in packages {
+            requested.insert(package.name.clone(), package.version.clone());
+            if let Some(repository) = &package.repository {
+                pinned.insert(package.name.clone(), repository.clone());
+            }
+        }
+
+        Ok((requested, pinned))
+    }
+
+    fn resolve(
+        &self,
+        repository: &Repository,
+        requested: HashMap<PackageName, VersionConstraint>,
+        pinned: HashMap<PackageName, String>,
+    ) -> Result<Packages> {
+        let excluded = self.config.exclude_versions().clone();
+        self.resolve_excluding(repository, requested, pinned, excluded)
+    }
+
+    fn resolve_excluding(
+        &self,
+        repository: &Repository,
+        requested: HashMap<PackageName, VersionConstraint>,
+        pinned: HashMap<PackageName, String>,
+        excluded: HashMap<PackageName, Vec<Version>>,
+    ) -> Result<Packages> {
+        // A package that doesn't exist anywhere is the most common mistake, so we
+        // want to say exactly that, rather than that there was no solution. A
+        // repository can also list a package without any releases, which isn't
+        // the same thing as it not existing, so we say that instead.
+        for name in requested.keys() {
+            if repository.candidates(name)?.is_empty() {
+                if repository.package_names().contains(&name) {
+                    return Err(SolverError::NoAvailableVersions {
+                        package: name.clone(),
+                    }
+                    .into());
+                }
+
+                return Err(SolverError::PackageNotFound {
+                    package: name.clone(),
+                    searched_repositories: self
+                        .config
+                        .repositories()
+                        .iter()
+                        .map(|r| r.name.clone())
+                        .collect(),
+                    suggestions: suggestions(name, repository.package_names()),
+                }
+                .into());
+            }
+        }
+
+        // Check if anything that was requested can only be satisfied by versions
+        // that have been excluded, since the resolver would otherwise just tell
+        // us that there was no solution, without telling us why.
+        for (name, req) in requested.iter() {
+            if let Some(excluded) = excluded.get(name) {
+                let mut versions: Vec<Version> = repository
+                    .candidates(name)?
+                    .iter()
+                    .filter(|c| c.satisfies_with(req, self.allows_prereleases(name)))
+                    .map(Version::from)
+                    .collect();
+                versions.sort_by(|l, r| r.cmp(l));
+                versions.dedup();
+
+                if !versions.is_empty() && versions.iter().all(|v| excluded.contains(v)) {
+                    return Err(SolverError::ExcludedVersion {
+                        package: name.clone(),
+                        versions,
+                    }
+                    .into());
+                }
+            }
+        }
+
+        // Likewise, a request pinned to a repository that can't satisfy it should
+        // say so, rather than just failing to find a solution.
+        for (name, pin) in pinned.iter() {
+            let req = &requested[name];
+            let satisfiable = repository
+                .pinned_candidates(name, pin)?
+                .iter()
+                .any(|c| c.satisfies_with(req, self.allows_prereleases(name)));
+
+            if !satisfiable {
+                return Err(SolverError::PinnedUnavailable {
+                    package: name.clone(),
+                    repository: pin.clone(),
+                }
+                .into());
+            }
+        }
+
+        // An override that conflicts with what was explicitly requested can never
+        // be satisfied, so we report that directly too.
+        for (name, req) in requested.iter() {
+            if let Some(constraint) = self.config.overrides().get(name) {
+                let candidates: Vec<_> = repository
+                    .candidates(name)?
+                    .into_iter()
+                    .filter(|c| c.satisfies_with(req, self.allows_prereleases(name)))
+                    .collect();
+
+                if !candidates.is_empty()
+                    && !candidates
+                        .iter()
+                        .any(|c| c.satisfies_with(constraint, self.allows_prereleases(name)))
+                {
+                    return Err(SolverError::OverrideConflict {
+                        package: name.clone(),
+                        requested: req.clone(),
+                        constraint: constraint.clone(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        let mut solution = self.solve_excluding(
+            repository,
+            requested.clone(),
+            pinned.clone(),
+            excluded.clone(),
+        )?;
+        if self.recommends && !self.no_deps {
+            solution = self.recommended(repository, requested, pinned, excluded, solution)?;
+        }
+
+        // Assertions guard the final resolved versions, however we got to them,
+        // and we report every one that fails rather than just the first.
+        let mut failures: Vec<AssertionFailure> = self
+            .config
+            .assertions()
+            .iter()
+            .filter_map(|(name, constraint)| {
+                let package = solution.get(name)?;
+                (!package.satisfies(constraint)).then(|| AssertionFailure {
+                    package: name.clone(),
+                    version: package.version().clone(),
+                    constraint: constraint.clone(),
+                })
+            })
+            .collect();
+        if !failures.is_empty() {
+            failures.sort_by(|l, r| l.package.cmp(&r.package));
+            return Err(SolverError::AssertionFailed { failures }.into());
+        }
+
+        // Yanked releases are only ever picked when they were already locked in, but
+        // whoever is installing them should still know, and why, so that they can
+        // decide whether that's safe.
+        for (name, package) in solution.iter() {
+            if let Some(yanked) = package.source().yanked() {
+                self.warn(format!(
+                    "{} {} has been {}",
+                    name,
+                    package.version(),
+                    yanked
+                ))?;
+            }
+        }
+
+        Ok(solution)
+    }
+
+    // Downloads every package in solution that came from one of our repositories,
+    // from the repository that it came from, or finds the local archive that it
+    // came from otherwise.
+    fn download_all(
+        &self,
+        repository: &Repository,
+        solution: &Packages,
+    ) -> Result<Vec<(PackageName, VfsPath)>> {
+        let bar = self.progress.bar(solution.len().try_into().unwrap());
+        let mut archives = Vec::new();
+        let result: Result<()> = solution.iter().try_for_each(|(name, package)| {
+            // Anything that didn't come from one of our repositories came from a
+            // local archive, which we already have.
+            let archive = match package.source().repository() {
+                Some(repo) => Some(repository.pinned_download(name, package.version(), repo)?),
+                None => repository.archive(name).map(|a| a.file()).transpose()?,
+            };
+            if let Some(archive) = archive {
+                archives.push((name.clone(), archive));
+            }
+            bar.update(1);
+            Ok(())
+        });
+        bar.finish();
+        result?;
+
+        // Anything we had to warn about while downloading, such as not being able
+        // to cache what we downloaded, counts as much as what we warned about while
+        // fetching.
+        if self.warnings_as_errors && !repository.warnings().is_empty() {
+            return Err(InstallerError::Warnings(repository.warnings()));
+        }
+
+        Ok(archives)
+    }
+
+    // Every package that's new, or has changed, is extracted into our staging
+    // area, and only once all of them have been is anything moved into the
+    // target, so a bad archive can never leave the target half installed. Any
+    // package that's installed but no longer part of solution has its files
+    // removed, and solution is recorded as what's resolved. Every operation that
+    // changes what's resolved goes through here, so that the target always
+    // follows along.
+    fn install_all(
+        &mut self,
+        archives: &[(PackageName, VfsPath)],
+        solution: &Packages,
+    ) -> Result<()> {
+        let state = self.db.read()?;
+        let previous = state.resolved;
+        let resolved = resolution(solution);
+
+        let staging = self.db.staging()?;
+        let mut staged = Vec::new();
+        let bar = self.progress.bar(archives.len().try_into().unwrap());
+        for (name, archive) in archives.iter() {
+            let unchanged = previous.get(name) == resolved.get(name);
+            if !unchanged || self.db.installed(name)?.files.is_empty() {
+                let dir = staging
+                    .join(&name.to_string())
+                    .map_err(ArchiveError::from)?;
+                dir.create_dir_all().map_err(ArchiveError::from)?;
+
+                let reader = archive.open_file().map_err(ArchiveError::from)?;
+                let installed = archive::extract(reader, archive.as_str(), &dir)?;
+                staged.push((name.clone(), installed));
+            }
+            bar.update(1);
+        }
+        bar.finish();
+
+        let installed: BTreeSet<&PackageName> = state
+            .installed
+            .keys()
+            .chain(state.directories.keys())
+            .collect();
+        for name in installed.into_iter() {
+            if !solution.contains_key(name) {
+                staged.push((name.clone(), Installed::default()));
+            }
+        }
+
+        self.db.swap(staged)?;
+        Ok(self.db.set_resolved(resolved)?)
+    }
+
+    // Every operation that changes what's resolved goes through the same steps,
+    // fetching our repositories, working out a solution, downloading it and then
+    // installing it, checking our deadline after each of them. Only how the
+    // solution is worked out differs, which is up to solve.
+    fn install_solved<F>(&mut self, solved: &str, solve: F) -> Result<Packages>
+    where
+        F: FnOnce(&Self, &Repository) -> Result<Packages>,
+    {
+        // Grab our repository, and pre-emptively fetch all of the data
+        let repository = self.repository()?;
+        self.console(step(1, 4, OFFICE_PAPER, "Fetched package metadata"));
+        self.check_deadline()?;
+
+        let solution = solve(self, &repository)?;
+        self.console(step(2, 4, LOOKING_GLASS, solved));
+        self.check_deadline()?;
+
+        // Nothing is recorded as installed unless every package was downloaded,
+        // and matched its digest.
+        let archives = self.download_all(&repository, &solution)?;
+        self.console(step(3, 4, TRUCK, "Downloaded packages"));
+        drop(repository);
+        self.check_deadline()?;
+
+        // Our files are only swapped into place once this has been committed,
+        // and that's journaled, so even dying part way through is recoverable.
+        self.install_all(&archives, &solution)?;
+        self.console(step(4, 4, PACKAGE, "Installed packages"));
+
+        Ok(solution)
+    }
+
+    // Resolves requested to a full set of packages, and installs them.
+    fn install_requested(
+        &mut self,
+        requested: HashMap<PackageName, VersionConstraint>,
+        pinned: HashMap<PackageName, String>,
+    ) -> Result<Packages> {
+        self.install_solved("Resolved dependencies", |installer, repository| {
+            installer.resolve(repository, requested, pinned)
+        })
+    }
+
+    fn solve(
+        &self,
+        repository: &Repository,
+        requested: HashMap<PackageName, VersionConstraint>,
+        pinned: HashMap<PackageName, String>,
+    ) -> Result<Packages> {
+        let excluded = self.config.exclude_versions().clone();
+        self.solve_excluding(repository, requested, pinned, excluded)
+    }
+
+    fn solve_excluding(
+        &self,
+        repository: &Repository,
+        requested: HashMap<PackageName, VersionConstraint>,
+        pinned: HashMap<PackageName, String>,
+        excluded: HashMap<PackageName, Vec<Version>>,
+    ) -> Result<Packages> {
+        let state = self.db.read()?;
+
+        // Only what's newly requested goes without its dependencies, everything
+        // that was already requested still gets them, as they were locked in.
+        let no_deps: HashSet<PackageName> = match self.no_deps {
+            true => requested
+                .iter()
+                .filter(|(name, constraint)| {
+                    let before = match &self.requested_before {
+                        Some(before) => before.get(*name),
+                        None => state.requested.get(*name).map(|r| &r.version),
+                    };
+                    before != Some(*constraint)
+                })
+                .map(|(name, _)| name.clone())
+                .collect(),
+            false => HashSet::new(),
+        };
+        if !no_deps.is_empty() {
+            let mut names: Vec<String> = no_deps.iter().map(|n| n.to_string()).collect();
+            names.sort();
+            self.warn(format!("ignoring the dependencies of {}", names.join(", ")))?;
+        }
+
+        // Whatever is already resolved is as good as locked, along with anything
+        // that we're trying to get back to.
+        let mut locked: HashMap<PackageName, Version> = state
+            .resolved
+            .into_iter()
+            .map(|(name, package)| (name, package.version))
+            .collect();
+        locked.extend(self.preferred.clone());
+
+        let choose = |name: &PackageName, choices: &[Choice]| self.choose(name, choices);
+        let spinner = self.progress.spinner("Resolving dependencies");
+        let solver = Solver::new(repository)
+            .with_no_deps(no_deps)
+            .with_environment(self.target.clone())
+            .with_preferred(self.preferred.clone())
+            .with_upgrading(self.upgrading.clone(), self.strategy)
+            .with_locked(locked)
+            .with_prereleases(self.prereleases)
+            .with_prereleases_for(self.prerelease_packages.clone())
+            .with_excluded(excluded)
+            .with_overrides(self.config.overrides().clone())
+            .with_limit(self.budget.get().or(self.config.max_resolve_decisions()))
+            .with_deadline(self.deadline)
+            .with_pinned(pinned);
+        let decisions = Cell::new(0);
+        let solution = solver.resolve(
+            requested,
+            || spinner.update(1),
+            |progress| {
+                decisions.set(progress.decisions as u64);
+                if let Some(cb) = &self.resolve_progress {
+                    (cb)(progress);
+                }
+            },
+            self.chooser.as_ref().map(|_| &choose as &Chooser),
+        );
+        spinner.finish();
+
+        if let Some(remaining) = self.budget.get() {
+            self.budget
+                .set(Some(remaining.saturating_sub(decisions.get())));
+        }
+
+        match solution {
+            Err(SolverError::DeadlineExceeded) => Err(InstallerError::DeadlineExceeded),
+            solution => Ok(solution?),
+        }
+    }
+
+    // Which of several repositories to take a version from is up to our chooser,
+    // but it's only ever asked once for each package and version, however many
+    // times we resolve, such as while installing best effort.
+    fn choose(&self, name: &PackageName, choices: &[Choice]) -> usize {
+        let (chooser, version) = match (&self.chooser, choices.first()) {
+            (Some(chooser), Some(choice)) => (chooser, choice.version.clone()),
+            _ => return 0,
+        };
+
+        let key = (name.clone(), version);
+        let remembered = self.chosen.borrow().get(&key).cloned();
+        if let Some(idx) = remembered.and_then(|s| choices.iter().position(|c| c.source == s)) {
+            return idx;
+        }
+
+        let idx = chooser(name, choices);
+        if let Some(choice) = choices.get(idx) {
+            self.chosen.borrow_mut().insert(key, choice.source.clone());
+        }
+
+        idx
+    }
+
+    // Recommended packages get installed whenever they can be, but they must
+    // never cause resolving to fail, so we add them one at a time, re-resolving
+    // each time, and skip any that can't be resolved alongside everything else.
+    fn recommended(
+        &self,
+        repository: &Repository,
+        mut requested: HashMap<PackageName, VersionConstraint>,
+        pinned: HashMap<PackageName, String>,
+        excluded: HashMap<PackageName, Vec<Version>>,
+        mut solution: Packages,
+    ) -> Result<Packages> {
+        let mut skipped = HashSet::new();
+
+        loop {
+            let mut pending = BTreeMap::new();
+            for (name, package) in solution.iter() {
+                for (dep, constraint) in
+                    repository.recommends(name, package.version(), package.source().repository())?
+                {
+                    if !solution.contains_key(&dep) && !skipped.contains(&dep) {
+                        pending.entry(dep).or_insert(constraint);
+                    }
+                }
+            }
+
+            let (dep, constraint) = match pending.into_iter().next() {
+                Some(recommended) => recommended,
+                None => break,
+            };
+
+            let mut attempt = requested.clone();
+            attempt.insert(dep.clone(), constraint);
+            match self.solve_excluding(
+                repository,
+                attempt.clone(),
+                pinned.clone(),
+                excluded.clone(),
+            ) {
+                Ok(result) => {
+                    requested = attempt;
+                    solution = result;
+                }
+                Err(InstallerError::DeadlineExceeded) => {
+                    return Err(InstallerError::DeadlineExceeded)
+                }
+                Err(err) => {
+                    self.warn(format!("skipping recommended package {}: {}", dep, err))?;
+                    skipped.insert(dep);
+                }
+            }
+        }
+
+        Ok(solution)
+    }
+}
+
+// Finds the names closest to name, for when name doesn't exist, so that we can
+// suggest what might have been meant.
+fn suggestions(name: &PackageName, names: Vec<&PackageName>) -> Vec<PackageName> {
+    let target = name.to_string().to_ascii_lowercase();
+    let mut scored: Vec<(usize, &PackageName)> = names
+        .into_iter()
+        .map(|n| (levenshtein(&target, &n.to_string().to_ascii_lowercase()), n))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .collect();
+    scored.sort_by(|l, r| l.0.cmp(&r.0).then_with(|| l.1.cmp(r.1)));
+
+    scored
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, n)| n.clone())
+        .collect()
+}
+
+// The requested packages that depend on package, either directly or through
+// any of their own dependencies, within solution.
+fn dependents(
+    solution: &Packages,
+    requested: &HashMap<PackageName, VersionConstraint>,
+    package: &PackageName,
+) -> Vec<PackageName> {
+    let graph = dependency_graph(solution);
+    let mut dependents: Vec<PackageName> = requested
+        .keys()
+        .filter(|n| *n != package && reachable(&graph, n).contains(package))
+        .cloned()
+        .collect();
+    dependents.sort();
+
+    dependents
+}
+
+// The names of what each package in solution depends on, taken from the exact
+// candidate that was resolved, so its source and markers are respected.
+fn dependency_graph(solution: &Packages) -> HashMap<&PackageName, Vec<PackageName>> {
+    solution
+        .iter()
+        .map(|(name, package)| (name, package.dependency_names()))
+        .collect()
+}
+
+// Everything that root depends on, directly or not, including root itself.
+fn reachable<'g>(
+    graph: &'g HashMap<&PackageName, Vec<PackageName>>,
+    root: &'g PackageName,
+) -> HashSet<&'g PackageName> {
+    let mut seen = HashSet::new();
+    let mut pending = vec![root];
+    while let Some(name) = pending.pop() {
+        if seen.insert(name) {
+            pending.extend(graph.get(name).into_iter().flatten());
+        }
+    }
+
+    seen
+}
+
+fn names(packages: &[PackageSpecifier]) -> Vec<PackageName> {
+    packages.iter().map(|p| p.name.clone()).collect()
+}
+
+fn step(n: u8, t: u8, emoji: Emoji, msg: &str) -> String {
+    let prefix = style(format!("[{n}/{t}]")).bold().dim();
+    format!("{prefix} {emoji}{msg}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use vfs::MemoryFS;
+
+    use super::*;
+
+    const REPOSITORY: &str = "https://example.com/index.json";
+
+    // Every release in our index, along with what it depends on.
+    const RELEASES: &[(&str, &str, &str)] = &[
+        ("foo", "1.0.0", "{}"),
+        ("foo", "2.0.0", r#"{"bar": "^1"}"#),
+        ("bar", "1.0.0", r#"{"baz": "^1"}"#),
+        ("baz", "1.0.0", "{}"),
+        ("baz", "1.1.0", "{}"),
+        ("baz", "1.2.0-beta.1", "{}"),
+    ];
+
+    // The archive for a release, with a single file named after its package, so
+    // that no two packages ever install the same file.
+    fn artifact(name: &str, version: &str) -> (Vec<u8>, Digest) {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(version.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, format!("{}.txt", name), version.as_bytes())
+            .unwrap();
+        let data = builder.into_inner().unwrap().finish().unwrap();
+
+        let mut writer = DigestAlgorithm::Sha256.writer(std::io::sink());
+        writer.write_all(&data).unwrap();
+        (data, writer.finish().unwrap())
+    }
+
+    fn index() -> String {
+        let mut packages: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (name, version, deps) in RELEASES {
+            let (_, digest) = artifact(name, version);
+            packages.entry(name).or_default().push(format!(
+                r#""{}": {{"urls": ["https://example.com/{}-{}.tar.gz"], "digest": "{}", "dependencies": {}}}"#,
+                version, name, version, digest.hex, deps
+            ));
+        }
+        let packages: Vec<String> = packages
+            .into_iter()
+            .map(|(name, releases)| format!(r#""{}": {{{}}}"#, name, releases.join(", ")))
+            .collect();
+
+        format!(
+            r#"{{"meta": {{"name": "example"}}, "packages": {{{}}}}}"#,
+            packages.join(", ")
+        )
+    }
+
+    fn builder() -> InstallerBuilder {
+        builder_with("")
+    }
+
+    fn builder_with(config: &str) -> InstallerBuilder {
+        builder_in(VfsPath::new(MemoryFS::new()), config)
+    }
+
+    // Every release is already in our cache, so nothing is ever downloaded.
+    fn builder_in(root: VfsPath, config: &str) -> InstallerBuilder {
+        root.join("mqpkg.yml")
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(format!("repositories:\n  - {}\n{}", REPOSITORY, config).as_bytes())
+            .unwrap();
+        let artifacts = root.join("pkgdb/cache/artifacts").unwrap();
+        artifacts.create_dir_all().unwrap();
+        for (name, version, _) in RELEASES {
+            let (data, digest) = artifact(name, version);
+            artifacts
+                .join(&format!("{}-{}", digest.algorithm, digest.hex))
+                .unwrap()
+                .create_file()
+                .unwrap()
+                .write_all(&data)
+                .unwrap();
+        }
+        let config = Config::load(&root).unwrap();
+
+        InstallerBuilder::new(config, root, "test")
+    }
+
+    fn name(name: &str) -> PackageName {
+        PackageName::new(name).unwrap()
+    }
+
+    #[test]
+    fn preloaded_available() {
+        let installer: Installer<()> = builder().with_index(REPOSITORY, index()).build().unwrap();
+
+        let mut versions: Vec<Version> = installer
+            .available(&name("foo"))
+            .unwrap()
+            .into_iter()
+            .map(|available| available.version)
+            .collect();
+        versions.sort();
+        assert_eq!(versions, vec![Version::new(1, 0, 0), Version::new(2, 0, 0)]);
+    }
+
+    #[test]
+    fn preloaded_solutions() {
+        let installer: Installer<()> = builder().with_index(REPOSITORY, index()).build().unwrap();
+
+        let solutions = installer.solutions(&["foo".parse().unwrap()], 1).unwrap();
+        let solution = &solutions.solutions[0];
+        assert_eq!(solution[&name("foo")].version, Version::new(2, 0, 0));
+        assert_eq!(solution[&name("bar")].version, Version::new(1, 0, 0));
+    }
+
+    #[test]
+    fn solutions_pass_assertions() {
+        let installer: Installer<()> = builder_with("assertions:\n  foo: \">=2.0.0\"\n")
+            .with_index(REPOSITORY, index())
+            .build()
+            .unwrap();
+
+        // Excluding foo 2.0.0 would give us foo 1.0.0, but that fails our assertion.
+        let solutions = installer.solutions(&["foo".parse().unwrap()], 5).unwrap();
+        assert_eq!(solutions.solutions.len(), 1);
+        assert_eq!(
+            solutions.solutions[0][&name("foo")].version,
+            Version::new(2, 0, 0)
+        );
+        assert!(!solutions.more);
+    }
+
+    #[test]
+    fn no_deps_only_for_new_requests() -> Result<()> {
+        let mut installer: Installer<()> = builder().with_index(REPOSITORY, index()).build()?;
+        let bar: PackageSpecifier = "bar".parse().unwrap();
+        transaction!(installer.db, { installer.db.add(&bar)? });
+
+        // foo's dependency on bar is ignored, but bar was already requested, so it
+        // still gets its dependency on baz.
+        installer.with_no_deps(true);
+        let solutions = installer.solutions(&["foo".parse().unwrap()], 1)?;
+        let names: Vec<&PackageName> = solutions.solutions[0].keys().collect();
+        assert_eq!(names, vec![&name("bar"), &name("baz"), &name("foo")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_deps_through_install() -> Result<()> {
+        let mut installer: Installer<()> = builder().with_index(REPOSITORY, index()).build()?;
+        installer.with_no_deps(true);
+
+        // Our requests are added before resolving, but they're still new.
+        let resolved = installer.install(&["foo".parse().unwrap()])?;
+        let names: Vec<&PackageName> = resolved.keys().collect();
+        assert_eq!(names, vec![&name("foo")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn prereleases() -> Result<()> {
+        let mut installer: Installer<()> = builder().with_index(REPOSITORY, index()).build()?;
+        let resolved = |installer: &Installer<()>, specifier: &str| -> Result<Version> {
+            let solutions = installer.solutions(&[specifier.parse().unwrap()], 1)?;
+            Ok(solutions.solutions[0][&name("baz")].version.clone())
+        };
+        let satisfies = |installer: &Installer<()>| -> Result<Vec<Version>> {
+            Ok(installer
+                .available(&name("baz"))?
+                .into_iter()
+                .filter(|available| available.satisfies)
+                .map(|available| available.version)
+                .collect())
+        };
+        let beta = Version::parse("1.2.0-beta.1").unwrap();
+
+        // Pre-releases are never picked unless they've been allowed.
+        assert_eq!(resolved(&installer, "baz@latest")?, Version::new(1, 1, 0));
+
+        installer.with_prereleases_for(&[name("baz")]);
+        assert_eq!(resolved(&installer, "baz@latest")?, beta);
+        assert_eq!(resolved(&installer, "baz")?, beta);
+
+        // Stable never allows a pre-release, even once they've been allowed.
+        assert_eq!(resolved(&installer, "baz@stable")?, Version::new(1, 1, 0));
+
+        // What's available agrees with what we'd resolve.
+        transaction!(installer.db, {
+            installer.db.add(&"baz@stable".parse().unwrap())?
+        });
+        assert!(!satisfies(&installer)?.contains(&beta));
+        transaction!(installer.db, {
+            installer.db.add(&"baz@latest".parse().unwrap())?
+        });
+        assert!(satisfies(&installer)?.contains(&beta));
+
+        Ok(())
+    }
+
+    #[test]
+    fn warnings_as_errors() -> Result<()> {
+        let mut installer: Installer<()> = builder().with_index(REPOSITORY, index()).build()?;
+        installer.with_no_deps(true);
+        installer.with_warnings_as_errors(true);
+
+        let err = installer
+            .solutions(&["foo".parse().unwrap()], 1)
+            .unwrap_err();
+        assert!(
+            matches!(err, InstallerError::Warnings(w) if w == ["ignoring the dependencies of foo"])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn uninstall_reports_dependencies() -> Result<()> {
+        let events = RefCell::new(Vec::new());
+        let mut installer: Installer<()> = builder().with_index(REPOSITORY, index()).build()?;
+        installer.with_events(|event| events.borrow_mut().push(event.clone()));
+
+        let foo: PackageSpecifier = "foo".parse().unwrap();
+        let resolved = installer.solutions(&[foo.clone()], 1)?.solutions.remove(0);
+        transaction!(installer.db, {
+            installer.db.add(&foo)?;
+            installer.db.set_resolved(resolved)?;
+        });
+
+        installer.uninstall(&[name("foo")])?;
+        let removed: Vec<(PackageName, Reason)> = events
+            .borrow()
+            .iter()
+            .filter_map(|event| match event {
+                Event::PackageRemoved { name, reason, .. } => Some((name.clone(), *reason)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            removed,
+            vec![
+                (name("foo"), Reason::Requested),
+                (name("bar"), Reason::Dependency),
+                (name("baz"), Reason::Dependency),
+            ]
+        );
+
+        Ok(())
+    }
+
+    fn upgraded(strategy: UpgradeStrategy) -> Result<Resolution> {
+        let mut installer: Installer<()> = builder().with_index(REPOSITORY, index()).build()?;
+
+        // Everything starts out resolved with the older baz, as if it had been
+        // installed before baz 1.1.0 was released.
+        let foo: PackageSpecifier = "foo".parse().unwrap();
+        let resolved = installer
+            .solutions(&[foo.clone(), "baz@1.0.0".parse().unwrap()], 1)?
+            .solutions
+            .remove(0);
+        transaction!(installer.db, {
+            installer.db.add(&foo)?;
+            installer.db.set_resolved(resolved)?;
+        });
+
+        installer.upgrade(&[name("foo")], strategy)
+    }
+
+    #[test]
+    fn upgrade_strategies() -> Result<()> {
+        let resolved = upgraded(UpgradeStrategy::OnlyIfNeeded)?;
+        assert_eq!(resolved[&name("baz")].version, Version::new(1, 0, 0));
+
+        let resolved = upgraded(UpgradeStrategy::Eager)?;
+        assert_eq!(resolved[&name("baz")].version, Version::new(1, 1, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn target_follows_every_operation() -> Result<()> {
+        let root = VfsPath::new(MemoryFS::new());
+        let mut installer: Installer<()> = builder_in(root.clone(), "")
+            .with_index(REPOSITORY, index())
+            .build()?;
+        let installed = |names: &[&str]| {
+            RELEASES.iter().all(|(name, _, _)| {
+                let path = root.join(&format!("{}.txt", name)).unwrap();
+                path.exists().unwrap() == names.contains(name)
+            })
+        };
+
+        installer.install(&["foo".parse().unwrap()])?;
+        assert!(installed(&["foo", "bar", "baz"]));
+
+        installer.uninstall(&[name("foo")])?;
+        assert!(installed(&[]));
+
+        installer.install_dependencies(&["foo".parse().unwrap()])?;
+        assert!(installed(&["bar", "baz"]));
+
+        installer.reset()?;
+        assert!(installed(&[]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_op_emits_nothing() -> Result<()> {
+        let events = RefCell::new(Vec::new());
+        let mut installer: Installer<()> = builder().with_index(REPOSITORY, index()).build()?;
+        installer.with_events(|event| events.borrow_mut().push(event.clone()));
+
+        installer.install(&["foo".parse().unwrap()])?;
+        installer.reset()?;
+        assert_eq!(
+            events
+                .borrow()
+                .iter()
+                .filter(|e| matches!(e, Event::TransactionCommitted))
+                .count(),
+            2
+        );
+
+        // Nothing is left to reset, or to install dependencies for.
+        events.borrow_mut().clear();
+        installer.reset()?;
+        installer.install_dependencies(&["baz".parse().unwrap()])?;
+        assert!(events.borrow().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn archives_recorded_once_installed() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qux-1.0.0.tar.gz");
+        let metadata = br#"{"name": "qux", "version": "1.0.0", "dependencies": {"baz": "^1"}}"#;
+        let mut tarball = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(metadata.len() as u64);
+        header.set_mode(0o644);
+        tarball
+            .append_data(&mut header, "metadata.json", &metadata[..])
+            .unwrap();
+        std::fs::write(&path, tarball.into_inner().unwrap().finish().unwrap()).unwrap();
+
+        let mut installer: Installer<()> = builder().with_index(REPOSITORY, index()).build()?;
+        let qux = installer.add_archive(&path)?;
+        assert_eq!(
+            qux.version,
+            VersionConstraint::exact(&Version::new(1, 0, 0))
+        );
+
+        // An install that fails leaves nothing behind, including the archive.
+        assert!(installer
+            .install(&[qux.clone(), "missing".parse().unwrap()])
+            .is_err());
+        assert!(installer.db.read()?.archives.is_empty());
+
+        installer.install(&[qux])?;
+        assert!(installer.db.read()?.archives.contains_key(&name("qux")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn install_dependencies() {
+        let mut installer: Installer<()> =
+            builder().with_index(REPOSITORY, index()).build().unwrap();
+
+        let resolved = installer
+            .install_dependencies(&["foo".parse().unwrap()])
+            .unwrap();
+        assert_eq!(
+            resolved.keys().collect::<Vec<_>>(),
+            vec![&name("bar"), &name("baz")]
+        );
+
+        // Only what foo depends on directly is requested, baz is only there for bar.
+        let requested = installer.db.read().unwrap().requested;
+        assert_eq!(requested.keys().collect::<Vec<_>>(), vec![&name("bar")]);
+    }
+
+    #[test]
+    fn remembers_choices() {
+        let asked = std::cell::Cell::new(0);
+        let mut installer: Installer<()> = builder().build().unwrap();
+        installer.with_chooser(|_, _| {
+            asked.set(asked.get() + 1);
+            1
+        });
+
+        let choices: Vec<Choice> = ["first", "second"]
+            .iter()
+            .map(|source| Choice {
+                version: Version::new(1, 0, 0),
+                source: source.to_string(),
+            })
+            .collect();
+        assert_eq!(installer.choose(&name("foo"), &choices), 1);
+        assert_eq!(installer.choose(&name("foo"), &choices), 1);
+        assert_eq!(asked.get(), 1);
+
+        // The same source is still chosen when the choices come in another order.
+        let reversed: Vec<Choice> = choices.into_iter().rev().collect();
+        assert_eq!(installer.choose(&name("foo"), &reversed), 0);
+        assert_eq!(asked.get(), 1);
+    }
+
+    #[test]
+    fn unknown_index() {
+        let result: Result<Installer<()>> = builder().with_index("unknown", index()).build();
+        assert!(matches!(
+            result,
+            Err(InstallerError::RepositoryError(
+                RepositoryError::UnknownRepository { .. }
+            ))
+        ));
+    }
 }
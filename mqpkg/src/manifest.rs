@@ -0,0 +1,226 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ManifestError;
+use crate::types::{PackageName, PackageSpecifier, Resolution, VersionConstraint};
+
+const MANIFEST_VERSION: u32 = 1;
+
+const LOCKFILE_FILENAME: &str = "mqpkg.lock";
+const LOCKFILE_VERSION: u32 = 1;
+
+type Result<T, E = ManifestError> = core::result::Result<T, E>;
+
+// A manifest captures what was asked for, rather than what it resolved to, so
+// that the same environment can be recreated elsewhere, resolving fresh against
+// whatever repositories are available there. Unlike our state file, this is
+// meant to be read and edited by people, so its format needs to stay stable.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    pub requests: Vec<ManifestRequest>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ManifestRequest {
+    pub name: PackageName,
+    #[serde(default = "VersionConstraint::any")]
+    pub version: VersionConstraint,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub(crate) fn new(mut requests: Vec<ManifestRequest>) -> Manifest {
+        requests.sort_by(|l, r| l.name.cmp(&r.name));
+        Manifest {
+            version: MANIFEST_VERSION,
+            requests,
+        }
+    }
+
+    pub fn load<R: Read>(reader: R) -> Result<Manifest> {
+        let manifest: Manifest = serde_yaml::from_reader(reader)
+            .map_err(|source| ManifestError::InvalidManifest { source })?;
+
+        if manifest.version != MANIFEST_VERSION {
+            return Err(ManifestError::UnsupportedVersion {
+                version: manifest.version,
+            });
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn save<W: Write>(&self, writer: W) -> Result<()> {
+        serde_yaml::to_writer(writer, self)
+            .map_err(|source| ManifestError::InvalidManifest { source })
+    }
+
+    pub(crate) fn specifiers(&self) -> Vec<PackageSpecifier> {
+        self.requests
+            .iter()
+            .map(|req| PackageSpecifier {
+                name: req.name.clone(),
+                version: req.version.clone(),
+                repository: req.repository.clone(),
+                metadata: req.metadata.clone(),
+            })
+            .collect()
+    }
+}
+
+// A flat list of exact pins, one name==version per line like pip's frozen
+// requirements, for other tools that only understand that. Unlike a manifest,
+// this is what was resolved, rather than what was asked for, so installing it
+// reproduces exactly the same set of packages.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Requirements {
+    pub pins: Vec<Pin>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Pin {
+    pub name: PackageName,
+    pub version: Version,
+    // Written as a comment, so it's purely informational.
+    pub source: Option<String>,
+}
+
+impl Requirements {
+    pub fn new(resolution: &Resolution, sources: bool) -> Requirements {
+        // Resolutions are ordered by name already, so our pins are too.
+        Requirements {
+            pins: resolution
+                .values()
+                .map(|package| Pin {
+                    name: package.name.clone(),
+                    version: package.version.clone(),
+                    source: sources.then(|| package.source.clone()),
+                })
+                .collect(),
+        }
+    }
+
+    // Blank lines, and anything after a #, are ignored.
+    pub fn load<R: Read>(reader: R) -> Result<Requirements> {
+        let mut pins = Vec::new();
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            let (pin, source) = match line.split_once('#') {
+                Some((pin, comment)) => (pin.trim(), Some(comment.trim())),
+                None => (line.trim(), None),
+            };
+            if pin.is_empty() {
+                continue;
+            }
+
+            let invalid = || ManifestError::InvalidRequirement {
+                line: line.to_string(),
+            };
+            let (name, version) = pin.split_once("==").ok_or_else(invalid)?;
+            pins.push(Pin {
+                name: name.trim().parse().map_err(|_| invalid())?,
+                version: version.trim().parse().map_err(|_| invalid())?,
+                source: source.filter(|s| !s.is_empty()).map(|s| s.to_string()),
+            });
+        }
+
+        pins.sort_by(|l, r| l.name.cmp(&r.name));
+        Ok(Requirements { pins })
+    }
+
+    pub fn save<W: Write>(&self, mut writer: W) -> Result<()> {
+        for pin in self.pins.iter() {
+            match &pin.source {
+                Some(source) => writeln!(writer, "{}=={}  # {}", pin.name, pin.version, source)?,
+                None => writeln!(writer, "{}=={}", pin.name, pin.version)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn specifiers(&self) -> Vec<PackageSpecifier> {
+        self.pins
+            .iter()
+            .map(|pin| {
+                PackageSpecifier::new(
+                    pin.name.clone(),
+                    VersionConstraint::exact(&pin.version),
+                    None,
+                )
+            })
+            .collect()
+    }
+}
+
+// A lockfile records exactly what was resolved, down to the repository that each
+// package came from and its digest, along with what was asked for, so that
+// installing from it gets exactly the same packages on every machine, without
+// resolving again.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Lockfile {
+    pub version: u32,
+    pub requests: Vec<ManifestRequest>,
+    pub packages: Vec<LockedPackage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct LockedPackage {
+    pub name: PackageName,
+    pub version: Version,
+    pub repository: String,
+    // Algorithm names to hex digests, like in a repository index.
+    pub digests: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    pub fn filename() -> &'static str {
+        LOCKFILE_FILENAME
+    }
+
+    pub(crate) fn new(
+        mut requests: Vec<ManifestRequest>,
+        mut packages: Vec<LockedPackage>,
+    ) -> Lockfile {
+        requests.sort_by(|l, r| l.name.cmp(&r.name));
+        packages.sort_by(|l, r| l.name.cmp(&r.name));
+        Lockfile {
+            version: LOCKFILE_VERSION,
+            requests,
+            packages,
+        }
+    }
+
+    pub fn load<R: Read>(reader: R) -> Result<Lockfile> {
+        let lockfile: Lockfile = serde_yaml::from_reader(reader)
+            .map_err(|source| ManifestError::InvalidLockfile { source })?;
+
+        if lockfile.version != LOCKFILE_VERSION {
+            return Err(ManifestError::UnsupportedVersion {
+                version: lockfile.version,
+            });
+        }
+
+        Ok(lockfile)
+    }
+
+    pub fn save<W: Write>(&self, writer: W) -> Result<()> {
+        serde_yaml::to_writer(writer, self)
+            .map_err(|source| ManifestError::InvalidLockfile { source })
+    }
+
+    pub(crate) fn specifiers(&self) -> Vec<PackageSpecifier> {
+        Manifest::new(self.requests.clone()).specifiers()
+    }
+}
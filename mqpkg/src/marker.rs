@@ -0,0 +1,426 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::errors::MarkerError;
+
+type Result<T, E = MarkerError> = core::result::Result<T, E>;
+
+// A marker is a condition on the environment that we're resolving for, in the
+// style of PEP 508, like `python_version >= "3.8" and sys_platform == "linux"`,
+// which decides whether a dependency applies at all.
+#[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub(crate) struct Marker {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare { left: Value, op: Op, right: Value },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Value {
+    Variable(String),
+    Literal(String),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+    NotIn,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Op(Op),
+    LParen,
+    RParen,
+    And,
+    Or,
+}
+
+impl Marker {
+    // Variables that the environment doesn't have a value for are treated as
+    // empty, so a marker about something we know nothing about won't match.
+    pub(crate) fn evaluate(&self, env: &HashMap<String, String>) -> bool {
+        self.expr.evaluate(env)
+    }
+}
+
+impl Expr {
+    fn evaluate(&self, env: &HashMap<String, String>) -> bool {
+        match self {
+            Expr::And(l, r) => l.evaluate(env) && r.evaluate(env),
+            Expr::Or(l, r) => l.evaluate(env) || r.evaluate(env),
+            Expr::Compare { left, op, right } => {
+                let (left, right) = (left.resolve(env), right.resolve(env));
+                match op {
+                    Op::In => right.contains(left),
+                    Op::NotIn => !right.contains(left),
+                    op => {
+                        let ordering = compare(left, right);
+                        match op {
+                            Op::Eq => ordering == Ordering::Equal,
+                            Op::Ne => ordering != Ordering::Equal,
+                            Op::Lt => ordering == Ordering::Less,
+                            Op::Le => ordering != Ordering::Greater,
+                            Op::Gt => ordering == Ordering::Greater,
+                            Op::Ge => ordering != Ordering::Less,
+                            Op::In | Op::NotIn => unreachable!(),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Value {
+    fn resolve<'a>(&'a self, env: &'a HashMap<String, String>) -> &'a str {
+        match self {
+            Value::Variable(name) => env.get(name).map(|v| v.as_str()).unwrap_or(""),
+            Value::Literal(value) => value,
+        }
+    }
+}
+
+// Values that both look like versions are compared as versions, so that 3.10
+// comes after 3.8, and anything else is just compared as a string.
+fn compare(left: &str, right: &str) -> Ordering {
+    match (version_key(left), version_key(right)) {
+        (Some(l), Some(r)) => l.cmp(&r),
+        _ => left.cmp(right),
+    }
+}
+
+fn version_key(value: &str) -> Option<Vec<u64>> {
+    let mut parts = value
+        .split('.')
+        .map(|p| p.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    // Trailing zeros don't change a version, 3.8 and 3.8.0 are the same thing.
+    while parts.len() > 1 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+
+    Some(parts)
+}
+
+fn tokenize(marker: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = marker.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    _ => Token::RParen,
+                });
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(v) => value.push(v),
+                        None => {
+                            return Err(MarkerError::UnterminatedString {
+                                marker: marker.to_string(),
+                            })
+                        }
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            '=' | '!' | '<' | '>' => {
+                let mut op = String::new();
+                while let Some(&o) = chars.peek() {
+                    if !matches!(o, '=' | '!' | '<' | '>') {
+                        break;
+                    }
+                    op.push(o);
+                    chars.next();
+                }
+                tokens.push(Token::Op(match op.as_str() {
+                    "==" => Op::Eq,
+                    "!=" => Op::Ne,
+                    "<" => Op::Lt,
+                    "<=" => Op::Le,
+                    ">" => Op::Gt,
+                    ">=" => Op::Ge,
+                    _ => return Err(unexpected(marker, &op)),
+                }));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&i) = chars.peek() {
+                    if !(i.is_ascii_alphanumeric() || i == '_') {
+                        break;
+                    }
+                    ident.push(i);
+                    chars.next();
+                }
+                tokens.push(match ident.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "in" => Token::Op(Op::In),
+                    _ => Token::Ident(ident),
+                });
+            }
+            c => return Err(unexpected(marker, &c.to_string())),
+        }
+    }
+
+    // "not in" is the only operator that's two words, so we join them up here.
+    // Since not isn't otherwise meaningful, it's left as an identifier if it's
+    // used anywhere else, which then fails to parse.
+    let mut joined = Vec::with_capacity(tokens.len());
+    for token in tokens.into_iter() {
+        match (joined.last(), &token) {
+            (Some(Token::Ident(not)), Token::Op(Op::In)) if not == "not" => {
+                joined.pop();
+                joined.push(Token::Op(Op::NotIn));
+            }
+            _ => joined.push(token),
+        }
+    }
+
+    Ok(joined)
+}
+
+struct Parser<'m> {
+    marker: &'m str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Result<Token> {
+        let token =
+            self.tokens
+                .get(self.pos)
+                .cloned()
+                .ok_or_else(|| MarkerError::UnexpectedEnd {
+                    marker: self.marker.to_string(),
+                })?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.atom()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.atom()?));
+        }
+        Ok(expr)
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let expr = self.or()?;
+            return match self.next()? {
+                Token::RParen => Ok(expr),
+                token => Err(self.unexpected(&token)),
+            };
+        }
+
+        let left = self.value()?;
+        let op = match self.next()? {
+            Token::Op(op) => op,
+            token => return Err(self.unexpected(&token)),
+        };
+        let right = self.value()?;
+
+        Ok(Expr::Compare { left, op, right })
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.next()? {
+            Token::Ident(name) => Ok(Value::Variable(name)),
+            Token::Str(value) => Ok(Value::Literal(value)),
+            token => Err(self.unexpected(&token)),
+        }
+    }
+
+    fn unexpected(&self, token: &Token) -> MarkerError {
+        let token = match token {
+            Token::Ident(name) => name.clone(),
+            Token::Str(value) => format!("{:?}", value),
+            Token::Op(op) => op.to_string(),
+            Token::LParen => "(".to_string(),
+            Token::RParen => ")".to_string(),
+            Token::And => "and".to_string(),
+            Token::Or => "or".to_string(),
+        };
+        unexpected(self.marker, &token)
+    }
+}
+
+fn unexpected(marker: &str, token: &str) -> MarkerError {
+    MarkerError::UnexpectedToken {
+        marker: marker.to_string(),
+        token: token.to_string(),
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::In => "in",
+            Op::NotIn => "not in",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for Marker {
+    type Err = MarkerError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            marker: value,
+            tokens: tokenize(value)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(parser.unexpected(token));
+        }
+
+        Ok(Marker {
+            source: value.to_string(),
+            expr,
+        })
+    }
+}
+
+impl TryFrom<String> for Marker {
+    type Error = MarkerError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn evaluate(marker: &str, env: &HashMap<String, String>) -> bool {
+        marker.parse::<Marker>().unwrap().evaluate(env)
+    }
+
+    #[test]
+    fn parses_markers() {
+        for marker in [
+            r#"os == "linux""#,
+            r#"os == 'linux' and arch != "x86""#,
+            r#"(os == "linux" or os == "macos") and python_version >= "3.8""#,
+            r#"os not in "windows cygwin""#,
+        ] {
+            let parsed: Marker = marker.parse().unwrap();
+            assert_eq!(parsed.to_string(), marker);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_markers() {
+        assert!(matches!(
+            r#"os == "linux"#.parse::<Marker>(),
+            Err(MarkerError::UnterminatedString { .. })
+        ));
+        assert!(matches!(
+            r#"os =="#.parse::<Marker>(),
+            Err(MarkerError::UnexpectedEnd { .. })
+        ));
+        assert!(matches!(
+            r#"os === "linux""#.parse::<Marker>(),
+            Err(MarkerError::UnexpectedToken { .. })
+        ));
+        assert!(matches!(
+            r#"os == "linux" "extra""#.parse::<Marker>(),
+            Err(MarkerError::UnexpectedToken { .. })
+        ));
+    }
+
+    #[test]
+    fn evaluates_markers() {
+        let linux = env(&[("os", "linux"), ("python_version", "3.10")]);
+
+        assert!(evaluate(r#"os == "linux""#, &linux));
+        assert!(!evaluate(r#"os == "windows""#, &linux));
+        assert!(evaluate(r#"os == "windows" or os == "linux""#, &linux));
+        assert!(!evaluate(r#"os == "linux" and os != "linux""#, &linux));
+        assert!(evaluate(r#"os in "linux macos""#, &linux));
+        assert!(evaluate(r#"os not in "windows""#, &linux));
+
+        // Versions are compared as versions, not as strings.
+        assert!(evaluate(r#"python_version >= "3.8""#, &linux));
+        assert!(evaluate(r#"python_version == "3.10.0""#, &linux));
+
+        // Anything the environment doesn't know about is empty.
+        assert!(!evaluate(r#"arch == "x86_64""#, &linux));
+        assert!(evaluate(r#"arch == """#, &linux));
+    }
+}
@@ -2,43 +2,129 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::default::Default;
+use std::io::{Read, Write};
 use std::mem::drop;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
 
-use log::trace;
-use semver::VersionReq;
-use serde::{Deserialize, Serialize};
-use vfs::VfsPath;
+use log::{trace, warn};
+use serde::{Deserialize, Serialize, Serializer};
+use vfs::{PhysicalFS, VfsPath};
 
 use crate::errors::DBError;
-use crate::pkgdb::transactions::{Transaction, TransactionManager};
-use crate::types::{PackageName, PackageSpecifier};
+use crate::pkgdb::transactions::{Journal, StagedPackage, Transaction, TransactionManager};
+use crate::types::{
+    Digest, HistoryEntry, PackageName, PackageSpecifier, Resolution, ResolutionDiff,
+    VersionConstraint,
+};
 
 mod transactions;
 
 const LOGNAME: &str = "mqpkg::pkgdb";
 
 const PKGDB_DIR: &str = "pkgdb";
-const STATE_FILE: &str = "state.yml";
+const CACHE_DIR: &str = "cache";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const STAGING_DIR: &str = "staging";
+const STATE_FILE_STEM: &str = "state";
+const STATE_FILE_EXT: &str = "yml";
+const HISTORY_FILE_STEM: &str = "history";
+const HISTORY_FILE_EXT: &str = "jsonl";
+const JOURNAL_FILE_STEM: &str = "journal";
+const JOURNAL_FILE_EXT: &str = "json";
+
+// A file that's been changed since we installed it is never thrown away, instead
+// it's moved aside, to its own path with this added.
+const BACKUP_SUFFIX: &str = ".mqpkg-save";
+
+// Once our history has this many entries, it's rotated out to a single previous
+// file, replacing whatever was rotated out before it.
+const MAX_HISTORY_ENTRIES: usize = 1000;
+
+const STATE_VERSION: u32 = 1;
 
 type Result<T, E = DBError> = core::result::Result<T, E>;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub(crate) struct PackageRequest {
     pub(crate) name: PackageName,
-    pub(crate) version: VersionReq,
+    pub(crate) version: VersionConstraint,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) repository: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) metadata: BTreeMap<String, String>,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(default)]
-struct State {
-    requested: HashMap<PackageName, PackageRequest>,
+pub(crate) struct State {
+    pub(crate) version: u32,
+    // Saved sorted by name, so that the state file doesn't get reshuffled every
+    // time it's saved, since people commit it and review the diffs.
+    #[serde(serialize_with = "serialize_sorted")]
+    pub(crate) requested: HashMap<PackageName, PackageRequest>,
+    pub(crate) resolved: Resolution,
+    // Every file that each package has installed, so that they can be removed
+    // again, or checked for changes, without needing the package itself.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) installed: BTreeMap<PackageName, Vec<InstalledFile>>,
+    // The directories that each package's files were installed into, which are
+    // removed along with them, once nothing else is in them.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) directories: BTreeMap<PackageName, Vec<String>>,
+    // Local package archives that have been added, which every later operation
+    // needs to be able to find again, since nothing else provides them.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) archives: BTreeMap<PackageName, LocalArchive>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct InstalledFile {
+    // Relative to the target directory, always using / as the separator.
+    pub(crate) path: String,
+    pub(crate) digest: Digest,
+    pub(crate) mode: u32,
+}
+
+// Everything that a package has installed into the target.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Installed {
+    pub(crate) files: Vec<InstalledFile>,
+    // Relative to the target directory, just like files.
+    pub(crate) directories: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct LocalArchive {
+    pub(crate) path: String,
+    pub(crate) digest: Digest,
+}
+
+impl Default for State {
+    // State files from before we started versioning them are all version 1, so
+    // a missing version should be treated as the current version.
+    fn default() -> State {
+        State {
+            version: STATE_VERSION,
+            requested: HashMap::new(),
+            resolved: Resolution::new(),
+            installed: BTreeMap::new(),
+            directories: BTreeMap::new(),
+            archives: BTreeMap::new(),
+        }
+    }
 }
 
 impl State {
-    fn load(fs: &VfsPath) -> Result<State> {
-        let filename = state_path(fs)?;
+    fn load(fs: &VfsPath, profile: Option<&str>) -> Result<State> {
+        State::load_from(&state_path(fs, profile)?)
+    }
+
+    fn load_from(filename: &VfsPath) -> Result<State> {
         trace!(
             target: LOGNAME,
             "loading state from {:?}",
@@ -57,10 +143,12 @@ impl State {
         Ok(state)
     }
 
-    fn save(&self, fs: &VfsPath) -> Result<()> {
+    fn save(&self, fs: &VfsPath, profile: Option<&str>) -> Result<()> {
         ensure_dir(&pkgdb_path(fs)?)?;
+        self.save_to(&state_path(fs, profile)?)
+    }
 
-        let filename = state_path(fs)?;
+    fn save_to(&self, filename: &VfsPath) -> Result<()> {
         trace!(target: LOGNAME, "saving state to {:?}", filename.as_str());
         let file = filename.create_file()?;
         serde_yaml::to_writer(file, self).map_err(|source| DBError::InvalidState { source })?;
@@ -71,7 +159,21 @@ impl State {
 pub(crate) struct Database {
     id: String,
     fs: VfsPath,
+    // Where fs is on disk, if we know, for anything that vfs can't tell us.
+    root: Option<PathBuf>,
+    profile: Option<String>,
+    layers: Vec<VfsPath>,
+    // Whether a transaction is currently alive, which the transaction itself
+    // clears when it's dropped, even if it never got to commit.
+    active: Rc<Cell<bool>>,
     state: Option<State>,
+    // The state as it was when we loaded it, so we can tell if it's changed.
+    loaded: Option<State>,
+    // What the current transaction is doing, for our history.
+    operation: Option<HistoryEntry>,
+    // Packages that the current transaction has staged, which are only moved into
+    // the target once it commits.
+    staged: Vec<StagedPackage>,
 }
 
 impl Database {
@@ -79,28 +181,118 @@ impl Database {
         Ok(Database {
             id,
             fs,
+            root: None,
+            profile: None,
+            layers: Vec::new(),
+            active: Rc::new(Cell::new(false)),
             state: None,
+            loaded: None,
+            operation: None,
+            staged: Vec::new(),
         })
     }
 
+    pub(crate) fn with_root(&mut self, root: Option<PathBuf>) {
+        self.root = root;
+    }
+
+    // Selects a profile, which gets its own state file, so that several
+    // environments can share a single pkgdb without touching each other's state.
+    // They do all share the same target though, so they share one lock too.
+    pub(crate) fn with_profile(&mut self, profile: &str) -> Result<()> {
+        if !valid_name(profile) {
+            return Err(DBError::InvalidProfile {
+                profile: profile.to_string(),
+            });
+        }
+
+        self.profile = Some(profile.to_string());
+        Ok(())
+    }
+
+    // Layers are state files underneath our own, which are merged in order, with
+    // later layers (and finally our own state) overriding earlier ones. They're
+    // only ever read, so every change we make only goes to our own state.
+    pub(crate) fn with_layers(&mut self, layers: &[String]) -> Result<()> {
+        for layer in layers.iter() {
+            let path = self.fs.join(layer)?;
+            if !path.is_file()? {
+                return Err(DBError::MissingLayer {
+                    layer: layer.to_string(),
+                });
+            }
+            self.layers.push(path);
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn transaction(&self) -> Result<TransactionManager> {
         Ok(TransactionManager::new(&self.id)?)
     }
 
+    // Transactions don't nest, an operation that's already within a transaction
+    // has to do its work within that transaction, rather than beginning its own.
+    // Otherwise the inner transaction would wait forever on the lock that the
+    // outer one holds, or commit the outer one's state out from under it.
     pub(crate) fn begin<'r>(&mut self, txnm: &'r TransactionManager) -> Result<Transaction<'r>> {
-        let txn = txnm.begin()?;
+        if self.in_transaction() {
+            return Err(DBError::NestedTransaction);
+        }
+
+        let txn = txnm.begin(self.active.clone())?;
         trace!(target: LOGNAME, "begin transaction");
+
+        // If a previous transaction failed part way through, it never got to
+        // commit, so we have to make sure none of its state leaks into this one.
+        self.state = None;
+        self.loaded = None;
+        self.operation = None;
+        self.staged = Vec::new();
+
+        // If a previous process died part way through committing, then whatever it
+        // left behind has to be dealt with before we can trust anything on disk.
+        self.recover()?;
+
         Ok(txn)
     }
 
     pub(crate) fn commit(&mut self, txn: Transaction) -> Result<()> {
         trace!(target: LOGNAME, "commit transaction");
-        let fs = self.fs.clone();
 
         // Save all our various pieces of data that we've built up in our
-        // transaction.
-        self.state()?.save(&fs)?;
+        // transaction, unless nothing has actually changed, in which case there's
+        // no reason to rewrite anything. Everything goes through our journal, so
+        // that none of it can end up only half done.
+        match self.changed() {
+            true => {
+                let state = self.state()?.clone();
+                let before = self.loaded.clone().unwrap_or_default();
+                let mut operation = self.operation.take();
+                if let Some(entry) = operation.as_mut() {
+                    entry.requested = requests(&state);
+                    entry.resolved = state.resolved.clone();
+                    entry.changes = ResolutionDiff::new(&before.resolved, &state.resolved);
+                }
+                let journal = Journal {
+                    state,
+                    operation,
+                    staged: std::mem::take(&mut self.staged),
+                };
+
+                let filename = journal_path(&self.fs, self.profile.as_deref(), false)?;
+                let partial = journal_path(&self.fs, self.profile.as_deref(), true)?;
+                ensure_dir(&pkgdb_path(&self.fs)?)?;
+                let dir = self.root.as_ref().map(|root| root.join(PKGDB_DIR));
+                journal.save(&filename, &partial, dir.as_deref())?;
+                self.apply(&journal)?;
+            }
+            false => trace!(target: LOGNAME, "no changes to save"),
+        }
         self.state = None;
+        self.loaded = None;
+        self.operation = None;
+        self.staged = Vec::new();
 
         // Drop our transaction, which unlocks everything, and ensures that
         // our transaction is open to everyone to use again. We could just
@@ -112,6 +304,20 @@ impl Database {
         Ok(())
     }
 
+    // Describes what the current transaction is doing, which is added to our
+    // history when it's committed, as long as it actually changed anything.
+    pub(crate) fn describe(&mut self, operation: &str, packages: Vec<PackageName>, at: SystemTime) {
+        self.operation = Some(HistoryEntry {
+            id: 0,
+            timestamp: at,
+            operation: operation.to_string(),
+            packages,
+            requested: Vec::new(),
+            resolved: Resolution::new(),
+            changes: ResolutionDiff::default(),
+        });
+    }
+
     pub(crate) fn add(&mut self, package: &PackageSpecifier) -> Result<()> {
         let state = self.state()?;
         trace!(
@@ -120,41 +326,818 @@ impl Database {
             package.name,
             package.version
         );
+
+        // Requesting a package again replaces the request, but any metadata that
+        // was attached to it is kept, unless it's being given a new value.
+        let mut metadata = state
+            .requested
+            .get(&package.name)
+            .map(|req| req.metadata.clone())
+            .unwrap_or_default();
+        metadata.extend(package.metadata.clone());
+
         state.requested.insert(
             package.name.clone(),
             PackageRequest {
                 name: package.name.clone(),
                 version: package.version.clone(),
+                repository: package.repository.clone(),
+                metadata,
             },
         );
         Ok(())
     }
 
-    pub(crate) fn requested(&mut self) -> Result<&HashMap<PackageName, PackageRequest>> {
-        Ok(&self.state()?.requested)
+    // Records a local package archive as where package comes from, replacing any
+    // archive that it came from before.
+    pub(crate) fn add_archive(
+        &mut self,
+        package: &PackageName,
+        archive: LocalArchive,
+    ) -> Result<()> {
+        let state = self.state()?;
+        trace!(
+            target: LOGNAME,
+            "adding {} from archive {}",
+            package,
+            archive.path
+        );
+
+        state.archives.insert(package.clone(), archive);
+        Ok(())
+    }
+
+    // Removes a request, returning it. Only our own requests can be removed, any
+    // that come from one of our layers are read only. Any archive that it came
+    // from is forgotten too, since nothing needs it anymore.
+    pub(crate) fn remove(&mut self, name: &PackageName) -> Result<PackageRequest> {
+        let state = self.state()?;
+        trace!(target: LOGNAME, "removing {} from requested packages", name);
+
+        state.archives.remove(name);
+        state
+            .requested
+            .remove(name)
+            .ok_or_else(|| DBError::NotRequested {
+                package: name.clone(),
+            })
+    }
+
+    // Resets our state back to empty, as if nothing had ever been installed,
+    // returning whatever had been requested. Every file that was installed is
+    // removed from the target too, but like any other change, this only takes
+    // effect once the transaction has been committed.
+    pub(crate) fn clear(&mut self) -> Result<Vec<PackageRequest>> {
+        let state = self.state()?;
+        trace!(target: LOGNAME, "clearing all requested packages");
+        let cleared = std::mem::take(state);
+
+        let mut directories = cleared.directories;
+        for (package, previous) in cleared.installed.into_iter() {
+            self.staged.push(StagedPackage {
+                previous_directories: directories.remove(&package).unwrap_or_default(),
+                package,
+                previous,
+                files: Vec::new(),
+                directories: Vec::new(),
+            });
+        }
+
+        Ok(cleared.requested.into_values().collect())
+    }
+
+    // Everything that's been requested, including by any of our layers.
+    pub(crate) fn requested(&mut self) -> Result<HashMap<PackageName, PackageRequest>> {
+        let mut requested = self.layered()?;
+        requested.extend(self.state()?.requested.clone());
+        Ok(requested)
+    }
+
+    pub(crate) fn changed(&self) -> bool {
+        self.state != self.loaded
+    }
+
+    // Replaces everything we've requested with packages, without touching anything
+    // that's been resolved or installed.
+    pub(crate) fn set_requested(&mut self, packages: &[PackageSpecifier]) -> Result<()> {
+        trace!(target: LOGNAME, "replacing all requested packages");
+        self.state()?.requested.clear();
+        for package in packages {
+            self.add(package)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_resolved(&mut self, resolved: Resolution) -> Result<()> {
+        self.state()?.resolved = resolved;
+        Ok(())
+    }
+
+    // The files that package has installed, if it's installed any.
+    pub(crate) fn installed(&mut self, package: &PackageName) -> Result<Installed> {
+        let state = self.state()?;
+        Ok(Installed {
+            files: state.installed.get(package).cloned().unwrap_or_default(),
+            directories: state.directories.get(package).cloned().unwrap_or_default(),
+        })
+    }
+
+    // Replaces whatever files we had recorded for package, which like any other
+    // change, is only saved once the transaction has been committed.
+    pub(crate) fn set_installed(
+        &mut self,
+        package: &PackageName,
+        installed: Installed,
+    ) -> Result<()> {
+        let state = self.state()?;
+        let Installed {
+            mut files,
+            mut directories,
+        } = installed;
+        trace!(
+            target: LOGNAME,
+            "recording {} installed files for {}",
+            files.len(),
+            package
+        );
+
+        files.sort_by(|l, r| l.path.cmp(&r.path));
+        match files.is_empty() {
+            true => state.installed.remove(package),
+            false => state.installed.insert(package.clone(), files),
+        };
+        directories.sort();
+        match directories.is_empty() {
+            true => state.directories.remove(package),
+            false => state.directories.insert(package.clone(), directories),
+        };
+        Ok(())
+    }
+
+    // A fresh, empty, directory for packages to be extracted into, so that nothing
+    // in the target is touched until every package has been. Anything left in it
+    // by a transaction that failed part way through is thrown away first.
+    pub(crate) fn staging(&self) -> Result<VfsPath> {
+        if !self.in_transaction() {
+            return Err(DBError::NoTransaction);
+        }
+
+        let dir = staging_path(&self.fs, self.profile.as_deref())?;
+        if dir.is_dir()? {
+            dir.remove_dir_all()?;
+        }
+        dir.create_dir_all()?;
+
+        Ok(dir)
+    }
+
+    // Swaps each package's files in our staging area into the target, removing any
+    // files that package used to install but no longer does, and records the files
+    // that it now installs. A package with no files is removed from the target
+    // entirely. Like any other change, nothing is actually moved until the
+    // transaction has been committed.
+    pub(crate) fn swap(&mut self, staged: Vec<(PackageName, Installed)>) -> Result<()> {
+        let mut owners = self.owned_elsewhere()?;
+
+        // Within our own profile, a path belongs to whichever package installs it
+        // once we're done, so anything that's being swapped out doesn't count.
+        let profile = self.profile.clone();
+        for (package, files) in self.state()?.installed.iter() {
+            if !staged.iter().any(|(p, _)| p == package) {
+                for file in files.iter() {
+                    owners.insert(file.path.clone(), (profile.clone(), package.clone()));
+                }
+            }
+        }
+
+        for (package, installed) in staged.iter() {
+            for file in installed.files.iter() {
+                match owners.get(&file.path) {
+                    Some((profile, owner)) if owner != package => {
+                        return Err(DBError::FileConflict {
+                            path: file.path.clone(),
+                            package: package.clone(),
+                            owner: owner.clone(),
+                            profile: profile.clone(),
+                        })
+                    }
+                    _ => {
+                        owners.insert(file.path.clone(), (profile.clone(), package.clone()));
+                    }
+                }
+            }
+        }
+
+        for (package, installed) in staged.into_iter() {
+            trace!(target: LOGNAME, "staging {} to be swapped into place", package);
+            let previous = self.installed(&package)?;
+            self.set_installed(&package, installed.clone())?;
+            self.staged.push(StagedPackage {
+                package,
+                previous: previous.files,
+                previous_directories: previous.directories,
+                files: installed.files,
+                directories: installed.directories,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Saves a copy of our state (not including any layers) as a named snapshot,
+    // which can be restored later, replacing any snapshot with the same name.
+    pub(crate) fn snapshot(&mut self, name: &str) -> Result<()> {
+        let dir = snapshot_path(&self.fs, name)?;
+        let profile = self.profile.clone();
+        let state = self.state()?.clone();
+
+        trace!(target: LOGNAME, "saving snapshot {}", name);
+        ensure_dir(&pkgdb_path(&self.fs)?)?;
+        ensure_dir(&pkgdb_path(&self.fs)?.join(SNAPSHOTS_DIR)?)?;
+        ensure_dir(&dir)?;
+        state.save_to(&state_file(&dir, profile.as_deref())?)
+    }
+
+    // Replaces what's requested with what a snapshot requested, which like any
+    // other change, only takes effect once the transaction has been committed.
+    // What's resolved and installed is left alone, since none of the snapshot's
+    // files are in the target, instead what it resolved is returned, so that it
+    // can be installed again.
+    pub(crate) fn restore(&mut self, name: &str) -> Result<Resolution> {
+        let filename = state_file(&snapshot_path(&self.fs, name)?, self.profile.as_deref())?;
+        if !filename.is_file()? {
+            return Err(DBError::UnknownSnapshot {
+                name: name.to_string(),
+            });
+        }
+
+        trace!(target: LOGNAME, "restoring snapshot {}", name);
+        let mut snapshot = State::load_from(&filename)?;
+        let state = self.state()?;
+        let resolved = std::mem::replace(&mut snapshot.resolved, state.resolved.clone());
+        snapshot.installed = std::mem::take(&mut state.installed);
+        snapshot.directories = std::mem::take(&mut state.directories);
+        *state = snapshot;
+
+        Ok(resolved)
+    }
+
+    pub(crate) fn remove_snapshot(&mut self, name: &str) -> Result<()> {
+        let dir = snapshot_path(&self.fs, name)?;
+        let filename = state_file(&dir, self.profile.as_deref())?;
+        if !filename.is_file()? {
+            return Err(DBError::UnknownSnapshot {
+                name: name.to_string(),
+            });
+        }
+
+        trace!(target: LOGNAME, "removing snapshot {}", name);
+        filename.remove_file()?;
+
+        // Other profiles can have a snapshot with the same name, so we only clean
+        // up the directory once it's no longer being used by any of them.
+        if dir.read_dir()?.next().is_none() {
+            dir.remove_dir()?;
+        }
+
+        Ok(())
+    }
+
+    // The names of every snapshot that we have for our profile, in order.
+    pub(crate) fn snapshots(&self) -> Result<Vec<String>> {
+        let dir = pkgdb_path(&self.fs)?.join(SNAPSHOTS_DIR)?;
+        if !dir.is_dir()? {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for snapshot in dir.read_dir()? {
+            if state_file(&snapshot, self.profile.as_deref())?.is_file()? {
+                names.push(snapshot.filename());
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+
+    // Every operation that has been committed for our profile, oldest first,
+    // going back as far as our last rotation allows.
+    pub(crate) fn history(&self) -> Result<Vec<HistoryEntry>> {
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        for rotated in [true, false] {
+            let filename = history_path(&self.fs, self.profile.as_deref(), rotated)?;
+            if !filename.is_file()? {
+                continue;
+            }
+
+            let mut contents = String::new();
+            filename.open_file()?.read_to_string(&mut contents)?;
+            for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                let mut entry: HistoryEntry = serde_json::from_str(line)
+                    .map_err(|source| DBError::InvalidHistory { source })?;
+                if entry.id == 0 {
+                    entry.id = entries.last().map(|e| e.id).unwrap_or_default() + 1;
+                }
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // Creates a new profile, with nothing requested or resolved. Selecting a
+    // profile that doesn't exist yet works too, this just makes it explicit.
+    pub(crate) fn create_profile(&self, profile: &str) -> Result<()> {
+        if !valid_name(profile) {
+            return Err(DBError::InvalidProfile {
+                profile: profile.to_string(),
+            });
+        }
+
+        // We take the lock, so that we can't race anything else that's creating
+        // (or using) the same profile.
+        let txnm = TransactionManager::new(&self.id)?;
+        let txn = txnm.begin(Rc::new(Cell::new(false)))?;
+
+        let filename = state_path(&self.fs, Some(profile))?;
+        if filename.is_file()? {
+            return Err(DBError::ProfileExists {
+                profile: profile.to_string(),
+            });
+        }
+
+        trace!(target: LOGNAME, "creating profile {}", profile);
+        ensure_dir(&pkgdb_path(&self.fs)?)?;
+        State::default().save_to(&filename)?;
+        drop(txn);
+
+        Ok(())
+    }
+
+    // The names of every profile that has any state, in order, which doesn't
+    // include the default profile.
+    pub(crate) fn profiles(&self) -> Result<Vec<String>> {
+        let dir = pkgdb_path(&self.fs)?;
+        if !dir.is_dir()? {
+            return Ok(Vec::new());
+        }
+
+        let prefix = format!("{}-", STATE_FILE_STEM);
+        let suffix = format!(".{}", STATE_FILE_EXT);
+        let mut names = Vec::new();
+        for path in dir.read_dir()? {
+            let filename = path.filename();
+            let profile = filename
+                .strip_prefix(&prefix)
+                .and_then(|f| f.strip_suffix(&suffix));
+            if let Some(profile) = profile {
+                if valid_name(profile) && path.is_file()? {
+                    names.push(profile.to_string());
+                }
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+
+    // Unlike the other methods, this doesn't require a transaction, and instead
+    // will read the state off disk if we're not already in one. Since there's no
+    // lock held, the result is only a snapshot and shouldn't be used to make changes.
+    // A journal that's waiting to be rolled forward already has the state that
+    // we're about to have, so that's what gets read instead.
+    pub(crate) fn read(&self) -> Result<State> {
+        let mut state = match &self.state {
+            Some(state) if self.in_transaction() => state.clone(),
+            _ => match Journal::load(&journal_path(&self.fs, self.profile.as_deref(), false)?)? {
+                Some(journal) => journal.state,
+                None => State::load(&self.fs, self.profile.as_deref())?,
+            },
+        };
+
+        let mut requested = self.layered()?;
+        requested.extend(state.requested);
+        state.requested = requested;
+
+        Ok(state)
+    }
+
+    // Throws away a transaction that was interrupted while it was committing,
+    // instead of finishing it, for when finishing it keeps failing, returning
+    // whether there was one. Our state stays as it was before that transaction,
+    // but anything it had already moved into the target is left where it is.
+    pub(crate) fn discard(&self) -> Result<bool> {
+        let txnm = TransactionManager::new(&self.id)?;
+        let txn = txnm.begin(Rc::new(Cell::new(false)))?;
+
+        let filename = journal_path(&self.fs, self.profile.as_deref(), false)?;
+        let pending = filename.is_file()?;
+        if pending {
+            warn!(target: LOGNAME, "discarding an interrupted transaction");
+            Journal::remove(&filename)?;
+        }
+        let staging = staging_path(&self.fs, self.profile.as_deref())?;
+        if staging.is_dir()? {
+            staging.remove_dir_all()?;
+        }
+        drop(txn);
+
+        Ok(pending)
+    }
+
+    // The cache is kept separate from our state, since it can always be thrown
+    // away, and so it can live somewhere else entirely, even another volume.
+    pub(crate) fn cache(&self, dir: Option<&str>) -> Result<VfsPath> {
+        match dir {
+            Some(dir) if Path::new(dir).is_absolute() => {
+                Ok(PhysicalFS::new(PathBuf::from(dir)).into())
+            }
+            Some(dir) => Ok(self.fs.join(dir)?),
+            None => Ok(pkgdb_path(&self.fs)?.join(CACHE_DIR)?),
+        }
+    }
+
+    // Where our cache is on disk, if it's on disk at all, which it always is when
+    // it's somewhere else entirely, and otherwise only when we know our own root.
+    pub(crate) fn cache_dir(&self, dir: Option<&str>) -> Option<PathBuf> {
+        match dir {
+            Some(dir) if Path::new(dir).is_absolute() => Some(PathBuf::from(dir)),
+            Some(dir) => Some(self.root.as_ref()?.join(dir)),
+            None => Some(self.root.as_ref()?.join(PKGDB_DIR).join(CACHE_DIR)),
+        }
+    }
+
+    pub(crate) fn location(&self) -> Result<String> {
+        Ok(pkgdb_path(&self.fs)?.as_str().to_string())
     }
 }
 
 impl Database {
-    fn in_transaction(&self) -> Result<bool> {
-        Ok(self.transaction()?.is_active()?)
+    fn in_transaction(&self) -> bool {
+        self.active.get()
+    }
+
+    // A journal left behind means that a previous process died while it was
+    // committing, after everything it needed had been written out, so we roll it
+    // forward. Anything staged without a journal never made it to committing, and
+    // never touched the target, so that's simply thrown away.
+    fn recover(&mut self) -> Result<()> {
+        let filename = journal_path(&self.fs, self.profile.as_deref(), false)?;
+        match Journal::load(&filename)? {
+            Some(journal) => {
+                warn!(target: LOGNAME, "recovering an interrupted transaction");
+                self.apply(&journal)?;
+            }
+            None => {
+                let staging = staging_path(&self.fs, self.profile.as_deref())?;
+                if staging.is_dir()? {
+                    trace!(target: LOGNAME, "discarding an interrupted transaction");
+                    staging.remove_dir_all()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Makes everything in journal happen, which is safe to do again if we die
+    // part way through, since every step either finishes or finds it already has.
+    fn apply(&mut self, journal: &Journal) -> Result<()> {
+        let profile = self.profile.clone();
+        let staging = staging_path(&self.fs, profile.as_deref())?;
+
+        // A file that one package no longer installs can be one that another now
+        // does, in which case it has to stay, whichever of them is moved first.
+        let owned: HashSet<&str> = journal
+            .state
+            .installed
+            .values()
+            .flatten()
+            .map(|f| f.path.as_str())
+            .collect();
+        for staged in journal.staged.iter() {
+            move_staged(&self.fs, self.root.as_deref(), &staging, staged, &owned)?;
+        }
+        journal.state.save(&self.fs, profile.as_deref())?;
+
+        Journal::remove(&journal_path(&self.fs, profile.as_deref(), false)?)?;
+        if staging.is_dir()? {
+            staging.remove_dir_all()?;
+        }
+
+        if let Some(entry) = &journal.operation {
+            self.record_history(entry);
+        }
+
+        Ok(())
+    }
+
+    // Our history is only a record, so failing to write it is never fatal, since
+    // by the time we do, our state has already been saved. That's also why it's
+    // recorded after our journal is gone, since it's better to miss an entry than
+    // to record the same one twice.
+    fn record_history(&self, entry: &HistoryEntry) {
+        let result = self.history().and_then(|history| {
+            let mut entry = entry.clone();
+            entry.id = history.last().map(|e| e.id).unwrap_or_default() + 1;
+            append_history(&self.fs, self.profile.as_deref(), &entry)
+        });
+
+        if let Err(err) = result {
+            warn!(target: LOGNAME, "could not record history: {}", err);
+        }
+    }
+
+    // Every profile installs into the same target, so any file that another
+    // profile has installed belongs to that profile, along with which package.
+    fn owned_elsewhere(&self) -> Result<HashMap<String, (Option<String>, PackageName)>> {
+        let mut profiles: Vec<Option<String>> = self.profiles()?.into_iter().map(Some).collect();
+        profiles.push(None);
+
+        let mut owners = HashMap::new();
+        for profile in profiles.into_iter().filter(|p| *p != self.profile) {
+            let state = State::load(&self.fs, profile.as_deref())?;
+            for (package, files) in state.installed.into_iter() {
+                for file in files.into_iter() {
+                    owners.insert(file.path, (profile.clone(), package.clone()));
+                }
+            }
+        }
+
+        Ok(owners)
+    }
+
+    fn layered(&self) -> Result<HashMap<PackageName, PackageRequest>> {
+        let mut requested = HashMap::new();
+        for layer in self.layers.iter() {
+            requested.extend(State::load_from(layer)?.requested);
+        }
+
+        Ok(requested)
     }
 
     fn state(&mut self) -> Result<&mut State> {
-        if self.in_transaction()? && self.state.is_none() {
-            self.state = Some(State::load(&self.fs)?);
+        if self.in_transaction() && self.state.is_none() {
+            let state = State::load(&self.fs, self.profile.as_deref())?;
+            self.loaded = Some(state.clone());
+            self.state = Some(state);
         }
 
         self.state.as_mut().ok_or(DBError::NoTransaction)
     }
 }
 
+fn serialize_sorted<S, K, V>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    K: Serialize + Ord,
+    V: Serialize,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+// Our own requests (not any from our layers), sorted by name, as they'd need to
+// be requested again to get back to state.
+fn requests(state: &State) -> Vec<PackageSpecifier> {
+    let mut requests: Vec<PackageSpecifier> = state
+        .requested
+        .values()
+        .map(|req| PackageSpecifier {
+            name: req.name.clone(),
+            version: req.version.clone(),
+            repository: req.repository.clone(),
+            metadata: req.metadata.clone(),
+        })
+        .collect();
+    requests.sort_by(|l, r| l.name.cmp(&r.name));
+
+    requests
+}
+
 fn pkgdb_path(fs: &VfsPath) -> Result<VfsPath> {
     Ok(fs.join(PKGDB_DIR)?)
 }
 
-fn state_path(fs: &VfsPath) -> Result<VfsPath> {
-    Ok(pkgdb_path(fs)?.join(STATE_FILE)?)
+fn state_path(fs: &VfsPath, profile: Option<&str>) -> Result<VfsPath> {
+    state_file(&pkgdb_path(fs)?, profile)
+}
+
+fn state_file(dir: &VfsPath, profile: Option<&str>) -> Result<VfsPath> {
+    let filename = match profile {
+        Some(profile) => format!("{}-{}.{}", STATE_FILE_STEM, profile, STATE_FILE_EXT),
+        None => format!("{}.{}", STATE_FILE_STEM, STATE_FILE_EXT),
+    };
+
+    Ok(dir.join(&filename)?)
+}
+
+fn history_path(fs: &VfsPath, profile: Option<&str>, rotated: bool) -> Result<VfsPath> {
+    let stem = match profile {
+        Some(profile) => format!("{}-{}", HISTORY_FILE_STEM, profile),
+        None => HISTORY_FILE_STEM.to_string(),
+    };
+    let filename = match rotated {
+        true => format!("{}.1.{}", stem, HISTORY_FILE_EXT),
+        false => format!("{}.{}", stem, HISTORY_FILE_EXT),
+    };
+
+    Ok(pkgdb_path(fs)?.join(&filename)?)
+}
+
+// History is append only, one JSON entry per line, until it fills up, at which
+// point it's rotated and a fresh file is started.
+fn append_history(fs: &VfsPath, profile: Option<&str>, entry: &HistoryEntry) -> Result<()> {
+    let filename = history_path(fs, profile, false)?;
+    if filename.is_file()? {
+        let mut contents = String::new();
+        filename.open_file()?.read_to_string(&mut contents)?;
+        if contents.lines().count() >= MAX_HISTORY_ENTRIES {
+            let rotated = history_path(fs, profile, true)?;
+            if rotated.is_file()? {
+                rotated.remove_file()?;
+            }
+            filename.move_file(&rotated)?;
+        }
+    }
+
+    let mut line =
+        serde_json::to_string(entry).map_err(|source| DBError::InvalidHistory { source })?;
+    line.push('\n');
+    let mut file = match filename.is_file()? {
+        true => filename.append_file()?,
+        false => filename.create_file()?,
+    };
+    file.write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+fn journal_path(fs: &VfsPath, profile: Option<&str>, partial: bool) -> Result<VfsPath> {
+    let stem = match profile {
+        Some(profile) => format!("{}-{}", JOURNAL_FILE_STEM, profile),
+        None => JOURNAL_FILE_STEM.to_string(),
+    };
+    let filename = match partial {
+        true => format!("{}.{}.partial", stem, JOURNAL_FILE_EXT),
+        false => format!("{}.{}", stem, JOURNAL_FILE_EXT),
+    };
+
+    Ok(pkgdb_path(fs)?.join(&filename)?)
+}
+
+// Moves a package's files out of staging and into the target, skipping any that
+// have already been moved, and removing the ones it no longer installs, unless
+// they're owned by something else now, along with any directories that it no
+// longer installs into, once they're empty. Anything that's been changed since
+// it was installed is backed up rather than removed or replaced. When we know
+// where the target is on disk, each file gets the mode it was recorded with too.
+fn move_staged(
+    fs: &VfsPath,
+    root: Option<&Path>,
+    staging: &VfsPath,
+    staged: &StagedPackage,
+    owned: &HashSet<&str>,
+) -> Result<()> {
+    trace!(target: LOGNAME, "swapping {} into place", staged.package);
+    for file in staged.previous.iter() {
+        let path = fs.join(&file.path)?;
+        if !owned.contains(file.path.as_str()) && path.is_file()? {
+            match modified(&path, Some(&file.digest))? {
+                true => back_up(fs, &file.path)?,
+                false => path.remove_file()?,
+            }
+        }
+    }
+
+    // Deepest first, so that a directory is already empty of any of ours by the
+    // time we get to the one that it's in.
+    let mut directories: Vec<&String> = staged
+        .previous_directories
+        .iter()
+        .filter(|d| !staged.directories.contains(d))
+        .collect();
+    directories.sort_by(|l, r| r.cmp(l));
+    for directory in directories {
+        let path = fs.join(directory)?;
+        if path.is_dir()? && path.read_dir()?.next().is_none() {
+            path.remove_dir()?;
+        }
+    }
+
+    for directory in staged.directories.iter() {
+        fs.join(directory)?.create_dir_all()?;
+    }
+
+    let source = staging.join(&staged.package.to_string())?;
+    for file in staged.files.iter() {
+        let from = source.join(&file.path)?;
+        if !from.is_file()? {
+            continue;
+        }
+
+        let dest = fs.join(&file.path)?;
+        if let Some((parent, _)) = file.path.rsplit_once('/') {
+            fs.join(parent)?.create_dir_all()?;
+        }
+        if dest.is_file()? {
+            let previous = staged.previous.iter().find(|f| f.path == file.path);
+            match modified(&dest, previous.map(|f| &f.digest))? {
+                true => back_up(fs, &file.path)?,
+                false => dest.remove_file()?,
+            }
+        }
+        from.move_file(&dest)?;
+    }
+
+    if let Some(root) = root {
+        for file in staged.files.iter() {
+            set_mode(&root.join(&file.path), file.mode)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the file at path isn't what we installed there, which it never is when
+// we didn't install anything there at all.
+fn modified(path: &VfsPath, digest: Option<&Digest>) -> Result<bool> {
+    let digest = match digest {
+        Some(digest) => digest,
+        None => return Ok(true),
+    };
+
+    let io_error = |source| DBError::FileUnavailable {
+        path: path.as_str().to_string(),
+        source,
+    };
+    let mut writer = digest.algorithm.writer(std::io::sink());
+    writer.hash_existing(path.open_file()?).map_err(io_error)?;
+
+    Ok(&writer.finish().map_err(io_error)? != digest)
+}
+
+fn back_up(fs: &VfsPath, path: &str) -> Result<()> {
+    let backup = fs.join(&format!("{}{}", path, BACKUP_SUFFIX))?;
+    warn!(
+        target: LOGNAME,
+        "{} has been changed, saving it as {}", path, backup.as_str()
+    );
+    if backup.is_file()? {
+        backup.remove_file()?;
+    }
+    fs.join(path)?.move_file(&backup)?;
+
+    Ok(())
+}
+
+// Archives aren't trusted, so only the permission bits are ever applied, never
+// setuid, setgid, or sticky.
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777)).map_err(
+        |source| DBError::PermissionsUnavailable {
+            path: path.display().to_string(),
+            source,
+        },
+    )
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+// Each profile stages separately, so that whatever one of them left behind is
+// only ever recovered, or thrown away, along with its own state.
+fn staging_path(fs: &VfsPath, profile: Option<&str>) -> Result<VfsPath> {
+    let dir = pkgdb_path(fs)?.join(STAGING_DIR)?;
+    match profile {
+        Some(profile) => Ok(dir.join(&format!("profile-{}", profile))?),
+        None => Ok(dir.join("default")?),
+    }
+}
+
+fn snapshot_path(fs: &VfsPath, name: &str) -> Result<VfsPath> {
+    if !valid_name(name) {
+        return Err(DBError::InvalidSnapshot {
+            name: name.to_string(),
+        });
+    }
+
+    Ok(pkgdb_path(fs)?.join(SNAPSHOTS_DIR)?.join(name)?)
+}
+
+// Profile and snapshot names end up in paths, so they're kept to a safe set of
+// characters.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
 }
 
 fn ensure_dir(path: &VfsPath) -> Result<()> {
@@ -165,6 +1148,8 @@ fn ensure_dir(path: &VfsPath) -> Result<()> {
     Ok(())
 }
 
+// Runs body within a transaction, committing it if body succeeds. Using this
+// while already within a transaction fails with DBError::NestedTransaction.
 macro_rules! transaction {
     ($db:expr, $body:block) => {{
         let __txnm = $db.transaction()?;
@@ -182,3 +1167,175 @@ macro_rules! transaction {
 }
 
 pub(crate) use transaction;
+
+#[cfg(test)]
+mod tests {
+    use vfs::MemoryFS;
+
+    use super::*;
+
+    // Our lock is named after the database id, and is shared by the whole
+    // process, so every test gets its own.
+    fn database(id: &str) -> Database {
+        Database::new(VfsPath::new(MemoryFS::new()), format!("test-{}", id)).unwrap()
+    }
+
+    fn nested(db: &mut Database, package: &PackageSpecifier) -> Result<()> {
+        transaction!(db, {
+            db.add(package)?;
+            transaction!(db, { db.add(package)? });
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn nested_transaction() {
+        let mut db = database("nested");
+        let package: PackageSpecifier = "foo".parse().unwrap();
+
+        assert!(matches!(
+            nested(&mut db, &package),
+            Err(DBError::NestedTransaction)
+        ));
+
+        // The outer transaction never committed, so nothing it did is kept, and
+        // it's no longer active once it's gone.
+        assert!(!db.in_transaction());
+        assert!(db.read().unwrap().requested.is_empty());
+    }
+
+    #[test]
+    fn sequential_transactions() {
+        let mut db = database("sequential");
+        let foo: PackageSpecifier = "foo".parse().unwrap();
+        let bar: PackageSpecifier = "bar".parse().unwrap();
+
+        let mut add = |package: &PackageSpecifier| -> Result<()> {
+            transaction!(db, { db.add(package)? });
+            Ok(())
+        };
+        add(&foo).unwrap();
+        add(&bar).unwrap();
+
+        let requested = db.read().unwrap().requested;
+        assert!(requested.contains_key(&foo.name));
+        assert!(requested.contains_key(&bar.name));
+    }
+
+    // An empty file, which is what every test creates in the target.
+    fn file(path: &str) -> InstalledFile {
+        InstalledFile {
+            path: path.to_string(),
+            digest: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                .parse()
+                .unwrap(),
+            mode: 0o644,
+        }
+    }
+
+    fn install(db: &mut Database, package: &str, path: &str) -> Result<()> {
+        let package: PackageName = package.parse().unwrap();
+        let installed = Installed {
+            files: vec![file(path)],
+            directories: Vec::new(),
+        };
+        transaction!(db, { db.swap(vec![(package, installed)])? });
+
+        Ok(())
+    }
+
+    #[test]
+    fn profiles_share_files() {
+        let fs = VfsPath::new(MemoryFS::new());
+        let mut first = Database::new(fs.clone(), "test-profiles".to_string()).unwrap();
+        install(&mut first, "foo", "bin/foo").unwrap();
+
+        // Another profile installs into the same target, so it can't take over a
+        // file that the first one installed, whatever its package is called.
+        let mut second = Database::new(fs, "test-profiles".to_string()).unwrap();
+        second.with_profile("other").unwrap();
+        assert!(matches!(
+            install(&mut second, "bar", "bin/foo"),
+            Err(DBError::FileConflict { owner, profile: None, .. }) if owner.to_string() == "foo"
+        ));
+        install(&mut second, "bar", "bin/bar").unwrap();
+    }
+
+    #[test]
+    fn clear_removes_installed_files() -> Result<()> {
+        let fs = VfsPath::new(MemoryFS::new());
+        let mut db = Database::new(fs.clone(), "test-clear".to_string())?;
+        install(&mut db, "foo", "foo.txt")?;
+        fs.join("foo.txt")?.create_file()?;
+        transaction!(db, { db.snapshot("before")? });
+
+        // Restoring leaves what's installed alone, since nothing has been
+        // installed for the snapshot yet.
+        transaction!(db, { db.restore("before")? });
+        assert!(db.read()?.installed.contains_key(&"foo".parse().unwrap()));
+
+        transaction!(db, { db.clear()? });
+        assert!(db.read()?.installed.is_empty());
+        assert!(!fs.join("foo.txt")?.exists()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn packages_own_their_files() -> Result<()> {
+        let fs = VfsPath::new(MemoryFS::new());
+        let mut db = Database::new(fs.clone(), "test-owners".to_string())?;
+        install(&mut db, "foo", "foo.txt")?;
+        assert!(matches!(
+            install(&mut db, "bar", "foo.txt"),
+            Err(DBError::FileConflict { owner, profile: None, .. }) if owner.to_string() == "foo"
+        ));
+
+        // A file that's been changed since it was installed is saved, rather than
+        // being removed along with everything else.
+        fs.join("foo.txt")?.create_file()?.write_all(b"changed")?;
+        transaction!(db, { db.clear()? });
+        assert!(!fs.join("foo.txt")?.exists()?);
+        assert!(fs.join("foo.txt.mqpkg-save")?.exists()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pending_journal() -> Result<()> {
+        let fs = VfsPath::new(MemoryFS::new());
+        let db = Database::new(fs.clone(), "test-journal".to_string())?;
+
+        // As if we'd died while committing, after our journal was saved.
+        let foo: PackageName = "foo".parse().unwrap();
+        let mut state = State::default();
+        state.requested.insert(
+            foo.clone(),
+            PackageRequest {
+                name: foo.clone(),
+                version: VersionConstraint::any(),
+                repository: None,
+                metadata: BTreeMap::new(),
+            },
+        );
+        let journal = Journal {
+            state,
+            operation: None,
+            staged: Vec::new(),
+        };
+        ensure_dir(&pkgdb_path(&fs)?)?;
+        journal.save(
+            &journal_path(&fs, None, false)?,
+            &journal_path(&fs, None, true)?,
+            None,
+        )?;
+        assert!(db.read()?.requested.contains_key(&foo));
+
+        assert!(db.discard()?);
+        assert!(db.read()?.requested.is_empty());
+        assert!(!db.discard()?);
+
+        Ok(())
+    }
+}
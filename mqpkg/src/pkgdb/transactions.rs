@@ -2,9 +2,20 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
-use named_lock::{Error as NLError, NamedLock, NamedLockGuard};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+
+use log::trace;
+use named_lock::{NamedLock, NamedLockGuard};
+use serde::{Deserialize, Serialize};
+use vfs::VfsPath;
 
 use crate::errors::TransactionError;
+use crate::pkgdb::{InstalledFile, State, LOGNAME};
+use crate::types::{HistoryEntry, PackageName};
 
 type Result<T, E = TransactionError> = core::result::Result<T, E>;
 
@@ -20,24 +31,120 @@ impl TransactionManager {
         })
     }
 
-    pub(super) fn begin(&self) -> Result<Transaction> {
+    // Active is shared with the database, and stays set for exactly as long as
+    // the transaction is alive, however it ends up being dropped.
+    pub(super) fn begin(&self, active: Rc<Cell<bool>>) -> Result<Transaction> {
+        let guard = self.lock.lock()?;
+        active.set(true);
+
         Ok(Transaction {
-            _guard: self.lock.lock()?,
+            _guard: guard,
+            active,
         })
     }
-
-    pub(super) fn is_active(&self) -> Result<bool> {
-        match self.lock.try_lock() {
-            Ok(_) => Ok(false),
-            Err(e) => match e {
-                NLError::WouldBlock => Ok(true),
-                e => Err(TransactionError::LockError(e)),
-            },
-        }
-    }
 }
 
 #[derive(Debug)]
 pub struct Transaction<'r> {
     _guard: NamedLockGuard<'r>,
+    active: Rc<Cell<bool>>,
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        self.active.set(false);
+    }
+}
+
+// Everything that committing a transaction is going to change on disk, which is
+// written out before any of it is changed, so that if we die part way through
+// committing, the next transaction can finish the job, rather than leaving our
+// state and the target disagreeing with each other.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Journal {
+    pub(super) state: State,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) operation: Option<HistoryEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) staged: Vec<StagedPackage>,
+}
+
+// A package whose files are waiting in our staging area to be moved into the
+// target, along with the files (and directories) it installed before, so that
+// any it no longer installs can be removed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct StagedPackage {
+    pub(super) package: PackageName,
+    pub(super) previous: Vec<InstalledFile>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) previous_directories: Vec<String>,
+    pub(super) files: Vec<InstalledFile>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) directories: Vec<String>,
+}
+
+impl Journal {
+    pub(super) fn load(filename: &VfsPath) -> Result<Option<Journal>> {
+        if !filename.is_file()? {
+            return Ok(None);
+        }
+
+        trace!(target: LOGNAME, "loading journal from {:?}", filename.as_str());
+        let mut contents = String::new();
+        filename.open_file()?.read_to_string(&mut contents)?;
+        let journal = serde_json::from_str(&contents)
+            .map_err(|source| TransactionError::InvalidJournal { source })?;
+
+        Ok(Some(journal))
+    }
+
+    // The journal is written to partial first, then moved into place, so that a
+    // journal that exists is always a complete one. When we know where they are
+    // on disk, dir being the directory that they're both in, they're synced
+    // too, so that the journal survives a crash, not just our process dying.
+    pub(super) fn save(
+        &self,
+        filename: &VfsPath,
+        partial: &VfsPath,
+        dir: Option<&Path>,
+    ) -> Result<()> {
+        trace!(target: LOGNAME, "saving journal to {:?}", filename.as_str());
+        let file = partial.create_file()?;
+        serde_json::to_writer(file, self)
+            .map_err(|source| TransactionError::InvalidJournal { source })?;
+        if let Some(dir) = dir {
+            File::open(dir.join(partial.filename()))?.sync_all()?;
+        }
+
+        if filename.is_file()? {
+            filename.remove_file()?;
+        }
+        partial.move_file(filename)?;
+        if let Some(dir) = dir {
+            sync_dir(dir)?;
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn remove(filename: &VfsPath) -> Result<()> {
+        if filename.is_file()? {
+            filename.remove_file()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Syncing a directory is how a file being moved into it is made durable, which
+// only some platforms support, or need.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
 }
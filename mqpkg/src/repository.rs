@@ -2,122 +2,2517 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
-use std::collections::HashMap;
+use std::cell::{OnceCell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader, Read, Write};
+use std::ops::{Range, RangeInclusive};
+use std::path::PathBuf;
+use std::process::Command;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use ed25519_dalek::{PublicKey, Signature};
 use indexmap::IndexMap;
-use log::info;
-use reqwest::blocking::Client as HTTPClient;
-use semver::{Version, VersionReq};
-use serde::Deserialize;
+use log::{info, trace, warn};
+use reqwest::blocking::{Client as HTTPClient, Response};
+use reqwest::header::{RANGE, RETRY_AFTER};
+use reqwest::redirect::Policy;
+use reqwest::StatusCode;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::value::{RawValue, Value};
+use tempfile::TempDir;
 use url::Url;
+use vfs::{VfsPath, VfsResult};
 
-use crate::config;
-use crate::errors::RepositoryError;
+use crate::archive::Archive;
+use crate::config::{self, ConflictStrategy};
+use crate::errors::{DigestError, RepositoryError};
 use crate::resolver::{Candidate, StaticDependencies};
-use crate::types::{PackageName, Source};
+use crate::types::{
+    deserialize_dependencies, deserialize_marked_dependencies, CacheCleanup, Clock,
+    DependencyConstraint, Digest, PackageName, ReleaseIssue, RepositoryFetch, Source, SystemClock,
+    VersionConstraint, Yanked,
+};
 
 const LOGNAME: &str = "mqpkg::repository";
 
+const DEFAULT_GIT_INDEX_PATH: &str = "index.json";
+
+// Relative to the index itself, where a sharded index keeps each package.
+const SHARD_DIR: &str = "packages";
+
+const INDEX_CACHE_DIR: &str = "indices";
+
+// Relative to our cached indices, where the shards of sharded indices are cached,
+// by their digest.
+const SHARD_CACHE_DIR: &str = "shards";
+
+const ARTIFACT_CACHE_DIR: &str = "artifacts";
+
+const VERIFIED_CACHE_FILE: &str = "verified.json";
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+const SUPPORTED_SCHEMA_VERSIONS: RangeInclusive<u32> = 1..=1;
+
 type Result<T, E = RepositoryError> = core::result::Result<T, E>;
 
-#[derive(Deserialize, Debug)]
-struct MetaData {
-    #[serde(rename = "name")]
-    _name: String,
+// Rewrites the url of a request just before it's made, such as to send it through
+// a caching proxy. Everything else, like our cache and messages, still uses the
+// original url, so that it's always clear where something really came from.
+pub(crate) type UrlRewrite = dyn Fn(&Url) -> Url;
+
+// The versions of a package that each repository provides, by the name of the
+// repository, in the order that resolving prefers them.
+pub(crate) type Providers = Vec<(String, Vec<Version>)>;
+
+fn default_schema_version() -> u32 {
+    1
+}
+
+#[derive(Deserialize, Debug)]
+struct MetaData {
+    #[serde(rename = "name")]
+    _name: String,
+    // Indices from before we versioned the schema are all version 1.
+    #[serde(default = "default_schema_version")]
+    schema_version: u32,
+    #[serde(default)]
+    aliases: HashMap<PackageName, PackageName>,
+    #[serde(default)]
+    layout: IndexLayout,
+    // Anything we don't know about yet, which newer indices might have.
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+// A monolithic index has every package's releases in it, which is simplest for
+// small repositories, while a sharded index only lists each package, along with
+// the digest of a separate file that has its releases, so that we only ever
+// fetch the packages that we actually need.
+#[derive(Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum IndexLayout {
+    #[default]
+    Monolithic,
+    Sharded,
+}
+
+#[derive(Debug)]
+struct Release {
+    // A package can be depended on under several markers, in the order listed.
+    dependencies: HashMap<PackageName, Vec<DependencyConstraint>>,
+    recommends: HashMap<PackageName, VersionConstraint>,
+    urls: Vec<Url>,
+    digest: Digest,
+    // Purely descriptive, these never have any effect on resolving.
+    category: Option<String>,
+    tags: Vec<String>,
+    yanked: Option<Yanked>,
+}
+
+impl Release {
+    // Our dependencies, expanded for version, which is the version of this release,
+    // leaving out any whose marker doesn't match the environment we resolve for.
+    // When more than one constraint on a package matches, the first one listed
+    // is the one that applies.
+    fn dependencies(
+        &self,
+        package: &PackageName,
+        version: &Version,
+        env: &HashMap<String, String>,
+    ) -> Result<HashMap<PackageName, VersionConstraint>> {
+        self.dependencies
+            .iter()
+            .filter_map(|(name, constraints)| {
+                constraints
+                    .iter()
+                    .find(|constraint| constraint.applies(env))
+                    .map(|constraint| (name, constraint))
+            })
+            .map(|(name, constraint)| {
+                let constraint = constraint.expand(version).map_err(|source| {
+                    RepositoryError::InvalidDependency {
+                        package: package.clone(),
+                        version: version.clone(),
+                        source,
+                    }
+                })?;
+                Ok((name.clone(), constraint))
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct RawRelease {
+    #[serde(default, deserialize_with = "deserialize_marked_dependencies")]
+    dependencies: HashMap<PackageName, Vec<DependencyConstraint>>,
+    #[serde(default, deserialize_with = "deserialize_dependencies")]
+    recommends: HashMap<PackageName, VersionConstraint>,
+    urls: Vec<Url>,
+    #[serde(default)]
+    digests: HashMap<String, String>,
+    #[serde(default)]
+    digest: Option<String>,
+    #[serde(default)]
+    category: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    yanked: Option<RawYanked>,
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+// A release is yanked with either true, or the reason that it was yanked.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawYanked {
+    Flag(bool),
+    Reason(String),
+}
+
+impl TryFrom<RawRelease> for Release {
+    type Error = DigestError;
+
+    // A map of digests names its algorithms, so it takes precedence over a bare
+    // digest, which we can only infer the algorithm of.
+    fn try_from(raw: RawRelease) -> Result<Release, DigestError> {
+        if !raw.extra.is_empty() {
+            trace!(
+                target: LOGNAME,
+                "ignoring unknown release fields: {}",
+                field_names(&raw.extra)
+            );
+        }
+
+        let digest = match (Digest::from_map(&raw.digests)?, raw.digest) {
+            (Some(digest), _) => digest,
+            (None, Some(hex)) => hex.parse()?,
+            (None, None) => return Err(DigestError::NoDigest),
+        };
+
+        Ok(Release {
+            dependencies: raw.dependencies,
+            recommends: raw.recommends,
+            urls: raw.urls,
+            digest,
+            category: raw.category,
+            tags: raw.tags,
+            yanked: match raw.yanked {
+                None | Some(RawYanked::Flag(false)) => None,
+                Some(RawYanked::Flag(true)) => Some(Yanked { reason: None }),
+                Some(RawYanked::Reason(reason)) => Some(Yanked {
+                    reason: Some(reason),
+                }),
+            },
+        })
+    }
+}
+
+// What we actually parse the index into, with the raw JSON for each package's
+// releases, borrowed from the index, which then get parsed either up front, or
+// lazily.
+#[derive(Deserialize, Debug)]
+struct RawRepoData<'a> {
+    meta: MetaData,
+    #[serde(borrow, default)]
+    packages: HashMap<PackageName, &'a RawValue>,
+}
+
+// For huge indices, fully parsing every package up front takes far more memory
+// than the index itself does, so a repository can opt into keeping the index as
+// it is, with only where each package is in it, which only gets parsed the first
+// time something asks for it. A sharded index goes further, and only fetches a
+// package the first time.
+#[derive(Debug)]
+enum Releases {
+    Parsed(HashMap<Version, Release>),
+    Lazy {
+        index: Rc<[u8]>,
+        span: Range<usize>,
+        parsed: OnceCell<HashMap<Version, Release>>,
+    },
+    Sharded {
+        url: Url,
+        digest: Digest,
+        parsed: OnceCell<HashMap<Version, Release>>,
+    },
+}
+
+impl Releases {
+    fn get(
+        &self,
+        package: &PackageName,
+        builder: &RepositoryBuilder,
+    ) -> Result<&HashMap<Version, Release>> {
+        match self {
+            Releases::Parsed(releases) => Ok(releases),
+            Releases::Lazy {
+                index,
+                span,
+                parsed,
+            } => {
+                if let Some(releases) = parsed.get() {
+                    return Ok(releases);
+                }
+
+                let releases = parse_releases(package, &index[span.clone()])?;
+                Ok(parsed.get_or_init(|| releases))
+            }
+            Releases::Sharded {
+                url,
+                digest,
+                parsed,
+            } => {
+                if let Some(releases) = parsed.get() {
+                    return Ok(releases);
+                }
+
+                let releases = builder.fetch_shard(package, url, digest)?;
+                Ok(parsed.get_or_init(|| releases))
+            }
+        }
+    }
+
+    // The number of releases, without forcing a lazy package to be parsed.
+    fn parsed_len(&self) -> usize {
+        match self {
+            Releases::Parsed(releases) => releases.len(),
+            Releases::Lazy { parsed, .. } | Releases::Sharded { parsed, .. } => {
+                parsed.get().map(|r| r.len()).unwrap_or(0)
+            }
+        }
+    }
+
+    // Whether this is a shard that we haven't fetched yet.
+    fn is_unfetched(&self) -> bool {
+        matches!(self, Releases::Sharded { parsed, .. } if parsed.get().is_none())
+    }
+
+    // Makes sure that a shard can still be read once wherever it came from is gone,
+    // by caching it, or by parsing it now if it can't be cached.
+    fn preserve(&self, package: &PackageName, builder: &RepositoryBuilder) -> Result<()> {
+        let (url, digest, parsed) = match self {
+            Releases::Sharded {
+                url,
+                digest,
+                parsed,
+            } => (url, digest, parsed),
+            _ => return Ok(()),
+        };
+        if parsed.get().is_some() || builder.cached_shard(digest).is_some() {
+            return Ok(());
+        }
+
+        let shard = builder.download_shard(package, url, digest)?;
+        if write_cached_shard(&builder.cache, digest, &shard).is_err() {
+            let releases = parse_releases(package, &shard)?;
+            parsed.get_or_init(|| releases);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct RepoData {
+    meta: MetaData,
+    packages: HashMap<PackageName, Releases>,
+}
+
+// Everything needed to fetch our repositories, before any of them have been
+// loaded. The only way to get a Repository is to fetch or preload one from here,
+// so there's never a Repository that silently has no candidates.
+#[derive(Clone, Debug)]
+pub(crate) struct RepositoryBuilder {
+    client: HTTPClient,
+    max_index_size: u64,
+    retries: u32,
+    cache: VfsPath,
+    conflict_strategy: ConflictStrategy,
+    fetches: IndexMap<config::Repository, RepositoryFetch>,
+    warnings: RefCell<Vec<String>>,
+    clock: Rc<dyn Clock>,
+    rewrite: Option<Rc<UrlRewrite>>,
+    environment: HashMap<String, String>,
+    default_repository: Option<String>,
+    allowed_schemes: Rc<[String]>,
+    // Where our cache is on disk, if it's on disk at all.
+    cache_dir: Option<PathBuf>,
+}
+
+impl RepositoryBuilder {
+    pub(crate) fn new(config: &config::Config, cache: VfsPath) -> Result<RepositoryBuilder> {
+        let max_redirects = config.max_redirects();
+        let allowed_schemes: Rc<[String]> = config.allowed_schemes().into();
+        let allowed = allowed_schemes.to_vec();
+        let client = HTTPClient::builder()
+            .gzip(true)
+            .timeout(config.timeout())
+            .redirect(Policy::custom(move |attempt| {
+                if attempt.previous().len() > max_redirects {
+                    return attempt.error("too many redirects");
+                }
+
+                // Our allowed schemes apply wherever we end up, not just to the url
+                // that we started with.
+                let scheme = attempt.url().scheme().to_string();
+                if !allowed.contains(&scheme) {
+                    let url = attempt.url().to_string();
+                    return attempt.error(DisallowedRedirect { url, scheme });
+                }
+
+                // A repository redirecting is usually fine, but operators should be
+                // able to notice when it's happening unexpectedly.
+                if let Some(previous) = attempt.previous().last() {
+                    info!(
+                        target: LOGNAME,
+                        "{} redirected to {}",
+                        previous,
+                        attempt.url()
+                    );
+                }
+                attempt.follow()
+            }))
+            .build()?;
+
+        Ok(RepositoryBuilder {
+            client,
+            max_index_size: config.max_index_size(),
+            retries: config.retries(),
+            cache,
+            conflict_strategy: config.conflict_strategy(),
+            fetches: IndexMap::new(),
+            warnings: RefCell::new(Vec::new()),
+            clock: Rc::new(SystemClock),
+            rewrite: None,
+            environment: HashMap::new(),
+            default_repository: config.default_repository().map(String::from),
+            allowed_schemes,
+            cache_dir: None,
+        })
+    }
+
+    // Where our cache is on disk, which vfs can't tell us, but which we need to
+    // tell whether a cached artifact has been modified since we verified it.
+    pub(crate) fn with_cache_dir(&mut self, cache_dir: Option<PathBuf>) {
+        self.cache_dir = cache_dir;
+    }
+
+    pub(crate) fn with_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub(crate) fn with_rewrite(&mut self, rewrite: Option<Rc<UrlRewrite>>) {
+        self.rewrite = rewrite;
+    }
+
+    // The values that dependency markers are evaluated against.
+    pub(crate) fn with_environment(&mut self, environment: HashMap<String, String>) {
+        self.environment = environment;
+    }
+
+    // Fetches all of the given repositories, returning a Repository loaded with
+    // their data. What we recorded while fetching is kept here as well, so that it
+    // can still be inspected when fetching fails.
+    pub(crate) fn fetch(
+        &mut self,
+        repos: &[config::Repository],
+        callback: impl Fn(),
+    ) -> Result<Repository> {
+        info!(target: LOGNAME, "fetching package metadata");
+        let mut data = IndexMap::<config::Repository, RepoData>::new();
+        for repo in repos.iter() {
+            data.insert(repo.clone(), self.fetch_and_record(repo)?);
+            (callback)();
+        }
+        self.check_conflicts(data.iter())?;
+
+        Ok(self.loaded(data))
+    }
+
+    // Uses the given indices as our data, as if they'd just been fetched, so that
+    // the Repository can be used without ever touching the network.
+    pub(crate) fn preload(&self, indices: &[(&config::Repository, &[u8])]) -> Result<Repository> {
+        let mut data = IndexMap::<config::Repository, RepoData>::new();
+        for (repo, index) in indices.iter() {
+            let (repo_data, _) = self.load(repo, &repo.url, *index)?;
+            data.insert((*repo).clone(), repo_data);
+        }
+        self.check_conflicts(data.iter())?;
+
+        Ok(self.loaded(data))
+    }
+
+    // Checks whether each of the given repositories is reachable, without
+    // downloading or parsing their indices.
+    pub(crate) fn check(
+        &self,
+        repos: &[config::Repository],
+    ) -> Vec<(config::Repository, Result<()>)> {
+        repos
+            .iter()
+            .map(|repo| {
+                (
+                    repo.clone(),
+                    self.ping(repo).map_err(|e| redirects(repo, e)),
+                )
+            })
+            .collect()
+    }
+
+    // Removes anything from our cache that we'd never use again: the cached indices
+    // of repositories that aren't in repos anymore, shards that none of the rest
+    // list, downloads that were never finished, and verifications of artifacts
+    // that are gone. Only our own cache directories are ever looked at, so nothing
+    // else that might share the cache directory, like our state, can be removed.
+    pub(crate) fn prune_cache(&self, repos: &[config::Repository]) -> Result<CacheCleanup> {
+        let mut cleanup = CacheCleanup::default();
+
+        let mut keep = Vec::new();
+        for repo in repos.iter() {
+            keep.push(cached_index_path(&self.cache, repo)?.filename());
+            keep.push(cached_at_path(&self.cache, repo)?.filename());
+        }
+        let indices = self.cache.join(INDEX_CACHE_DIR)?;
+        if indices.is_dir()? {
+            for path in indices.read_dir()? {
+                if path.is_file()? && !keep.contains(&path.filename()) {
+                    prune(&path, &mut cleanup)?;
+                }
+            }
+        }
+
+        // Shards are cached by their digest, so whether one is still needed comes
+        // down to whether any index that we're keeping lists it. An index that we
+        // can't read could list anything, so then every shard is kept.
+        let mut shards = Some(HashSet::new());
+        for repo in repos.iter() {
+            if !cached_index_path(&self.cache, repo)?.is_file()? {
+                continue;
+            }
+            let digests = read_cached_index(&self.cache, repo)
+                .ok()
+                .and_then(|index| shard_digests(&index));
+            match (shards.as_mut(), digests) {
+                (Some(shards), Some(digests)) => {
+                    for digest in digests.iter() {
+                        shards.insert(cached_shard_path(&self.cache, digest)?.filename());
+                    }
+                }
+                _ => shards = None,
+            }
+        }
+        let dir = self.cache.join(INDEX_CACHE_DIR)?.join(SHARD_CACHE_DIR)?;
+        if let Some(shards) = shards {
+            if dir.is_dir()? {
+                for path in dir.read_dir()? {
+                    if path.is_file()? && !shards.contains(&path.filename()) {
+                        prune(&path, &mut cleanup)?;
+                    }
+                }
+            }
+        }
+
+        let artifacts = self.cache.join(ARTIFACT_CACHE_DIR)?;
+        if artifacts.is_dir()? {
+            for path in artifacts.read_dir()? {
+                if path.is_file()? && path.filename().ends_with(".part") {
+                    prune(&path, &mut cleanup)?;
+                }
+            }
+
+            let mut verified = read_verified(&self.cache);
+            let before = verified.len();
+            verified.retain(|name, _| {
+                artifacts
+                    .join(name)
+                    .and_then(|path| path.is_file())
+                    .unwrap_or(false)
+            });
+            if verified.len() != before {
+                write_verified(&self.cache, &verified)?;
+            }
+        }
+
+        Ok(cleanup)
+    }
+
+    // Everything we've warned about while fetching, or since.
+    pub(crate) fn warnings(&self) -> Vec<String> {
+        self.warnings.borrow().clone()
+    }
+
+    // The outcome of the last attempt to fetch each repository.
+    pub(crate) fn fetches(&self) -> &IndexMap<config::Repository, RepositoryFetch> {
+        &self.fetches
+    }
+
+    fn loaded(&self, data: IndexMap<config::Repository, RepoData>) -> Repository {
+        Repository {
+            builder: self.clone(),
+            data,
+            archives: IndexMap::new(),
+        }
+    }
+}
+
+// A Repository always has data for every repository it was fetched with, and
+// only ever re-fetches when explicitly asked to refresh.
+#[derive(Debug)]
+pub(crate) struct Repository {
+    builder: RepositoryBuilder,
+    data: IndexMap<config::Repository, RepoData>,
+    archives: IndexMap<PackageName, Archive>,
+}
+
+impl Repository {
+    // Re-fetches a single repository, leaving the rest of our data as is. The
+    // repository keeps its existing position, so candidate precedence is unchanged.
+    pub(crate) fn refresh(&mut self, repo: &config::Repository) -> Result<()> {
+        if !self.data.contains_key(repo) {
+            return Err(RepositoryError::UnknownRepository {
+                repository: repo.name.clone(),
+            });
+        }
+
+        info!(target: LOGNAME, "refreshing package metadata for {}", repo.name);
+        let data = self.builder.fetch_and_record(repo)?;
+        self.builder
+            .check_conflicts(
+                self.data
+                    .iter()
+                    .map(|(r, d)| if r == repo { (r, &data) } else { (r, d) }),
+            )?;
+        self.data.insert(repo.clone(), data);
+
+        Ok(())
+    }
+
+    // Everything we've warned about while fetching, or since.
+    pub(crate) fn warnings(&self) -> Vec<String> {
+        self.builder.warnings()
+    }
+
+    // The outcome of the last attempt to fetch each repository.
+    pub(crate) fn fetches(&self) -> &IndexMap<config::Repository, RepositoryFetch> {
+        self.builder.fetches()
+    }
+
+    // These only affect what we do from now on, they never re-fetch anything.
+    pub(crate) fn with_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.builder.with_clock(clock);
+    }
+
+    pub(crate) fn with_rewrite(&mut self, rewrite: Option<Rc<UrlRewrite>>) {
+        self.builder.with_rewrite(rewrite);
+    }
+
+    pub(crate) fn with_environment(&mut self, environment: HashMap<String, String>) {
+        self.builder.with_environment(environment);
+    }
+
+    // Our repositories, along with their preference, in the order that we prefer
+    // them when more than one provides the same version. That's the order they're
+    // defined in, except that the default repository always comes first, which
+    // only ever breaks ties, rather than changing where anything else comes from.
+    // Lower is preferred, and 0 is kept for the default repository.
+    fn prioritized(&self) -> Vec<(u64, &config::Repository, &RepoData)> {
+        let mut prioritized: Vec<(u64, &config::Repository, &RepoData)> = self
+            .data
+            .iter()
+            .enumerate()
+            .map(|(idx, (repo, data))| {
+                let preference = match self.builder.default_repository.as_deref() {
+                    Some(name) if name == repo.name => 0,
+                    _ => idx as u64 + 1,
+                };
+                (preference, repo, data)
+            })
+            .collect();
+        prioritized.sort_by_key(|(preference, _, _)| *preference);
+
+        prioritized
+    }
+
+    // The releases of package from one of our repositories, fetching its shard if
+    // it has one that we haven't fetched yet. Unfetched shards are skipped when
+    // checking for conflicts as our indices are loaded, so a shard is checked
+    // against every other repository as soon as it's been fetched instead.
+    fn releases<'a>(
+        &'a self,
+        repo: &config::Repository,
+        data: &'a RepoData,
+        package: &PackageName,
+    ) -> Result<Option<&'a HashMap<Version, Release>>> {
+        let unfetched = data.is_unfetched(package);
+        let releases = data.releases(repo, package, &self.builder)?;
+        if unfetched {
+            self.builder
+                .check_package_conflicts(data.target(package), self.data.iter())?;
+        }
+
+        Ok(releases)
+    }
+
+    // Returns every release of package along with the repository that provides
+    // it, sorted from highest to lowest version, and by preference for any version
+    // provided by more than one repository.
+    pub(crate) fn available(
+        &self,
+        package: &PackageName,
+    ) -> Result<Vec<(Version, &config::Repository)>> {
+        let mut available = Vec::<(Version, &config::Repository)>::new();
+        for (_, repo, data) in self.prioritized() {
+            if let Some(releases) = self.releases(repo, data, package)? {
+                available.extend(releases.keys().map(|v| (v.clone(), repo)));
+            }
+        }
+
+        // This is a stable sort, so our preference order is kept for equal versions.
+        available.sort_by(|l, r| l.0.cmp(&r.0).reverse());
+        Ok(available)
+    }
+
+    // The packages recommended by a specific version of package, taken from the
+    // repository that it was resolved from, which is the one whose dependencies it
+    // was resolved with. Packages that didn't come from a repository, like local
+    // archives, don't recommend anything.
+    pub(crate) fn recommends(
+        &self,
+        package: &PackageName,
+        version: &Version,
+        repository: Option<&str>,
+    ) -> Result<HashMap<PackageName, VersionConstraint>> {
+        let (repo, data) = match repository
+            .and_then(|name| self.data.iter().find(|(repo, _)| repo.name == name))
+        {
+            Some(found) => found,
+            None => return Ok(HashMap::new()),
+        };
+
+        Ok(self
+            .releases(repo, data, package)?
+            .and_then(|releases| releases.get(version))
+            .map(|release| release.recommends.clone())
+            .unwrap_or_default())
+    }
+
+    // Checks every release that we know about, reporting every problem that we
+    // find rather than stopping at the first. With network, each URL is also
+    // downloaded, to make sure that it's reachable and matches its digest.
+    pub(crate) fn verify(&self, network: bool) -> Vec<ReleaseIssue> {
+        let mut issues = Vec::new();
+        for (repo, data) in self.data.iter() {
+            let mut names: Vec<&PackageName> = data.packages.keys().collect();
+            names.sort();
+
+            for name in names {
+                let releases = match data.packages[name].get(name, &self.builder) {
+                    Ok(releases) => releases,
+                    Err(err) => {
+                        issues.push(ReleaseIssue {
+                            repository: repo.name.clone(),
+                            package: name.clone(),
+                            version: None,
+                            url: None,
+                            problem: err.to_string(),
+                        });
+                        continue;
+                    }
+                };
+
+                let mut versions: Vec<&Version> = releases.keys().collect();
+                versions.sort();
+                for version in versions {
+                    let release = &releases[version];
+                    let issue = |url: Option<&Url>, problem: String| ReleaseIssue {
+                        repository: repo.name.clone(),
+                        package: name.clone(),
+                        version: Some(version.clone()),
+                        url: url.map(|u| u.to_string()),
+                        problem,
+                    };
+
+                    if release.urls.is_empty() {
+                        issues.push(issue(None, "release has no urls".to_string()));
+                    }
+                    for url in release.urls.iter() {
+                        if let Err(problem) =
+                            self.builder.verify_url(repo, url, &release.digest, network)
+                        {
+                            issues.push(issue(Some(url), problem));
+                        }
+                    }
+                }
+            }
+        }
+
+        issues
+    }
+
+    // The digest of a single release, from the named repository.
+    pub(crate) fn digest(
+        &self,
+        package: &PackageName,
+        version: &Version,
+        repository: &str,
+    ) -> Result<Option<&Digest>> {
+        for (repo, data) in self.data.iter().filter(|(r, _)| r.name == repository) {
+            if let Some(release) = self
+                .releases(repo, data, package)?
+                .and_then(|r| r.get(version))
+            {
+                return Ok(Some(&release.digest));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Downloads the artifact for a release into our cache, verified against its
+    // digest, and returns where it was put. Artifacts are stored by their digest,
+    // so one that's already in our cache is never downloaded again. A download
+    // that gets interrupted leaves a partial file behind, which the next attempt
+    // resumes from, as long as the server supports range requests.
+    pub(crate) fn download(&self, package: &PackageName, version: &Version) -> Result<VfsPath> {
+        self.download_where(package, version, |_| true)
+    }
+
+    // Like download, but only from the named repository, such as the one that
+    // resolving picked the release from.
+    pub(crate) fn pinned_download(
+        &self,
+        package: &PackageName,
+        version: &Version,
+        repository: &str,
+    ) -> Result<VfsPath> {
+        self.download_where(package, version, |repo| repo.name == repository)
+    }
+
+    fn download_where(
+        &self,
+        package: &PackageName,
+        version: &Version,
+        filter: impl Fn(&config::Repository) -> bool,
+    ) -> Result<VfsPath> {
+        let mut release = None;
+        for (_, repo, data) in self.prioritized().into_iter().filter(|(_, r, _)| filter(r)) {
+            if let Some(found) = self
+                .releases(repo, data, package)?
+                .and_then(|r| r.get(version))
+            {
+                release = Some((repo, found));
+                break;
+            }
+        }
+        let (repo, release) = release.ok_or_else(|| RepositoryError::UnknownRelease {
+            package: package.clone(),
+            version: version.clone(),
+        })?;
+
+        let dir = self.builder.cache.join(ARTIFACT_CACHE_DIR)?;
+        let name = format!("{}-{}", release.digest.algorithm, release.digest.hex);
+        let dest = dir.join(&name)?;
+        if dest.is_file()? {
+            if self.builder.verify_artifact(&dest, &release.digest)? {
+                trace!(target: LOGNAME, "using cached {} {}", package, version);
+                return Ok(dest);
+            }
+
+            self.builder.warn(format!(
+                "cached {} {} does not match its digest, downloading it again",
+                package, version
+            ));
+            dest.remove_file()?;
+        }
+        dir.create_dir_all()?;
+        let part = dir.join(&format!("{}.part", name))?;
+
+        let mut attempts = Vec::new();
+        for url in release.urls.iter() {
+            match check_local(repo, url)
+                .and_then(|_| self.builder.download_from(url, &release.digest, &part))
+            {
+                Ok(()) => {
+                    info!(
+                        target: LOGNAME,
+                        "downloaded {} {} from {}", package, version, url
+                    );
+                    part.move_file(&dest)?;
+                    self.builder.record_verified(&dest)?;
+                    return Ok(dest);
+                }
+                Err(err) => {
+                    warn!(
+                        target: LOGNAME,
+                        "could not download {} {} from {}: {}", package, version, url, err
+                    );
+                    attempts.push(format!("{}: {}", url, err));
+                }
+            }
+        }
+
+        Err(RepositoryError::DownloadFailed {
+            package: package.clone(),
+            version: version.clone(),
+            attempts,
+        })
+    }
+
+    // Adds a local package archive, which then shadows whatever our repositories
+    // have for that package, since asking for a specific archive means that the
+    // archive is what should be installed.
+    pub(crate) fn add_archive(&mut self, archive: Archive) {
+        self.archives.insert(archive.name.clone(), archive);
+    }
+
+    // The local archive that package comes from, if it was added as one.
+    pub(crate) fn archive(&self, package: &PackageName) -> Option<&Archive> {
+        self.archives.get(package)
+    }
+
+    // Every package name that any of our repositories knows about, including
+    // aliases, without any duplicates.
+    pub(crate) fn package_names(&self) -> Vec<&PackageName> {
+        let mut names: Vec<&PackageName> = self
+            .data
+            .iter()
+            .flat_map(|(repo, data)| {
+                let aliases = data
+                    .meta
+                    .aliases
+                    .iter()
+                    .filter(|(_, target)| repo.exposes(target))
+                    .map(|(alias, _)| alias);
+                data.packages
+                    .keys()
+                    .chain(aliases)
+                    .filter(move |name| repo.exposes(name))
+            })
+            .chain(self.archives.keys())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    // Every package that more than one of our repositories provides, in name
+    // order, which means that one of them may be shadowing the others. Each one
+    // comes with the repository that resolving would pick it from, if any, which
+    // skips yanked releases, and pre-releases unless prereleases allows them for
+    // it. Aliases are only other names for these packages, so they're left out.
+    // This only looks at what we've already loaded, so it never touches the network,
+    // which means that packages in shards we haven't fetched yet are skipped.
+    pub(crate) fn shadowed(
+        &self,
+        prereleases: impl Fn(&PackageName) -> bool,
+    ) -> Result<Vec<(&PackageName, Option<String>, Providers)>> {
+        let mut names: Vec<&PackageName> = self
+            .data
+            .iter()
+            .flat_map(|(repo, data)| data.packages.keys().filter(move |n| repo.exposes(n)))
+            .filter(|name| self.data.values().all(|data| !data.is_unfetched(name)))
+            .collect();
+        names.sort();
+        names.dedup();
+
+        let mut shadowed = Vec::new();
+        for name in names.into_iter() {
+            let mut candidates = self.candidates(name)?;
+            candidates.sort_by(|l, r| l.cmp(r).reverse());
+
+            let mut providers: Providers = Vec::new();
+            for candidate in candidates.iter() {
+                let repository = match candidate.source().repository() {
+                    Some(repository) => repository,
+                    None => continue,
+                };
+                let version = Version::from(candidate);
+                match providers.iter_mut().find(|(r, _)| r == repository) {
+                    Some((_, versions)) => versions.push(version),
+                    None => providers.push((repository.to_string(), vec![version])),
+                }
+            }
+
+            if providers.len() > 1 {
+                let preferred = candidates
+                    .iter()
+                    .filter(|c| c.source().yanked().is_none())
+                    .filter(|c| Version::from(*c).pre.is_empty() || prereleases(name))
+                    .find_map(|c| c.source().repository().map(String::from));
+                shadowed.push((name, preferred, providers));
+            }
+        }
+
+        Ok(shadowed)
+    }
+
+    // Every package with at least one release that has tag (if given) and is in
+    // category (if given), from any of our repositories, in name order. Fetching
+    // every shard would defeat the point of sharding, so packages in a shard we
+    // haven't fetched yet only match when there's nothing to filter them by.
+    pub(crate) fn search(
+        &self,
+        tag: Option<&str>,
+        category: Option<&str>,
+    ) -> Result<Vec<&PackageName>> {
+        let matches = |release: &Release| {
+            tag.is_none_or(|t| release.tags.iter().any(|rt| rt == t))
+                && category.is_none_or(|c| release.category.as_deref() == Some(c))
+        };
+
+        let mut names = Vec::new();
+        for (repo, data) in self.data.iter() {
+            for (name, releases) in data.packages.iter() {
+                if !repo.exposes(name) {
+                    continue;
+                }
+
+                let found = match releases.is_unfetched() {
+                    true => tag.is_none() && category.is_none(),
+                    false => releases.get(name, &self.builder)?.values().any(matches),
+                };
+                if found {
+                    names.push(name);
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+
+        Ok(names)
+    }
+
+    // Every candidate for every package, produced lazily, so that only a single
+    // package's candidates are held at a time. Packages come in name order, and
+    // each package's candidates in the order that resolving considers them.
+    pub(crate) fn all_candidates(
+        &self,
+    ) -> impl Iterator<Item = Result<(&PackageName, Candidate)>> + '_ {
+        self.package_names().into_iter().flat_map(move |name| {
+            let candidates: Vec<Result<(&PackageName, Candidate)>> = match self.candidates(name) {
+                Ok(mut candidates) => {
+                    candidates.sort_by(|l, r| l.cmp(r).reverse());
+                    candidates.into_iter().map(|c| Ok((name, c))).collect()
+                }
+                Err(err) => vec![Err(err)],
+            };
+            candidates
+        })
+    }
+
+    // The values that dependency markers are evaluated against, unless we're
+    // asked for candidates in some other environment.
+    pub(crate) fn environment(&self) -> &HashMap<String, String> {
+        &self.builder.environment
+    }
+
+    pub(crate) fn candidates<P: AsRef<PackageName>>(&self, package: P) -> Result<Vec<Candidate>> {
+        self.candidates_in(package, self.environment())
+    }
+
+    // Like candidates, but only returns candidates from the named repository.
+    pub(crate) fn pinned_candidates<P: AsRef<PackageName>>(
+        &self,
+        package: P,
+        repository: &str,
+    ) -> Result<Vec<Candidate>> {
+        self.pinned_candidates_in(package, repository, self.environment())
+    }
+
+    // Like candidates, but with dependency markers evaluated against environment,
+    // so the same data can be resolved for more than one target.
+    pub(crate) fn candidates_in<P: AsRef<PackageName>>(
+        &self,
+        package: P,
+        environment: &HashMap<String, String>,
+    ) -> Result<Vec<Candidate>> {
+        self.candidates_where(package, environment, |_| true)
+    }
+
+    pub(crate) fn pinned_candidates_in<P: AsRef<PackageName>>(
+        &self,
+        package: P,
+        repository: &str,
+        environment: &HashMap<String, String>,
+    ) -> Result<Vec<Candidate>> {
+        self.candidates_where(package, environment, |repo| repo.name == repository)
+    }
+
+    fn candidates_where<P: AsRef<PackageName>>(
+        &self,
+        package: P,
+        environment: &HashMap<String, String>,
+        filter: impl Fn(&config::Repository) -> bool,
+    ) -> Result<Vec<Candidate>> {
+        // The root candidate is internal too, and it uses 0, so we start at 1.
+        if let Some((idx, _, archive)) = self.archives.get_full(package.as_ref()) {
+            return Ok(vec![archive.candidate(source_id(idx)? + 1)]);
+        }
+
+        let mut candidates = Vec::<Candidate>::new();
+        let mut seen = HashMap::<&Version, (&config::Repository, &Release)>::new();
+
+        // Our repositories come in preference order, so our Vec is sorted by that,
+        // however the list of versions within that is not sorted, so we'll need to
+        // resort the full list later.
+        for (preference, repo, data) in self.prioritized() {
+            if !filter(repo) {
+                continue;
+            }
+
+            if let Some(packages) = self.releases(repo, data, package.as_ref())? {
+                for (version, release) in packages.iter() {
+                    // Our repositories are iterated in preference order, so if we've
+                    // already seen this version, then what we saw came from a
+                    // preferred repository, and if they disagree, it wins.
+                    if let Some((first, other)) = seen.get(version) {
+                        if other.dependencies != release.dependencies {
+                            info!(
+                                target: LOGNAME,
+                                "using dependencies for {} {} from {} instead of {}: {}",
+                                package.as_ref(),
+                                version,
+                                first.name,
+                                repo.name,
+                                differences(&other.dependencies, &release.dependencies)
+                            );
+                            continue;
+                        }
+                    } else {
+                        seen.insert(version, (repo, release));
+                    }
+
+                    candidates.push(Candidate::new(
+                        version,
+                        Box::new(
+                            RepositorySource::new(preference, repo.clone())
+                                .with_yanked(release.yanked.clone()),
+                        ),
+                        Box::new(StaticDependencies::new(release.dependencies(
+                            package.as_ref(),
+                            version,
+                            environment,
+                        )?)),
+                    ));
+                }
+            }
+        }
+
+        Ok(candidates)
+    }
+}
+
+impl RepositoryBuilder {
+    // With the strict strategy, any version of a package that more than one
+    // repository provides has to have the same dependencies in all of them.
+    // Checking that means parsing every package, even for lazy repositories,
+    // though shards that haven't been fetched yet are skipped, since fetching
+    // every one of them would defeat the point of sharding. Those are checked by
+    // check_package_conflicts once they've been fetched.
+    fn check_conflicts<'a>(
+        &self,
+        data: impl Iterator<Item = (&'a config::Repository, &'a RepoData)>,
+    ) -> Result<()> {
+        if self.conflict_strategy != ConflictStrategy::Strict {
+            return Ok(());
+        }
+
+        let mut seen = HashMap::new();
+        for (repo, data) in data {
+            for (package, releases) in data.packages.iter() {
+                if releases.is_unfetched() {
+                    continue;
+                }
+
+                check_releases(&mut seen, repo, package, releases.get(package, self)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Like check_conflicts, but only for package, fetching its shard from every
+    // repository that has one for it.
+    fn check_package_conflicts<'a>(
+        &self,
+        package: &'a PackageName,
+        data: impl Iterator<Item = (&'a config::Repository, &'a RepoData)>,
+    ) -> Result<()> {
+        if self.conflict_strategy != ConflictStrategy::Strict {
+            return Ok(());
+        }
+
+        let mut seen = HashMap::new();
+        for (repo, data) in data {
+            if let Some(releases) = data.packages.get(package) {
+                check_releases(&mut seen, repo, package, releases.get(package, self)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Fetches the releases of a single package from a sharded index. Shards are
+    // cached by their digest, so one that we already have is never fetched again,
+    // and a stale cached index can still be used without the network, as long as
+    // we've fetched the shards we need from it before.
+    fn fetch_shard(
+        &self,
+        package: &PackageName,
+        url: &Url,
+        digest: &Digest,
+    ) -> Result<HashMap<Version, Release>> {
+        let shard = match self.cached_shard(digest) {
+            Some(shard) => {
+                trace!(target: LOGNAME, "using cached releases for {}", package);
+                shard
+            }
+            None => {
+                let shard = self.download_shard(package, url, digest)?;
+                if let Err(err) = write_cached_shard(&self.cache, digest, &shard) {
+                    self.warn(format!(
+                        "could not cache the index shard for {}: {}",
+                        package, err
+                    ));
+                }
+                shard
+            }
+        };
+
+        parse_releases(package, &shard)
+    }
+
+    // A shard has to match the digest that its index has for it, since only the
+    // index itself is ever signed.
+    fn download_shard(&self, package: &PackageName, url: &Url, digest: &Digest) -> Result<Vec<u8>> {
+        info!(target: LOGNAME, "fetching releases for {} from {}", package, url);
+        let shard = self.read(self.open(url)?)?;
+        if !digest.matches(&shard[..])? {
+            return Err(RepositoryError::DigestMismatch {
+                url: url.to_string(),
+                digest: digest.clone(),
+            });
+        }
+
+        Ok(shard)
+    }
+
+    // A cached shard that doesn't match its digest anymore is as good as missing.
+    fn cached_shard(&self, digest: &Digest) -> Option<Vec<u8>> {
+        let mut shard = Vec::new();
+        cached_shard_path(&self.cache, digest)
+            .ok()?
+            .open_file()
+            .ok()?
+            .read_to_end(&mut shard)
+            .ok()?;
+
+        digest.matches(&shard[..]).ok()?.then_some(shard)
+    }
+
+    // Anything we warn about is also kept, so that callers who want to treat
+    // warnings as errors can.
+    fn warn(&self, message: String) {
+        warn!(target: LOGNAME, "{}", message);
+        self.warnings.borrow_mut().push(message);
+    }
+
+    fn fetch_and_record(&mut self, repo: &config::Repository) -> Result<RepoData> {
+        let start = Instant::now();
+        let (result, cached) = match self.fetch_one(repo).map_err(|e| redirects(repo, e)) {
+            Err(err)
+                if repo.allow_stale_on_error
+                    && matches!(repo.url.scheme(), "http" | "https")
+                    && is_transient(&err) =>
+            {
+                match self.load_stale(repo, &err) {
+                    Some(stale) => (Ok(stale), true),
+                    None => (Err(err), false),
+                }
+            }
+            result => (result, false),
+        };
+        if let Ok((data, _)) = &result {
+            for warning in check_aliases(repo, data) {
+                self.warn(warning);
+            }
+            if let Some(warning) = check_filters(repo, data) {
+                self.warn(warning);
+            }
+        }
+
+        let fetch = RepositoryFetch {
+            name: repo.name.clone(),
+            url: repo.url.to_string(),
+            bytes: result.as_ref().map(|(_, bytes)| *bytes).unwrap_or(0),
+            versions: result
+                .as_ref()
+                .map(|(data, _)| data.version_count())
+                .unwrap_or(0),
+            cached,
+            duration: start.elapsed(),
+            fetched_at: self.clock.now(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+        self.fetches.insert(repo.clone(), fetch);
+
+        result.map(|(data, _)| data)
+    }
+
+    // Falls back to our cached copy of a repository's index when it couldn't be
+    // fetched, which could be arbitrarily out of date, so we warn about it loudly,
+    // including how old it is. Returns None if we don't have a usable copy. This
+    // is only for when the repository couldn't be reached, since if it sent us
+    // something bad, falling back would let whoever sent it pin us to an old index.
+    fn load_stale(
+        &mut self,
+        repo: &config::Repository,
+        err: &RepositoryError,
+    ) -> Option<(RepoData, u64)> {
+        let index = read_cached_index(&self.cache, repo).ok()?;
+        let loaded = match self.load(repo, &repo.url, &index[..]) {
+            Ok(loaded) => loaded,
+            Err(load_err) => {
+                info!(
+                    target: LOGNAME,
+                    "cached index for {} is unusable: {}", repo.name, load_err
+                );
+                return None;
+            }
+        };
+
+        let age = read_cached_at(&self.cache, repo)
+            .and_then(|at| self.clock.now().duration_since(at).ok())
+            .map(|age| format!("{} old", format_age(age)))
+            .unwrap_or_else(|| "of unknown age".to_string());
+        self.warn(format!(
+            "could not fetch {}, using a stale cached index that is {}: {}",
+            repo.name, age, err
+        ));
+
+        Some(loaded)
+    }
+
+    fn fetch_one(&mut self, repo: &config::Repository) -> Result<(RepoData, u64)> {
+        if repo.mirrors.is_empty() {
+            return self.fetch_from(repo, &repo.url);
+        }
+
+        // Mirrors are interchangeable copies of the same index, so we just use
+        // whichever one we can get to first, trying the primary url first.
+        let mut attempts = Vec::new();
+        let mut transient = true;
+        for url in std::iter::once(&repo.url).chain(repo.mirrors.iter()) {
+            match self.fetch_from(repo, url).map_err(|e| redirects(repo, e)) {
+                Ok(result) => {
+                    info!(target: LOGNAME, "fetched {} from {}", repo.name, url);
+                    return Ok(result);
+                }
+                Err(err) => {
+                    self.warn(format!(
+                        "could not fetch {} from {}: {}",
+                        repo.name, url, err
+                    ));
+                    transient &= is_transient(&err);
+                    attempts.push(format!("{}: {}", url, err));
+                }
+            }
+        }
+
+        Err(RepositoryError::MirrorsFailed {
+            repository: repo.name.clone(),
+            attempts,
+            transient,
+        })
+    }
+
+    fn fetch_from(&self, repo: &config::Repository, url: &Url) -> Result<(RepoData, u64)> {
+        // For git repositories, we check the repository out into a temporary
+        // directory and then read the index from there like any other file, the
+        // checkout has to live until we're done reading from it.
+        let checkout = match url.scheme() {
+            "git+ssh" => Some(checkout(repo, url)?),
+            _ => None,
+        };
+        let url = match &checkout {
+            Some(dir) => {
+                let path = dir
+                    .path()
+                    .join(repo.path.as_deref().unwrap_or(DEFAULT_GIT_INDEX_PATH));
+                Url::from_file_path(path).map_err(|_| RepositoryError::GitError {
+                    repository: repo.name.clone(),
+                    message: "invalid checkout path".to_string(),
+                })?
+            }
+            None => url.clone(),
+        };
+
+        // We read the whole index up front, both because any signature has to be
+        // verified over the exact bytes we were given before we trust anything in
+        // them, and so that we can keep a copy of them in our cache.
+        let (index, part) = match self.fetch_delta(repo, &url) {
+            Ok(Some(index)) => (index, None),
+            Ok(None) => self.download_index(repo, &url)?,
+            Err(err) => {
+                info!(
+                    target: LOGNAME,
+                    "could not fetch delta for {}, fetching full index: {}", repo.name, err
+                );
+                self.download_index(repo, &url)?
+            }
+        };
+        if let Some(key) = &repo.public_key {
+            let signature = self.read(self.open(&signature_url(&url))?)?;
+            verify(repo, key, &index, &signature)?;
+        }
+
+        let loaded = self.load(repo, &url, &index[..])?;
+        match part {
+            Some(part) => self.cache_partial_index(repo, &part),
+            None => self.cache_index(repo, &index),
+        }
+
+        // Our checkout is gone as soon as we return, so any shards in it that we
+        // don't already have have to be cached now, even though they won't be read
+        // until they're first needed.
+        if checkout.is_some() {
+            for (name, releases) in loaded.0.packages.iter() {
+                releases.preserve(name, self)?;
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    // Rather than fetching the full index, we can ask the server for only what has
+    // changed since our cached copy, as a delta that rebuilds the new index out of
+    // pieces of our cached copy and whatever is new, wherever in the index it was
+    // changed. Since we can't otherwise know that the delta was made against the
+    // copy we have, we only trust the result if it matches the digest that the
+    // server publishes alongside the index. Returns None whenever we can't use a
+    // delta.
+    fn fetch_delta(&self, repo: &config::Repository, url: &Url) -> Result<Option<Vec<u8>>> {
+        if !repo.delta || !matches!(url.scheme(), "http" | "https") {
+            return Ok(None);
+        }
+
+        let cached = match read_cached_index(&self.cache, repo) {
+            Ok(index) => index,
+            Err(_) => return Ok(None),
+        };
+        let digest = match String::from_utf8(self.read(self.get(&digest_url(url))?)?)
+            .ok()
+            .and_then(|d| d.trim().parse::<Digest>().ok())
+        {
+            Some(digest) => digest,
+            None => {
+                info!(target: LOGNAME, "invalid index digest for {}", repo.name);
+                return Ok(None);
+            }
+        };
+
+        if digest.matches(&cached[..])? {
+            trace!(target: LOGNAME, "cached index for {} is up to date", repo.name);
+            return Ok(Some(cached));
+        }
+
+        let mut writer = digest.algorithm.writer(io::sink());
+        writer.write_all(&cached)?;
+        let from = writer.finish()?;
+
+        let response = self
+            .client
+            .get(self.rewritten(&delta_url(url, &from))?)
+            .send()?;
+        if !response.status().is_success() {
+            trace!(
+                target: LOGNAME,
+                "no delta for {} from {}", repo.name, from
+            );
+            return Ok(None);
+        }
+
+        let delta = self.read(response)?;
+        let index = match serde_json::from_slice::<Vec<DeltaOp>>(&delta)
+            .ok()
+            .and_then(|ops| apply_delta(&cached, &ops, self.max_index_size))
+        {
+            Some(index) => index,
+            None => {
+                info!(target: LOGNAME, "invalid delta for {}", repo.name);
+                return Ok(None);
+            }
+        };
+
+        if !digest.matches(&index[..])? {
+            info!(
+                target: LOGNAME,
+                "delta for {} did not apply cleanly", repo.name
+            );
+            return Ok(None);
+        }
+
+        trace!(
+            target: LOGNAME,
+            "fetched {} bytes of delta for {}",
+            delta.len(),
+            repo.name
+        );
+        Ok(Some(index))
+    }
+
+    // Streams an index into our cache as it's downloaded, rather than buffering the
+    // response, and then reads it back, so that we only ever have one copy of it
+    // in memory. It only replaces our cached copy once it's been verified and
+    // loaded, so it's returned along with the partial file that it's still in. If
+    // we can't write to our cache, we just download it into memory instead.
+    fn download_index(
+        &self,
+        repo: &config::Repository,
+        url: &Url,
+    ) -> Result<(Vec<u8>, Option<VfsPath>)> {
+        let part = match partial_index_path(&self.cache, repo).and_then(|part| {
+            self.cache.join(INDEX_CACHE_DIR)?.create_dir_all()?;
+            part.create_file().map(|file| (part, file))
+        }) {
+            Ok(part) => part,
+            Err(err) => {
+                self.warn(format!("could not cache index for {}: {}", repo.name, err));
+                return Ok((self.read(self.open(url)?)?, None));
+            }
+        };
+
+        let (part, mut file) = part;
+        let mut reader = LimitedReader::new(self.open(url)?, self.max_index_size);
+        let result = io::copy(&mut reader, &mut file);
+        drop(file);
+        if reader.exceeded {
+            part.remove_file()?;
+            return Err(RepositoryError::IndexTooLarge {
+                limit: self.max_index_size,
+            });
+        }
+        if let Err(err) = result {
+            part.remove_file()?;
+            return Err(err.into());
+        }
+
+        let mut index = Vec::new();
+        part.open_file()?.read_to_end(&mut index)?;
+
+        Ok((index, Some(part)))
+    }
+
+    // Like cache_index, but for an index that's already been downloaded into our
+    // cache, which only has to be moved into place.
+    fn cache_partial_index(&self, repo: &config::Repository, part: &VfsPath) {
+        if let Err(err) = commit_cached_index(&self.cache, repo, part, self.clock.now()) {
+            self.warn(format!("could not cache index for {}: {}", repo.name, err));
+        }
+    }
+
+    // The cache is purely derived from our repositories, so failing to write to
+    // it is never fatal, we just won't have a copy.
+    fn cache_index(&self, repo: &config::Repository, index: &[u8]) {
+        if let Err(err) = write_cached_index(&self.cache, repo, index, self.clock.now()) {
+            self.warn(format!("could not cache index for {}: {}", repo.name, err));
+        }
+    }
+
+    fn verify_url(
+        &self,
+        repo: &config::Repository,
+        url: &Url,
+        digest: &Digest,
+        network: bool,
+    ) -> Result<(), String> {
+        match url.scheme() {
+            "http" | "https" if url.host().is_some() => {}
+            "file" => check_local(repo, url)
+                .and_then(|_| local_path(url))
+                .map(drop)
+                .map_err(|err| err.to_string())?,
+            scheme => return Err(format!("unsupported url {}", scheme)),
+        }
+
+        if !network {
+            return Ok(());
+        }
+
+        let reader = self.open(url).map_err(|err| err.to_string())?;
+        match digest.matches(reader) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!("contents do not match {}", digest)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    // Hashing a large artifact over and over is expensive, so once an artifact
+    // has been verified, we remember its size and when it was last modified, and
+    // only hash it again if either has changed. Without knowing where our cache
+    // is on disk, we can't tell when it was modified, so we always hash it.
+    fn verify_artifact(&self, artifact: &VfsPath, digest: &Digest) -> Result<bool> {
+        let fingerprint = self.fingerprint(artifact);
+        if fingerprint.is_some()
+            && read_verified(&self.cache).get(&artifact.filename()) == fingerprint.as_ref()
+        {
+            return Ok(true);
+        }
+
+        if !digest.matches(artifact.open_file()?)? {
+            return Ok(false);
+        }
+        self.record_verified(artifact)?;
+
+        Ok(true)
+    }
+
+    // Like our cached indices, failing to record a verification is never fatal,
+    // the artifact will just get verified again next time.
+    fn record_verified(&self, artifact: &VfsPath) -> Result<()> {
+        let fingerprint = match self.fingerprint(artifact) {
+            Some(fingerprint) => fingerprint,
+            None => return Ok(()),
+        };
+        let mut verified = read_verified(&self.cache);
+        verified.insert(artifact.filename(), fingerprint);
+
+        if let Err(err) = write_verified(&self.cache, &verified) {
+            self.warn(format!(
+                "could not record verification of {}: {}",
+                artifact.filename(),
+                err
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn fingerprint(&self, artifact: &VfsPath) -> Option<Verified> {
+        let path = self
+            .cache_dir
+            .as_ref()?
+            .join(ARTIFACT_CACHE_DIR)
+            .join(artifact.filename());
+        let metadata = std::fs::metadata(path).ok()?;
+
+        Some(Verified {
+            size: metadata.len(),
+            modified: metadata.modified().ok()?,
+        })
+    }
+
+    // Downloads url into part, continuing from whatever part already holds when
+    // the server lets us, and otherwise starting over. Once it's complete, part
+    // has to match digest, and if it doesn't, it's removed so that the next
+    // attempt doesn't resume from something that's already wrong.
+    fn download_from(&self, url: &Url, digest: &Digest, part: &VfsPath) -> Result<()> {
+        let offset = match part.is_file()? {
+            true => part.metadata()?.len,
+            false => 0,
+        };
+
+        // The digest is checked as we download, so that a bad download is caught
+        // without having to read it back afterwards.
+        let verified = match url.scheme() {
+            "http" | "https" => {
+                let mut request = self.client.get(self.rewritten(url)?);
+                if offset > 0 {
+                    request = request.header(RANGE, format!("bytes={}-", offset));
+                }
+
+                let mut response = request.send()?;
+                match response.status() {
+                    StatusCode::PARTIAL_CONTENT if offset > 0 => {
+                        trace!(target: LOGNAME, "resuming {} from byte {}", url, offset);
+                        let mut writer = digest.algorithm.writer(part.append_file()?);
+                        writer.hash_existing(part.open_file()?)?;
+                        io::copy(&mut response, &mut writer)?;
+                        writer.finish()? == *digest
+                    }
+                    // There's nothing past the end of what we already have, so our
+                    // partial file should already be complete.
+                    StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+                        digest.matches(part.open_file()?)?
+                    }
+                    _ => {
+                        if offset > 0 {
+                            trace!(target: LOGNAME, "{} does not support resuming", url);
+                        }
+                        let mut response = response.error_for_status()?;
+                        let mut writer = digest.algorithm.writer(part.create_file()?);
+                        io::copy(&mut response, &mut writer)?;
+                        writer.finish()? == *digest
+                    }
+                }
+            }
+            _ => {
+                let mut writer = digest.algorithm.writer(part.create_file()?);
+                io::copy(&mut self.open(url)?, &mut writer)?;
+                writer.finish()? == *digest
+            }
+        };
+
+        if !verified {
+            part.remove_file()?;
+            return Err(RepositoryError::DigestMismatch {
+                url: url.to_string(),
+                digest: digest.clone(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn ping(&self, repo: &config::Repository) -> Result<()> {
+        let url = &repo.url;
+        match url.scheme() {
+            "file" => {
+                std::fs::metadata(local_path(url)?)?;
+            }
+            "git+ssh" => {
+                git(repo, &["ls-remote", "--exit-code", &git_url(url), "HEAD"])?;
+            }
+            _ => {
+                self.client
+                    .head(self.rewritten(url)?)
+                    .send()?
+                    .error_for_status()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn open(&self, url: &Url) -> Result<Box<dyn Read>> {
+        let reader: Box<dyn Read> = match url.scheme() {
+            "file" => Box::new(BufReader::new(File::open(local_path(url)?)?)),
+            _ => Box::new(self.get(url)?),
+        };
+
+        Ok(reader)
+    }
+
+    // Whatever a url is rewritten to still has to use one of our allowed schemes.
+    fn rewritten(&self, url: &Url) -> Result<Url> {
+        let rewritten = match &self.rewrite {
+            Some(rewrite) => {
+                let rewritten = rewrite(url);
+                if &rewritten != url {
+                    trace!(target: LOGNAME, "rewrote {} to {}", url, rewritten);
+                }
+                rewritten
+            }
+            None => url.clone(),
+        };
+
+        if !self.allowed_schemes.iter().any(|s| s == rewritten.scheme()) {
+            return Err(RepositoryError::DisallowedScheme {
+                url: rewritten.to_string(),
+                scheme: rewritten.scheme().to_string(),
+            });
+        }
+
+        Ok(rewritten)
+    }
+
+    // Rate limited servers tell us to slow down with a 429, so we wait for as
+    // long as they ask (or back off if they don't say) and then try again, up
+    // until we've used up our retries.
+    fn get(&self, url: &Url) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let response = self.client.get(self.rewritten(url)?).send()?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS || attempt >= self.retries {
+                return Ok(response.error_for_status()?);
+            }
+
+            let delay = retry_after(&response)
+                .unwrap_or_else(|| BACKOFF_BASE * 2u32.saturating_pow(attempt))
+                .min(MAX_RETRY_DELAY);
+            info!(
+                target: LOGNAME,
+                "{} is rate limited, retrying in {:?}", url, delay
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    fn read<R: Read>(&self, reader: R) -> Result<Vec<u8>> {
+        let mut reader = LimitedReader::new(reader, self.max_index_size);
+        let mut buf = Vec::new();
+        let result = reader.read_to_end(&mut buf);
+
+        if reader.exceeded {
+            return Err(RepositoryError::IndexTooLarge {
+                limit: self.max_index_size,
+            });
+        }
+
+        result?;
+        Ok(buf)
+    }
+
+    // Url is where the index came from, which is what a sharded index's shards are
+    // relative to.
+    fn load(&self, repo: &config::Repository, url: &Url, index: &[u8]) -> Result<(RepoData, u64)> {
+        if index.len() as u64 > self.max_index_size {
+            return Err(RepositoryError::IndexTooLarge {
+                limit: self.max_index_size,
+            });
+        }
+
+        // We hold off on parsing any releases until we know that we understand
+        // the schema of this index, since a newer schema could mean anything.
+        let data: RawRepoData =
+            serde_json::from_slice(index).map_err(|source| RepositoryError::InvalidIndex {
+                repository: repo.name.clone(),
+                // Errors that aren't about the JSON itself don't have a location.
+                location: (source.line() > 0).then(|| (source.line(), source.column())),
+                source,
+            })?;
+        if !SUPPORTED_SCHEMA_VERSIONS.contains(&data.meta.schema_version) {
+            return Err(RepositoryError::UnsupportedIndexSchema {
+                repository: repo.name.clone(),
+                found: data.meta.schema_version,
+                supported: SUPPORTED_SCHEMA_VERSIONS,
+            });
+        }
+
+        if !data.meta.extra.is_empty() {
+            info!(
+                target: LOGNAME,
+                "ignoring unknown metadata fields for {}: {}",
+                repo.name,
+                field_names(&data.meta.extra)
+            );
+        }
+
+        // Every lazy package shares the one copy of the index, and only knows
+        // where in it its releases are.
+        let shared: Option<Rc<[u8]>> =
+            (repo.lazy && data.meta.layout != IndexLayout::Sharded).then(|| Rc::from(index));
+
+        let mut packages = HashMap::with_capacity(data.packages.len());
+        for (name, raw) in data.packages.into_iter() {
+            if data.meta.layout == IndexLayout::Sharded {
+                let releases = shard(&name, url, raw)?;
+                packages.insert(name, releases);
+                continue;
+            }
+
+            let releases = match &shared {
+                None => Releases::Parsed(parse_releases(&name, raw.get().as_bytes())?),
+                Some(shared) => {
+                    // Raw is borrowed from index, so where it starts is just how
+                    // far its pointer is into index.
+                    let start = raw.get().as_ptr() as usize - index.as_ptr() as usize;
+                    Releases::Lazy {
+                        index: shared.clone(),
+                        span: start..start + raw.get().len(),
+                        parsed: OnceCell::new(),
+                    }
+                }
+            };
+            packages.insert(name, releases);
+        }
+
+        Ok((
+            RepoData {
+                meta: data.meta,
+                packages,
+            },
+            index.len() as u64,
+        ))
+    }
+}
+
+// What our redirect policy stops with when a redirect goes somewhere that uses a
+// scheme that we don't allow, so that we can tell it apart from too many
+// redirects once reqwest hands it back to us.
+#[derive(Debug)]
+struct DisallowedRedirect {
+    url: String,
+    scheme: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct Release {
-    #[serde(default)]
-    dependencies: HashMap<PackageName, VersionReq>,
-    #[serde(rename = "urls")]
-    _urls: Vec<Url>,
-    #[serde(rename = "digests")]
-    _digests: HashMap<String, String>,
+impl fmt::Display for DisallowedRedirect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "redirected to {}, which uses the {} scheme",
+            self.url, self.scheme
+        )
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct RepoData {
-    #[serde(rename = "meta")]
-    _meta: MetaData,
-    packages: HashMap<PackageName, HashMap<Version, Release>>,
+impl std::error::Error for DisallowedRedirect {}
+
+// reqwest only tells us that there were too many redirects, we want to say
+// which repository was doing it.
+fn redirects(repo: &config::Repository, err: RepositoryError) -> RepositoryError {
+    match err {
+        RepositoryError::HTTPError(e) if e.is_redirect() => {
+            match std::error::Error::source(&e).and_then(|s| s.downcast_ref::<DisallowedRedirect>())
+            {
+                Some(disallowed) => RepositoryError::DisallowedScheme {
+                    url: disallowed.url.clone(),
+                    scheme: disallowed.scheme.clone(),
+                },
+                None => RepositoryError::TooManyRedirects {
+                    repository: repo.name.clone(),
+                },
+            }
+        }
+        err => err,
+    }
 }
 
-#[derive(Debug)]
-pub(crate) struct Repository {
-    client: HTTPClient,
-    data: IndexMap<config::Repository, RepoData>,
+// Whether err means that we couldn't reach a repository, or it couldn't answer
+// us right now, rather than that it gave us something we won't accept.
+fn is_transient(err: &RepositoryError) -> bool {
+    match err {
+        RepositoryError::HTTPError(err) => match err.status() {
+            Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+            None => err.is_timeout() || err.is_connect() || err.is_request() || err.is_body(),
+        },
+        RepositoryError::IoError(_) => true,
+        RepositoryError::MirrorsFailed { transient, .. } => *transient,
+        _ => false,
+    }
 }
 
-impl Repository {
-    pub(crate) fn new() -> Result<Repository> {
-        let client = HTTPClient::builder().gzip(true).build()?;
-        let data = IndexMap::<config::Repository, RepoData>::new();
+// A file url with a host is somewhere on another machine, which we have no way to
+// reach, so only ones without one are actually local files.
+fn local_path(url: &Url) -> Result<PathBuf> {
+    match url.host() {
+        None => url
+            .to_file_path()
+            .map_err(|_| RepositoryError::NotLocalFile {
+                url: url.to_string(),
+            }),
+        Some(_) => Err(RepositoryError::NotLocalFile {
+            url: url.to_string(),
+        }),
+    }
+}
 
-        Ok(Repository { client, data })
+// A repository that we fetch from somewhere else has no business pointing us at
+// files on our own machine, so only local repositories can use file urls.
+fn check_local(repo: &config::Repository, url: &Url) -> Result<()> {
+    if url.scheme() == "file" && repo.url.scheme() != "file" {
+        return Err(RepositoryError::LocalFileForbidden {
+            url: url.to_string(),
+            repository: repo.name.clone(),
+        });
     }
 
-    pub(crate) fn fetch(
-        mut self,
-        repos: &[config::Repository],
-        callback: impl Fn(),
-    ) -> Result<Repository> {
-        info!(target: LOGNAME, "fetching package metadata");
-        for repo in repos.iter() {
-            let data: RepoData = match repo.url.scheme() {
-                "file" => {
-                    let file = File::open(repo.url.to_file_path().unwrap())?;
-                    let reader = BufReader::new(file);
+    Ok(())
+}
 
-                    serde_json::from_reader(reader)?
-                }
-                _ => self
-                    .client
-                    .get(repo.url.clone())
-                    .send()?
-                    .error_for_status()?
-                    .json()?,
-            };
-            self.data.insert(repo.clone(), data);
-            (callback)();
+// git itself doesn't know about our git+ prefix, and any fragment is the ref
+// that we should check out, rather than part of the url.
+fn git_url(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url.as_str().trim_start_matches("git+").to_string()
+}
+
+fn checkout(repo: &config::Repository, url: &Url) -> Result<TempDir> {
+    let dir = tempfile::tempdir()?;
+    let dest = dir.path().to_string_lossy().to_string();
+
+    let mut args = vec!["clone", "--depth", "1", "--quiet"];
+    if let Some(reference) = url.fragment() {
+        args.extend(["--branch", reference]);
+    }
+    let url = git_url(url);
+    args.extend([url.as_str(), dest.as_str()]);
+
+    info!(target: LOGNAME, "cloning {} for {}", url, repo.name);
+    git(repo, &args)?;
+
+    Ok(dir)
+}
+
+// Runs git, letting it use whatever ssh-agent or keys the user has configured.
+fn git(repo: &config::Repository, args: &[&str]) -> Result<()> {
+    let error = |message: String| RepositoryError::GitError {
+        repository: repo.name.clone(),
+        message,
+    };
+
+    let output = Command::new("git")
+        .args(args)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .map_err(|e| error(e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(error(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Retry-After can either be a number of seconds, or a date to wait until.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let when = httpdate::parse_http_date(value).ok()?;
+            Some(
+                when.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO),
+            )
+        }
+    }
+}
+
+fn signature_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_path(&format!("{}.sig", url.path()));
+    url
+}
+
+fn digest_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_path(&format!("{}.digest", url.path()));
+    url
+}
+
+// Deltas live next to the index, named for the digest of the index that they
+// were made against.
+fn delta_url(url: &Url, from: &Digest) -> Url {
+    let mut url = url.clone();
+    url.set_path(&format!(
+        "{}.delta/{}-{}.json",
+        url.path(),
+        from.algorithm,
+        from.hex
+    ));
+    url
+}
+
+// A delta is a list of operations, which together rebuild the new index, either
+// by copying a range of bytes out of the index that it was made against, or by
+// inserting bytes that are new.
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum DeltaOp {
+    Copy(usize, usize),
+    Insert(String),
+}
+
+// Returns None if any operation doesn't fit within index, or if the result would
+// be larger than limit.
+fn apply_delta(index: &[u8], ops: &[DeltaOp], limit: u64) -> Option<Vec<u8>> {
+    let mut applied = Vec::new();
+    for op in ops.iter() {
+        match op {
+            DeltaOp::Copy(start, end) => applied.extend_from_slice(index.get(*start..*end)?),
+            DeltaOp::Insert(bytes) => applied.extend_from_slice(bytes.as_bytes()),
         }
 
-        Ok(self)
+        if applied.len() as u64 > limit {
+            return None;
+        }
     }
 
-    pub(crate) fn candidates<P: AsRef<PackageName>>(&self, package: P) -> Vec<Candidate> {
-        let mut candidates = Vec::<Candidate>::new();
+    Some(applied)
+}
 
-        // Because our underlying type of self.data is an IndexMap, this will ensure
-        // that our Vec is sorted by the order our repositories were defined in, however
-        // the list of versions within that is not sorted, so we'll need to resort
-        // the full list later.
-        for (idx, (repo, data)) in self.data.iter().enumerate() {
-            if let Some(packages) = data.packages.get(package.as_ref()) {
-                for (version, release) in packages.iter() {
-                    candidates.push(Candidate::new(
-                        version,
-                        Box::new(RepositorySource::new(
-                            u64::try_from(idx).unwrap(),
-                            repo.clone(),
-                        )),
-                        Box::new(StaticDependencies::new(release.dependencies.clone())),
-                    ));
-                }
+// Shards live next to the index that lists them, whatever the index is named.
+fn shard_url(url: &Url, package: &PackageName) -> Url {
+    let mut url = url.clone();
+    let dir = url
+        .path()
+        .rsplit_once('/')
+        .map(|(dir, _)| dir.to_string())
+        .unwrap_or_default();
+    url.set_path(&format!("{}/{}/{}.json", dir, SHARD_DIR, package));
+    url
+}
+
+// In a sharded index, each package only has the digest of its shard, which we
+// don't fetch until something asks for that package.
+fn shard(package: &PackageName, url: &Url, raw: &RawValue) -> Result<Releases> {
+    let digest: String =
+        serde_json::from_str(raw.get()).map_err(|source| RepositoryError::InvalidPackage {
+            package: package.clone(),
+            source,
+        })?;
+
+    Ok(Releases::Sharded {
+        url: shard_url(url, package),
+        digest: digest
+            .parse()
+            .map_err(|source| RepositoryError::InvalidShardDigest {
+                package: package.clone(),
+                source,
+            })?,
+        parsed: OnceCell::new(),
+    })
+}
+
+// Both the public key and the signature are base64 encoded ed25519 values, and
+// the signature covers the raw bytes of the index exactly as they were served.
+fn verify(repo: &config::Repository, key: &str, index: &[u8], signature: &[u8]) -> Result<()> {
+    let key = base64::decode(key.trim())
+        .ok()
+        .and_then(|k| PublicKey::from_bytes(&k).ok())
+        .ok_or_else(|| RepositoryError::InvalidPublicKey {
+            repository: repo.name.clone(),
+        })?;
+
+    let signature = std::str::from_utf8(signature)
+        .ok()
+        .and_then(|s| base64::decode(s.trim()).ok())
+        .and_then(|s| Signature::try_from(&s[..]).ok());
+
+    match signature.map(|s| key.verify_strict(index, &s)) {
+        Some(Ok(())) => Ok(()),
+        _ => Err(RepositoryError::SignatureInvalid {
+            repository: repo.name.clone(),
+        }),
+    }
+}
+
+// Wraps a reader, and errors once more than limit bytes have been read from
+// it, which lets us cap how much data we'll accept without having to buffer
+// the entire thing up front.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R: Read> LimitedReader<R> {
+    fn new(inner: R, limit: u64) -> LimitedReader<R> {
+        LimitedReader {
+            inner,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            // We've used up our limit, but that's only a problem if there is
+            // actually more data left to read.
+            let mut probe = [0u8; 1];
+            if self.inner.read(&mut probe)? > 0 {
+                self.exceeded = true;
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "repository index exceeded size limit",
+                ));
+            }
+            return Ok(0);
+        }
+
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        self.remaining -= n as u64;
+
+        Ok(n)
+    }
+}
+
+impl RepoData {
+    // For lazily parsed repositories, this only counts the packages that have
+    // been parsed so far.
+    fn version_count(&self) -> usize {
+        self.packages
+            .values()
+            .map(|releases| releases.parsed_len())
+            .sum()
+    }
+
+    // The name that package's releases are actually under, which is the name of
+    // whatever it's an alias of, unless it's a real package itself.
+    fn target<'a>(&'a self, package: &'a PackageName) -> &'a PackageName {
+        match self.packages.contains_key(package) {
+            true => package,
+            false => self.meta.aliases.get(package).unwrap_or(package),
+        }
+    }
+
+    // Whether package's releases are in a shard that we haven't fetched yet.
+    fn is_unfetched(&self, package: &PackageName) -> bool {
+        self.packages
+            .get(self.target(package))
+            .is_some_and(|releases| releases.is_unfetched())
+    }
+
+    fn releases(
+        &self,
+        repo: &config::Repository,
+        package: &PackageName,
+        builder: &RepositoryBuilder,
+    ) -> Result<Option<&HashMap<Version, Release>>> {
+        // Anything filtered out of a repository is as if it wasn't there at all.
+        if !repo.exposes(package) {
+            return Ok(None);
+        }
+
+        // A real package always wins over an alias, so that a repository can't
+        // accidentally hide one of its own packages behind an alias.
+        if let Some(releases) = self.packages.get(package) {
+            return releases.get(package, builder).map(Some);
+        }
+
+        let target = match self.meta.aliases.get(package) {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        // An alias can't be a way around a filter, so what it points to has to
+        // be exposed too.
+        if !repo.exposes(target) {
+            return Ok(None);
+        }
+        info!(
+            target: LOGNAME,
+            "resolving {} as an alias of {} in {}", package, target, repo.name
+        );
+
+        self.packages
+            .get(target)
+            .map(|r| r.get(target, builder))
+            .transpose()
+    }
+}
+
+// A release without a digest that we can use can never be verified, so it can
+// never be installed, but that's no reason to throw away every other release of
+// the package, so we skip just that release.
+fn parse_releases(package: &PackageName, json: &[u8]) -> Result<HashMap<Version, Release>> {
+    let raw: HashMap<Version, RawRelease> =
+        serde_json::from_slice(json).map_err(|source| RepositoryError::InvalidPackage {
+            package: package.clone(),
+            source,
+        })?;
+
+    Ok(raw
+        .into_iter()
+        .filter_map(|(version, raw)| match Release::try_from(raw) {
+            Ok(release) => Some((version, release)),
+            Err(err) => {
+                warn!(
+                    target: LOGNAME,
+                    "skipping {} {}: {}", package, version, err
+                );
+                None
+            }
+        })
+        .collect())
+}
+
+// Records each of releases as seen, failing if a version that's already been seen
+// in another repository has different dependencies there.
+fn check_releases<'a>(
+    seen: &mut HashMap<(&'a PackageName, &'a Version), (&'a config::Repository, &'a Release)>,
+    repo: &'a config::Repository,
+    package: &'a PackageName,
+    releases: &'a HashMap<Version, Release>,
+) -> Result<()> {
+    for (version, release) in releases.iter() {
+        match seen.get(&(package, version)) {
+            Some((first, other)) if other.dependencies != release.dependencies => {
+                return Err(RepositoryError::DivergentMetadata {
+                    package: package.clone(),
+                    version: version.clone(),
+                    first: first.name.clone(),
+                    second: repo.name.clone(),
+                    differences: differences(&other.dependencies, &release.dependencies),
+                });
+            }
+            Some(_) => {}
+            None => {
+                seen.insert((package, version), (repo, release));
             }
         }
+    }
+
+    Ok(())
+}
+
+fn field_names(extra: &HashMap<String, Value>) -> String {
+    let mut names: Vec<&str> = extra.keys().map(|k| k.as_str()).collect();
+    names.sort_unstable();
+    names.join(", ")
+}
+
+// Cached indices are named for where they came from, rather than for the name of
+// their repository, so that no two repositories can ever share a cached index
+// unless they really do have the same index.
+fn cached_index_path(cache: &VfsPath, repo: &config::Repository) -> VfsResult<VfsPath> {
+    let mut source = repo.url.to_string();
+    if let Some(path) = &repo.path {
+        source.push('\n');
+        source.push_str(path);
+    }
+
+    // We're using MD5 here because it's short and fast, we're not using this in
+    // a security sensitive aspect.
+    cache
+        .join(INDEX_CACHE_DIR)?
+        .join(&format!("{:x}.json", md5::compute(source)))
+}
+
+// Where an index is downloaded to, before we know that it's one we can use.
+fn partial_index_path(cache: &VfsPath, repo: &config::Repository) -> VfsResult<VfsPath> {
+    let path = cached_index_path(cache, repo)?;
+    cache
+        .join(INDEX_CACHE_DIR)?
+        .join(&format!("{}.part", path.filename()))
+}
+
+// Our filesystem doesn't give us modification times, so we keep when each index
+// was cached alongside it, as seconds since the epoch.
+fn cached_at_path(cache: &VfsPath, repo: &config::Repository) -> VfsResult<VfsPath> {
+    let path = cached_index_path(cache, repo)?;
+    cache
+        .join(INDEX_CACHE_DIR)?
+        .join(&format!("{}.fetched", path.filename()))
+}
 
-        candidates
+fn write_cached_index(
+    cache: &VfsPath,
+    repo: &config::Repository,
+    index: &[u8],
+    now: SystemTime,
+) -> VfsResult<()> {
+    let part = partial_index_path(cache, repo)?;
+    cache.join(INDEX_CACHE_DIR)?.create_dir_all()?;
+    part.create_file()?.write_all(index)?;
+
+    commit_cached_index(cache, repo, &part, now)
+}
+
+// Moves a fully written index into place, so that our cached copy is always a
+// complete one, and records when it was cached.
+fn commit_cached_index(
+    cache: &VfsPath,
+    repo: &config::Repository,
+    part: &VfsPath,
+    now: SystemTime,
+) -> VfsResult<()> {
+    let path = cached_index_path(cache, repo)?;
+    if path.is_file()? {
+        path.remove_file()?;
+    }
+    part.move_file(&path)?;
+
+    if let Ok(since) = now.duration_since(SystemTime::UNIX_EPOCH) {
+        cached_at_path(cache, repo)?
+            .create_file()?
+            .write_all(since.as_secs().to_string().as_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn read_cached_at(cache: &VfsPath, repo: &config::Repository) -> Option<SystemTime> {
+    let mut contents = String::new();
+    cached_at_path(cache, repo)
+        .ok()?
+        .open_file()
+        .ok()?
+        .read_to_string(&mut contents)
+        .ok()?;
+    let secs: u64 = contents.trim().parse().ok()?;
+
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Only the largest unit matters for telling someone how stale something is.
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (count, unit) = match secs {
+        0..=59 => (secs, "second"),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+
+    match count {
+        1 => format!("{} {}", count, unit),
+        _ => format!("{} {}s", count, unit),
+    }
+}
+
+fn prune(path: &VfsPath, cleanup: &mut CacheCleanup) -> VfsResult<()> {
+    let size = path.metadata()?.len;
+    path.remove_file()?;
+    trace!(target: LOGNAME, "pruned {} from our cache", path.as_str());
+
+    cleanup.files += 1;
+    cleanup.bytes += size;
+
+    Ok(())
+}
+
+fn read_cached_index(cache: &VfsPath, repo: &config::Repository) -> VfsResult<Vec<u8>> {
+    let mut index = Vec::new();
+    cached_index_path(cache, repo)?
+        .open_file()?
+        .read_to_end(&mut index)?;
+
+    Ok(index)
+}
+
+// Shards are cached by their digest, rather than by the repository they came
+// from, since a shard with the same digest is the same shard, wherever it's from.
+fn cached_shard_path(cache: &VfsPath, digest: &Digest) -> VfsResult<VfsPath> {
+    cache
+        .join(INDEX_CACHE_DIR)?
+        .join(SHARD_CACHE_DIR)?
+        .join(&format!("{}-{}.json", digest.algorithm, digest.hex))
+}
+
+// The digest of every shard that index lists, or None when it isn't an index
+// that we can read.
+fn shard_digests(index: &[u8]) -> Option<Vec<Digest>> {
+    let data: RawRepoData = serde_json::from_slice(index).ok()?;
+    if data.meta.layout != IndexLayout::Sharded {
+        return Some(Vec::new());
+    }
+
+    data.packages
+        .values()
+        .map(|raw| serde_json::from_str::<String>(raw.get()).ok()?.parse().ok())
+        .collect()
+}
+
+fn write_cached_shard(cache: &VfsPath, digest: &Digest, shard: &[u8]) -> VfsResult<()> {
+    let dir = cache.join(INDEX_CACHE_DIR)?.join(SHARD_CACHE_DIR)?;
+    let path = cached_shard_path(cache, digest)?;
+    let part = dir.join(&format!("{}.part", path.filename()))?;
+    dir.create_dir_all()?;
+    part.create_file()?.write_all(shard)?;
+    if path.is_file()? {
+        path.remove_file()?;
+    }
+
+    part.move_file(&path)
+}
+
+// What an artifact looked like on disk when we verified it, which has to still
+// be true for us to trust that it's the same file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct Verified {
+    size: u64,
+    modified: SystemTime,
+}
+
+// Maps the filename of each artifact that has been verified to what it looked
+// like when it was. This is only ever a cache, so if it's missing or unreadable,
+// it's empty.
+fn read_verified(cache: &VfsPath) -> HashMap<String, Verified> {
+    cache
+        .join(ARTIFACT_CACHE_DIR)
+        .and_then(|dir| dir.join(VERIFIED_CACHE_FILE))
+        .and_then(|path| path.open_file())
+        .ok()
+        .and_then(|file| serde_json::from_reader(file).ok())
+        .unwrap_or_default()
+}
+
+fn write_verified(cache: &VfsPath, verified: &HashMap<String, Verified>) -> VfsResult<()> {
+    let path = cache.join(ARTIFACT_CACHE_DIR)?.join(VERIFIED_CACHE_FILE)?;
+    serde_json::to_writer(path.create_file()?, verified).map_err(io::Error::from)?;
+
+    Ok(())
+}
+
+// Candidates identify where they came from by the index of their repository.
+fn source_id(idx: usize) -> Result<u64> {
+    u64::try_from(idx).map_err(|_| RepositoryError::TooManyRepositories)
+}
+
+// Describes how two sets of dependencies differ, for any error or log message.
+fn differences<C: PartialEq + fmt::Display>(
+    first: &HashMap<PackageName, Vec<C>>,
+    second: &HashMap<PackageName, Vec<C>>,
+) -> String {
+    let mut names: Vec<&PackageName> = first.keys().chain(second.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    let describe = |reqs: Option<&Vec<C>>| match reqs {
+        Some(reqs) => reqs
+            .iter()
+            .map(|req| req.to_string())
+            .collect::<Vec<String>>()
+            .join(" / "),
+        None => "none".to_string(),
+    };
+
+    names
+        .into_iter()
+        .filter(|name| first.get(*name) != second.get(*name))
+        .map(|name| {
+            format!(
+                "{} ({} vs {})",
+                name,
+                describe(first.get(name)),
+                describe(second.get(name))
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn check_aliases(repo: &config::Repository, data: &RepoData) -> Vec<String> {
+    data.meta
+        .aliases
+        .iter()
+        .filter(|(_, target)| !data.packages.contains_key(*target))
+        .map(|(alias, target)| {
+            format!(
+                "alias {} in {} points to {}, which does not exist",
+                alias, repo.name, target
+            )
+        })
+        .collect()
+}
+
+// A repository whose filters exclude every one of its packages is almost
+// certainly a mistake, since it can't contribute anything.
+fn check_filters(repo: &config::Repository, data: &RepoData) -> Option<String> {
+    if data.packages.is_empty() || data.packages.keys().any(|name| repo.exposes(name)) {
+        return None;
     }
+
+    Some(format!(
+        "every package in {} is excluded by its filters, so it contributes nothing",
+        repo.name
+    ))
 }
 
 #[derive(Debug, Clone)]
 struct RepositorySource {
     repository_id: u64,
     repository: config::Repository,
+    yanked: Option<Yanked>,
 }
 
 impl RepositorySource {
@@ -125,8 +2520,14 @@ impl RepositorySource {
         RepositorySource {
             repository_id,
             repository,
+            yanked: None,
         }
     }
+
+    fn with_yanked(mut self, yanked: Option<Yanked>) -> RepositorySource {
+        self.yanked = yanked;
+        self
+    }
 }
 
 impl fmt::Display for RepositorySource {
@@ -148,4 +2549,445 @@ impl Source for RepositorySource {
     fn discriminator(&self) -> u64 {
         self.repository_id
     }
+
+    fn yanked(&self) -> Option<Yanked> {
+        self.yanked.clone()
+    }
+
+    fn repository(&self) -> Option<&str> {
+        Some(&self.repository.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use vfs::MemoryFS;
+
+    use super::*;
+    use crate::types::{DigestAlgorithm, WithSource};
+
+    fn write(path: &VfsPath, content: &str) {
+        path.create_file()
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+    }
+
+    fn setup() -> (VfsPath, config::Config) {
+        setup_with("repositories:\n  - https://example.com/index.json\n")
+    }
+
+    fn setup_with(config: &str) -> (VfsPath, config::Config) {
+        let root = VfsPath::new(MemoryFS::new());
+        write(&root.join("mqpkg.yml").unwrap(), config);
+        let config = config::Config::load(&root).unwrap();
+        (root, config)
+    }
+
+    #[test]
+    fn default_repository() {
+        let (root, config) = setup_with(
+            "repositories:
+  - https://first.example.com/index.json
+  - https://second.example.com/index.json
+  - https://third.example.com/index.json
+default_repository: https://second.example.com/index.json
+",
+        );
+        let index = format!(
+            r#"{{"meta": {{"name": "example"}}, "packages": {{"foo": {{"1.0.0": {{"urls": [], "digest": "{}"}}}}}}}}"#,
+            "0".repeat(64)
+        );
+        let repos = config.repositories();
+        let indices: Vec<(&config::Repository, &[u8])> =
+            repos.iter().map(|repo| (repo, index.as_bytes())).collect();
+
+        let builder = RepositoryBuilder::new(&config, root.join("cache").unwrap()).unwrap();
+        let repository = builder.preload(&indices).unwrap();
+
+        // The default repository wins the tie, and the rest keep their order.
+        let mut candidates = repository
+            .candidates(PackageName::new("foo").unwrap())
+            .unwrap();
+        candidates.sort_by(|l, r| l.cmp(r).reverse());
+        let order: Vec<&str> = candidates
+            .iter()
+            .map(|c| c.source().repository().unwrap())
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                repos[1].name.as_str(),
+                repos[0].name.as_str(),
+                repos[2].name.as_str()
+            ]
+        );
+    }
+
+    #[test]
+    fn lazy_releases() {
+        let (root, config) = setup();
+        let mut repo = config.repositories()[0].clone();
+        repo.lazy = true;
+        let release = r#"{"1.0.0": {"urls": ["https://example.com/foo-1.0.0.tar.gz"], "digests": {"sha256": "0000000000000000000000000000000000000000000000000000000000000000"}}}"#;
+        let index = format!(
+            r#"{{"meta": {{"name": "example"}}, "packages": {{"foo": {}}}}}"#,
+            release
+        );
+
+        let builder = RepositoryBuilder::new(&config, root.join("cache").unwrap()).unwrap();
+        let (data, bytes) = builder.load(&repo, &repo.url, index.as_bytes()).unwrap();
+        assert_eq!(bytes, index.len() as u64);
+
+        let foo = PackageName::new("foo").unwrap();
+        let releases = &data.packages[&foo];
+        match releases {
+            Releases::Lazy { index, span, .. } => {
+                assert_eq!(&index[span.clone()], release.as_bytes())
+            }
+            releases => panic!("expected lazy releases, got {:?}", releases),
+        }
+        assert_eq!(releases.parsed_len(), 0);
+        assert!(releases
+            .get(&foo, &builder)
+            .unwrap()
+            .contains_key(&Version::new(1, 0, 0)));
+        assert_eq!(releases.parsed_len(), 1);
+    }
+
+    #[test]
+    fn skips_unusable_digests() {
+        let release = |digests: &str| {
+            format!(
+                r#"{{"urls": ["https://example.com/foo.tar.gz"], "digests": {}}}"#,
+                digests
+            )
+        };
+        let json = format!(
+            r#"{{"1.0.0": {}, "1.1.0": {}, "1.2.0": {}}}"#,
+            release(&format!(r#"{{"sha256": "{}"}}"#, "0".repeat(64))),
+            release(r#"{"md5": "00000000000000000000000000000000"}"#),
+            release("{}"),
+        );
+
+        let foo = PackageName::new("foo").unwrap();
+        let releases = parse_releases(&foo, json.as_bytes()).unwrap();
+        assert_eq!(
+            releases.keys().collect::<Vec<_>>(),
+            vec![&Version::new(1, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn local_files() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        let remote: config::Repository = "https://example.com/index.json".parse().unwrap();
+        let local: config::Repository = "file:///srv/index.json".parse().unwrap();
+
+        assert_eq!(
+            local_path(&url("file:///srv/foo.tar.gz")).unwrap(),
+            PathBuf::from("/srv/foo.tar.gz")
+        );
+        assert!(matches!(
+            local_path(&url("file://example.com/srv/foo.tar.gz")),
+            Err(RepositoryError::NotLocalFile { .. })
+        ));
+
+        assert!(check_local(&local, &url("file:///srv/foo.tar.gz")).is_ok());
+        assert!(check_local(&remote, &url("https://example.com/foo.tar.gz")).is_ok());
+        assert!(matches!(
+            check_local(&remote, &url("file:///etc/passwd")),
+            Err(RepositoryError::LocalFileForbidden { .. })
+        ));
+    }
+
+    #[test]
+    fn deltas() {
+        let index = br#"{"a": 1, "b": 2}"#;
+        let ops: Vec<DeltaOp> = serde_json::from_str(
+            r#"[{"copy": [0, 8]}, {"insert": " \"c\": 3,"}, {"copy": [8, 16]}]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            apply_delta(index, &ops, 1024).unwrap(),
+            br#"{"a": 1, "c": 3, "b": 2}"#.to_vec()
+        );
+        assert_eq!(apply_delta(index, &ops, 10), None);
+        assert_eq!(apply_delta(index, &[DeltaOp::Copy(8, 32)], 1024), None);
+    }
+
+    #[test]
+    fn rewritten_schemes() {
+        let (root, config) = setup();
+        let mut builder = RepositoryBuilder::new(&config, root.join("cache").unwrap()).unwrap();
+        let url = Url::parse("https://example.com/index.json").unwrap();
+        assert_eq!(builder.rewritten(&url).unwrap(), url);
+
+        let rewrite: Rc<UrlRewrite> = Rc::new(|url: &Url| {
+            let mut url = url.clone();
+            url.set_scheme("http").unwrap();
+            url
+        });
+        builder.with_rewrite(Some(rewrite));
+        assert!(matches!(
+            builder.rewritten(&url),
+            Err(RepositoryError::DisallowedScheme { scheme, .. }) if scheme == "http"
+        ));
+    }
+
+    #[test]
+    fn prune_cache() {
+        let (root, config) = setup();
+        let cache = root.join("cache").unwrap();
+        let repo = &config.repositories()[0];
+
+        write_cached_index(&cache, repo, b"{}", SystemTime::now()).unwrap();
+        write(
+            &cache
+                .join(INDEX_CACHE_DIR)
+                .unwrap()
+                .join("gone.json")
+                .unwrap(),
+            "{}",
+        );
+        write(&root.join("state.yml").unwrap(), "");
+
+        let artifacts = cache.join(ARTIFACT_CACHE_DIR).unwrap();
+        artifacts.create_dir_all().unwrap();
+        write(&artifacts.join("sha256-aa").unwrap(), "aa");
+        write(&artifacts.join("sha256-bb.part").unwrap(), "b");
+        let fingerprint = Verified {
+            size: 2,
+            modified: SystemTime::UNIX_EPOCH,
+        };
+        let verified = HashMap::from([
+            ("sha256-aa".to_string(), fingerprint),
+            ("sha256-cc".to_string(), fingerprint),
+        ]);
+        write_verified(&cache, &verified).unwrap();
+
+        let builder = RepositoryBuilder::new(&config, cache.clone()).unwrap();
+        let cleanup = builder.prune_cache(config.repositories()).unwrap();
+
+        assert_eq!(cleanup, CacheCleanup { files: 2, bytes: 3 });
+        assert!(read_cached_index(&cache, repo).is_ok());
+        assert!(read_cached_at(&cache, repo).is_some());
+        assert!(root.join("state.yml").unwrap().is_file().unwrap());
+        assert!(artifacts.join("sha256-aa").unwrap().is_file().unwrap());
+        assert_eq!(
+            read_verified(&cache),
+            HashMap::from([("sha256-aa".to_string(), fingerprint)])
+        );
+    }
+
+    #[test]
+    fn verification_cache() {
+        let (_, config) = setup();
+        let dir = TempDir::new().unwrap();
+        let cache: VfsPath = vfs::PhysicalFS::new(dir.path().to_path_buf()).into();
+        let artifacts = cache.join(ARTIFACT_CACHE_DIR).unwrap();
+        artifacts.create_dir_all().unwrap();
+
+        let digest: Digest = format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(b"aa"))
+            .parse()
+            .unwrap();
+        let artifact = artifacts.join("sha256-aa").unwrap();
+        write(&artifact, "aa");
+
+        // Without knowing where our cache is on disk, every check hashes again.
+        let mut builder = RepositoryBuilder::new(&config, cache.clone()).unwrap();
+        assert!(builder.verify_artifact(&artifact, &digest).unwrap());
+        assert!(read_verified(&cache).is_empty());
+
+        builder.with_cache_dir(Some(dir.path().to_path_buf()));
+        assert!(builder.verify_artifact(&artifact, &digest).unwrap());
+        assert!(read_verified(&cache).contains_key("sha256-aa"));
+
+        // Replacing it with something else of the same size changes when it was
+        // modified, so it gets hashed again, and fails.
+        write(&artifact, "bb");
+        let path = dir.path().join(ARTIFACT_CACHE_DIR).join("sha256-aa");
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        assert!(!builder.verify_artifact(&artifact, &digest).unwrap());
+    }
+
+    #[test]
+    fn only_transient_errors_are_stale() {
+        let unreachable = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert!(is_transient(&RepositoryError::IoError(unreachable)));
+        assert!(!is_transient(&RepositoryError::SignatureInvalid {
+            repository: "example".to_string()
+        }));
+        assert!(!is_transient(&RepositoryError::IndexTooLarge { limit: 1 }));
+
+        // Mirrors only count if every one of them couldn't be reached.
+        let mirrors = |transient| RepositoryError::MirrorsFailed {
+            repository: "example".to_string(),
+            attempts: Vec::new(),
+            transient,
+        };
+        assert!(is_transient(&mirrors(true)));
+        assert!(!is_transient(&mirrors(false)));
+    }
+
+    #[test]
+    fn aliases_respect_filters() {
+        let (root, config) = setup_with(
+            "repositories:
+  - name: example
+    url: https://example.com/index.json
+    exclude: [bar]
+",
+        );
+        let index = format!(
+            r#"{{"meta": {{"name": "example", "aliases": {{"foo": "bar"}}}}, "packages": {{"bar": {{"1.0.0": {{"urls": [], "digest": "{}"}}}}}}}}"#,
+            "0".repeat(64)
+        );
+        let repo = &config.repositories()[0];
+
+        let builder = RepositoryBuilder::new(&config, root.join("cache").unwrap()).unwrap();
+        let repository = builder.preload(&[(repo, index.as_bytes())]).unwrap();
+
+        // Excluding bar has to exclude it under any other name as well.
+        let foo = PackageName::new("foo").unwrap();
+        assert!(repository.candidates(&foo).unwrap().is_empty());
+        assert!(repository.package_names().is_empty());
+    }
+
+    #[test]
+    fn shadowed_follows_candidates() {
+        let (root, config) = setup_with(
+            "repositories:
+  - https://first.example.com/index.json
+  - https://second.example.com/index.json
+",
+        );
+        let release = format!(r#"{{"urls": [], "digest": "{}"}}"#, "0".repeat(64));
+        let first = format!(
+            r#"{{"meta": {{"name": "first"}}, "packages": {{"foo": {{"1.0.0": {}}}}}}}"#,
+            release
+        );
+        let second = format!(
+            r#"{{"meta": {{"name": "second", "aliases": {{"bar": "foo"}}}}, "packages": {{"foo": {{"1.0.0": {}, "2.0.0-beta": {}}}}}}}"#,
+            release, release
+        );
+        let repos = config.repositories();
+        let indices = [
+            (&repos[0], first.as_bytes()),
+            (&repos[1], second.as_bytes()),
+        ];
+
+        let builder = RepositoryBuilder::new(&config, root.join("cache").unwrap()).unwrap();
+        let repository = builder.preload(&indices).unwrap();
+
+        // The pre-release is only preferred when it's allowed, and the alias is
+        // never listed as a package of its own.
+        let shadowed = repository.shadowed(|_| false).unwrap();
+        assert_eq!(shadowed.len(), 1);
+        let (name, preferred, providers) = &shadowed[0];
+        assert_eq!(name.to_string(), "foo");
+        assert_eq!(preferred.as_deref(), Some(repos[0].name.as_str()));
+        assert_eq!(
+            providers,
+            &vec![
+                (
+                    repos[1].name.clone(),
+                    vec![Version::parse("2.0.0-beta").unwrap(), Version::new(1, 0, 0)]
+                ),
+                (repos[0].name.clone(), vec![Version::new(1, 0, 0)]),
+            ]
+        );
+
+        let shadowed = repository.shadowed(|_| true).unwrap();
+        assert_eq!(shadowed[0].1.as_deref(), Some(repos[1].name.as_str()));
+    }
+
+    #[test]
+    fn prune_cache_shards() {
+        let (root, config) = setup();
+        let cache = root.join("cache").unwrap();
+        let repo = &config.repositories()[0];
+
+        let digest = |shard: &str| {
+            let mut writer = DigestAlgorithm::Sha256.writer(std::io::sink());
+            writer.write_all(shard.as_bytes()).unwrap();
+            let digest = writer.finish().unwrap();
+            write_cached_shard(&cache, &digest, shard.as_bytes()).unwrap();
+            digest
+        };
+        let listed = digest("{}");
+        let unlisted = digest("[]");
+        let index = format!(
+            r#"{{"meta": {{"name": "example", "layout": "sharded"}}, "packages": {{"foo": "{}"}}}}"#,
+            listed.hex
+        );
+        write_cached_index(&cache, repo, index.as_bytes(), SystemTime::now()).unwrap();
+
+        let builder = RepositoryBuilder::new(&config, cache.clone()).unwrap();
+        let cleanup = builder.prune_cache(config.repositories()).unwrap();
+
+        assert_eq!(cleanup, CacheCleanup { files: 1, bytes: 2 });
+        assert!(builder.cached_shard(&listed).is_some());
+        assert!(builder.cached_shard(&unlisted).is_none());
+    }
+
+    #[test]
+    fn sharded_releases() {
+        let (root, config) = setup_with(
+            "repositories:
+  - https://first.example.com/index.json
+  - https://second.example.com/index.json
+conflict_strategy: strict
+",
+        );
+        let cache = root.join("cache").unwrap();
+
+        // Each shard is already in our cache, so nothing is ever fetched.
+        let index = |name: &str, dependencies: &str| {
+            let shard = format!(
+                r#"{{"1.0.0": {{"urls": [], "digest": "{}", "dependencies": {}}}}}"#,
+                "0".repeat(64),
+                dependencies
+            );
+            let mut writer = DigestAlgorithm::Sha256.writer(std::io::sink());
+            writer.write_all(shard.as_bytes()).unwrap();
+            let digest = writer.finish().unwrap();
+            write_cached_shard(&cache, &digest, shard.as_bytes()).unwrap();
+
+            format!(
+                r#"{{"meta": {{"name": "{}", "layout": "sharded"}}, "packages": {{"foo": "{}"}}}}"#,
+                name, digest.hex
+            )
+        };
+        let first = index("first", "{}");
+        let second = index("second", r#"{"bar": "*"}"#);
+        let repos = config.repositories();
+        let builder = RepositoryBuilder::new(&config, cache.clone()).unwrap();
+        let repository = builder
+            .preload(&[
+                (&repos[0], first.as_bytes()),
+                (&repos[1], second.as_bytes()),
+            ])
+            .unwrap();
+        let foo = PackageName::new("foo").unwrap();
+
+        // Neither searching nor looking for shadowed packages fetches a shard.
+        assert_eq!(repository.search(None, None).unwrap(), vec![&foo]);
+        assert!(repository.shadowed(|_| true).unwrap().is_empty());
+        assert!(repository.data.values().all(|data| data.is_unfetched(&foo)));
+
+        // Once a shard is fetched, it's held to the strict strategy like any other.
+        assert!(matches!(
+            repository.candidates(foo.clone()),
+            Err(RepositoryError::DivergentMetadata { .. })
+        ));
+    }
 }
@@ -2,14 +2,18 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
+use std::collections::HashMap;
 use std::fmt;
 
 use pubgrub::error::PubGrubError;
-use pubgrub::report::{DefaultStringReporter, Reporter};
+use pubgrub::report::{DefaultStringReporter, DerivationTree, External, Reporter};
+use pubgrub::term::Term;
+use pubgrub::version_set::VersionSet as BaseVersionSet;
 
-use crate::errors::SolverError;
+use crate::errors::{RepositoryError, SolverError};
 use crate::resolver::pubgrub::{Candidate, DerivedResult, VersionSet};
 use crate::resolver::types::Name;
+use crate::types::{Explanation, ExplanationFact, ExplanationStep, ExplanationTerm, PackageName};
 
 impl SolverError {
     pub(super) fn from_pubgrub(err: PubGrubError<Name, VersionSet<Candidate>>) -> Self {
@@ -30,8 +34,43 @@ impl SolverError {
             },
             PubGrubError::Failure(s) => SolverError::Failure(s),
             PubGrubError::ErrorRetrievingDependencies { .. } => SolverError::Impossible,
-            PubGrubError::ErrorChoosingPackageVersion(_) => SolverError::Impossible,
-            PubGrubError::ErrorInShouldCancel(_) => SolverError::Impossible,
+            PubGrubError::ErrorChoosingPackageVersion(err) => {
+                match err.downcast::<RepositoryError>() {
+                    Ok(err) => SolverError::RepositoryError(*err),
+                    Err(_) => SolverError::Impossible,
+                }
+            }
+            // We only ever cancel because we've exhausted our search or run out
+            // of time, and we pass the error describing that through as is.
+            PubGrubError::ErrorInShouldCancel(err) => match err.downcast::<SolverError>() {
+                Ok(err) => *err,
+                Err(_) => SolverError::Impossible,
+            },
+        }
+    }
+
+    // Why there was no solution, step by step, for anyone that wants to render it
+    // themselves, rather than relying on humanized.
+    pub fn explanation(&self) -> Option<Explanation> {
+        match self {
+            SolverError::NoSolution(dt) => {
+                let mut dt = (**dt).clone();
+                dt.collapse_no_versions();
+
+                let mut explanation = Explanation { steps: Vec::new() };
+                let mut shared = HashMap::new();
+                match explain(&dt, &mut explanation, &mut shared) {
+                    Cause::Step(_) => {}
+                    Cause::Fact(fact) => explanation.steps.push(ExplanationStep {
+                        facts: vec![fact],
+                        steps: Vec::new(),
+                        incompatible: Vec::new(),
+                    }),
+                }
+
+                Some(explanation)
+            }
+            _ => None,
         }
     }
 
@@ -63,3 +102,95 @@ impl std::error::Error for HumanizedNoSolutionError {
         None
     }
 }
+
+enum Cause {
+    Fact(ExplanationFact),
+    Step(usize),
+}
+
+// Walks dt depth first, so that every step comes after the steps that it follows
+// from, and steps that pubgrub shares between derivations are only added once.
+fn explain(
+    dt: &DerivedResult,
+    explanation: &mut Explanation,
+    shared: &mut HashMap<usize, usize>,
+) -> Cause {
+    match dt {
+        DerivationTree::External(external) => Cause::Fact(fact(external)),
+        DerivationTree::Derived(derived) => {
+            if let Some(step) = derived.shared_id.and_then(|id| shared.get(&id)) {
+                return Cause::Step(*step);
+            }
+
+            let mut step = ExplanationStep {
+                facts: Vec::new(),
+                steps: Vec::new(),
+                incompatible: Vec::new(),
+            };
+            for cause in [&derived.cause1, &derived.cause2] {
+                match explain(cause, explanation, shared) {
+                    Cause::Fact(fact) => step.facts.push(fact),
+                    Cause::Step(i) => step.steps.push(i),
+                }
+            }
+
+            let mut terms: Vec<(&Name, &Term<VersionSet<Candidate>>)> =
+                derived.terms.iter().collect();
+            terms.sort_by_key(|(name, _)| name.to_string());
+            step.incompatible = terms
+                .into_iter()
+                .map(|(name, term)| match term {
+                    Term::Positive(vs) => explain_term(name, vs, false),
+                    Term::Negative(vs) => explain_term(name, vs, true),
+                })
+                .collect();
+
+            explanation.steps.push(step);
+            let index = explanation.steps.len() - 1;
+            if let Some(id) = derived.shared_id {
+                shared.insert(id, index);
+            }
+
+            Cause::Step(index)
+        }
+    }
+}
+
+fn fact(external: &External<Name, VersionSet<Candidate>>) -> ExplanationFact {
+    match external {
+        External::NotRoot(..) => ExplanationFact::Requested,
+        External::NoVersions(name, vs) => ExplanationFact::NoVersions {
+            package: name.clone().into(),
+            versions: match *vs == VersionSet::full() {
+                true => None,
+                false => Some(vs.to_string()),
+            },
+        },
+        External::UnavailableDependencies(name, vs) => ExplanationFact::UnavailableDependencies {
+            package: name.clone().into(),
+            versions: vs.to_string(),
+        },
+        External::FromDependencyOf(name, vs, dependency, constraint) => ExplanationFact::Requires {
+            package: package(name),
+            versions: vs.to_string(),
+            dependency: dependency.clone().into(),
+            constraint: constraint.to_string(),
+        },
+    }
+}
+
+fn explain_term(name: &Name, vs: &VersionSet<Candidate>, negated: bool) -> ExplanationTerm {
+    ExplanationTerm {
+        package: package(name),
+        versions: vs.to_string(),
+        negated,
+    }
+}
+
+// Our root isn't really a package, it stands in for everything that was requested.
+fn package(name: &Name) -> Option<PackageName> {
+    match name.is_root() {
+        true => None,
+        false => Some(name.clone().into()),
+    }
+}
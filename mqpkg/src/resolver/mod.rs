@@ -2,17 +2,20 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
 use ::pubgrub::solver::resolve;
 use log::{info, log_enabled, trace};
 
 use crate::errors::SolverError;
 use crate::repository::Repository;
+use crate::resolver::pubgrub::RepositoryProvider;
 pub(crate) use crate::resolver::pubgrub::{Candidate, DerivedResult};
-use crate::resolver::pubgrub::{CandidateTrait, RepositoryProvider};
 pub(crate) use crate::resolver::types::{Name, Requirement, StaticDependencies};
-use crate::types::{Package, Packages, WithSource};
+use crate::types::{
+    Choice, Package, PackageName, Packages, ResolveProgress, UpgradeStrategy, VersionConstraint,
+};
 
 mod errors;
 mod pubgrub;
@@ -20,46 +23,182 @@ mod types;
 
 const LOGNAME: &str = "mqpkg::resolver";
 
-pub(crate) struct Solver {
-    repository: Repository,
+// Picks between equally good candidates, returning the index of the chosen one.
+pub(crate) type Chooser<'c> = dyn Fn(&PackageName, &[Choice]) -> usize + 'c;
+
+pub(crate) struct Solver<'r> {
+    repository: &'r Repository,
+    excluded: HashMap<PackageName, Vec<semver::Version>>,
+    pinned: HashMap<PackageName, String>,
+    overrides: HashMap<PackageName, VersionConstraint>,
+    limit: Option<u64>,
+    deadline: Option<Instant>,
+    no_deps: HashSet<PackageName>,
+    environment: Option<HashMap<String, String>>,
+    preferred: HashMap<PackageName, semver::Version>,
+    upgrading: HashSet<PackageName>,
+    strategy: UpgradeStrategy,
+    locked: HashMap<PackageName, semver::Version>,
+    prereleases: bool,
+    prerelease_packages: HashSet<PackageName>,
 }
 
-impl Solver {
-    pub(crate) fn new(repository: Repository) -> Solver {
-        Solver { repository }
+impl<'r> Solver<'r> {
+    pub(crate) fn new(repository: &'r Repository) -> Solver<'r> {
+        Solver {
+            repository,
+            excluded: HashMap::new(),
+            pinned: HashMap::new(),
+            overrides: HashMap::new(),
+            limit: None,
+            deadline: None,
+            no_deps: HashSet::new(),
+            environment: None,
+            preferred: HashMap::new(),
+            upgrading: HashSet::new(),
+            strategy: UpgradeStrategy::default(),
+            locked: HashMap::new(),
+            prereleases: false,
+            prerelease_packages: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn with_pinned(mut self, pinned: HashMap<PackageName, String>) -> Solver<'r> {
+        self.pinned = pinned;
+        self
+    }
+
+    pub(crate) fn with_overrides(
+        mut self,
+        overrides: HashMap<PackageName, VersionConstraint>,
+    ) -> Solver<'r> {
+        self.overrides = overrides;
+        self
+    }
+
+    pub(crate) fn with_limit(mut self, limit: Option<u64>) -> Solver<'r> {
+        self.limit = limit;
+        self
+    }
+
+    pub(crate) fn with_deadline(mut self, deadline: Option<Instant>) -> Solver<'r> {
+        self.deadline = deadline;
+        self
+    }
+
+    // Ignores the dependencies of packages, so that they're resolved by themselves,
+    // like pip's --no-deps, while everything else still gets its dependencies.
+    pub(crate) fn with_no_deps(mut self, packages: HashSet<PackageName>) -> Solver<'r> {
+        self.no_deps = packages;
+        self
+    }
+
+    // Evaluates dependency markers against environment, instead of the one the
+    // repository was fetched with.
+    pub(crate) fn with_environment(
+        mut self,
+        environment: Option<HashMap<String, String>>,
+    ) -> Solver<'r> {
+        self.environment = environment;
+        self
+    }
+
+    // Versions to keep whenever they still satisfy everything, instead of picking
+    // the newest version that does.
+    pub(crate) fn with_preferred(
+        mut self,
+        preferred: HashMap<PackageName, semver::Version>,
+    ) -> Solver<'r> {
+        self.preferred = preferred;
+        self
+    }
+
+    // Packages that are being upgraded, so any preferred version of theirs isn't
+    // kept. Eagerly, neither is one of anything that they depend on.
+    pub(crate) fn with_upgrading(
+        mut self,
+        packages: HashSet<PackageName>,
+        strategy: UpgradeStrategy,
+    ) -> Solver<'r> {
+        self.upgrading = packages;
+        self.strategy = strategy;
+        self
+    }
+
+    // Versions that are already locked in, which are still allowed if they've been
+    // yanked since, where any other yanked version is never picked.
+    pub(crate) fn with_locked(
+        mut self,
+        locked: HashMap<PackageName, semver::Version>,
+    ) -> Solver<'r> {
+        self.locked = locked;
+        self
+    }
+
+    // Allows pre-releases of every package, rather than only ones that a
+    // constraint explicitly mentions.
+    pub(crate) fn with_prereleases(mut self, enabled: bool) -> Solver<'r> {
+        self.prereleases = enabled;
+        self
+    }
+
+    // Like with_prereleases, but only for packages.
+    pub(crate) fn with_prereleases_for(mut self, packages: HashSet<PackageName>) -> Solver<'r> {
+        self.prerelease_packages = packages;
+        self
+    }
+
+    pub(crate) fn with_excluded(
+        mut self,
+        excluded: HashMap<PackageName, Vec<semver::Version>>,
+    ) -> Solver<'r> {
+        self.excluded = excluded;
+        self
     }
 
     pub(crate) fn resolve<N: Into<Name> + Clone, R: Into<Requirement> + Clone>(
         &self,
         reqs: HashMap<N, R>,
         callback: impl Fn(),
+        progress: impl Fn(&ResolveProgress),
+        chooser: Option<&Chooser>,
     ) -> Result<Packages, SolverError> {
         let package = Name::root();
         let version = Candidate::root(reqs.clone());
 
         let resolver = RepositoryProvider::new(
-            &self.repository,
+            self.repository,
+            &self.excluded,
+            &self.pinned,
+            &self.overrides,
             reqs.into_iter()
                 .map(|(p, r)| (p.into(), r.into()))
                 .collect(),
             Box::new(callback),
-        );
+            Box::new(progress),
+        )
+        .with_limit(self.limit)
+        .with_deadline(self.deadline)
+        .with_no_deps(Some(&self.no_deps))
+        .with_environment(self.environment.as_ref())
+        .with_preferred(Some(&self.preferred))
+        .with_upgrading(Some(&self.upgrading), self.strategy)
+        .with_locked(Some(&self.locked))
+        .with_prereleases(self.prereleases, Some(&self.prerelease_packages))
+        .with_chooser(chooser);
 
         info!(target: LOGNAME, "resolving requested packages");
 
-        let result = resolve(&resolver, package, version).map_err(SolverError::from_pubgrub)?;
+        let result = resolve(&resolver, package, version);
+        resolver.report();
+        let result = result.map_err(SolverError::from_pubgrub)?;
         let packages: Packages = result
             .into_iter()
             // Filter out the root package from our results since nothing but this
             // module should even be aware it exists.
             .filter(|(p, _)| !p.is_root())
             // Turn our (Name, Candidate) into (PackageName, Package)
-            .map(|(p, c)| {
-                (
-                    p.clone().into(),
-                    Package::new(p, c.version(), c.source().clone()),
-                )
-            })
+            .map(|(p, c)| (p.clone().into(), Package::new(p, c)))
             .collect();
 
         if log_enabled!(log::Level::Trace) {
@@ -6,11 +6,15 @@ use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 
+use pubgrub::version_set::VersionSet as BaseVersionSet;
+use semver::VersionReq;
+
 use crate::resolver::pubgrub::versionset::Candidate as CandidateTrait;
+use crate::resolver::pubgrub::VersionSet;
 use crate::resolver::types::{
-    Dependencies, Name, Requirement, StaticDependencies, Version, WithDependencies,
+    convert_req, Dependencies, Name, Requirement, StaticDependencies, Version, WithDependencies,
 };
-use crate::types::{Source, WithSource};
+use crate::types::{PackageName, Source, VersionConstraint, WithSource};
 
 #[derive(Debug, Clone)]
 struct InternalSource(u64);
@@ -60,6 +64,16 @@ impl Candidate {
         }
     }
 
+    // A candidate that didn't come from any repository, such as one read from a
+    // local package archive.
+    pub(crate) fn internal<V: Into<Version>>(
+        version: V,
+        id: u64,
+        dependencies: Box<dyn Dependencies + Sync + Send>,
+    ) -> Candidate {
+        Candidate::new(version, Box::new(InternalSource::new(id)), dependencies)
+    }
+
     pub(in crate::resolver) fn root<N: Into<Name>, R: Into<Requirement>>(
         reqs: HashMap<N, R>,
     ) -> Candidate {
@@ -77,6 +91,51 @@ impl Candidate {
             )),
         }
     }
+
+    // A candidate that stands in for version itself, as one end of a range, rather
+    // than for any release of it, so unlike new, it keeps the source id that
+    // version was built with.
+    pub(in crate::resolver) fn bound(version: Version) -> Candidate {
+        Candidate {
+            version,
+            source: Box::new(InternalSource::new(0)),
+            dependencies: Box::new(StaticDependencies::new(HashMap::<Name, Requirement>::new())),
+        }
+    }
+
+    // Whether this candidate matches req, using the same rules that the resolver
+    // itself uses, most notably for pre-releases, which a plain VersionReq::matches
+    // doesn't agree with us on. Anything that filters candidates should go through
+    // here (or satisfies) so that we only have one set of rules to get right.
+    pub(crate) fn matches(&self, req: &VersionReq) -> bool {
+        convert_req(req).contains(self)
+    }
+
+    // The names of everything that this candidate depends on.
+    pub(crate) fn dependency_names(&self) -> Vec<PackageName> {
+        self.dependencies
+            .get()
+            .unwrap_or_default()
+            .into_keys()
+            .map(PackageName::from)
+            .collect()
+    }
+
+    pub(crate) fn satisfies(&self, constraint: &VersionConstraint) -> bool {
+        self.satisfies_with(constraint, false)
+    }
+
+    // Like satisfies, but with any pre-release allowed when prereleases is, the same
+    // as the resolver does for a package that pre-releases have been allowed for,
+    // unless constraint is stable.
+    pub(crate) fn satisfies_with(&self, constraint: &VersionConstraint, prereleases: bool) -> bool {
+        let requirement = Requirement::from(constraint.clone());
+        let mut vs: VersionSet<Candidate> = (&requirement).into();
+        if prereleases && !requirement.is_stable() {
+            vs = vs.allowing_prereleases();
+        }
+        vs.contains(self)
+    }
 }
 
 impl WithDependencies for Candidate {
@@ -123,6 +182,12 @@ impl PartialOrd for Candidate {
     }
 }
 
+impl From<&Candidate> for semver::Version {
+    fn from(candidate: &Candidate) -> semver::Version {
+        (&candidate.version).into()
+    }
+}
+
 impl CandidateTrait for Candidate {
     type V = Version;
 
@@ -3,23 +3,38 @@
 // for complete details.
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::time::{Duration, Instant};
 
 use ::pubgrub::solver::{
     choose_package_with_fewest_versions, Dependencies as PDependencies, DependencyProvider,
 };
 use ::pubgrub::type_aliases::DependencyConstraints;
+use ::pubgrub::version_set::VersionSet as BaseVersionSet;
 use log::{log_enabled, trace};
 
+use crate::errors::{RepositoryError, SolverError};
 use crate::repository::Repository;
 pub(crate) use crate::resolver::pubgrub::Candidate;
-use crate::resolver::pubgrub::VersionSet;
+use crate::resolver::pubgrub::{CandidateTrait, VersionSet};
 use crate::resolver::types::WithDependencies;
 pub(crate) use crate::resolver::types::{Name, Requirement};
+use crate::resolver::Chooser;
+use crate::types::{
+    Choice, PackageName, ResolveProgress, UpgradeStrategy, VersionConstraint, WithSource,
+};
 
 const LOGNAME: &str = "mqpkg::resolver";
 
+// How often we report our progress, the resolver can take many thousands of
+// steps on a hard problem, so reporting on every one of them would be wasteful.
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+// How many of the most contended packages to name when we give up.
+const CONTENDED_PACKAGES: usize = 5;
+
 // Internal Solver keeps us from having to carefully maintain state, and let's us
 // rely on the rust lifetime mechanic for that. We construct a new InternalSolver
 // anytime that Solver::resolve is ran, which means that items that we don't want
@@ -28,32 +43,187 @@ const LOGNAME: &str = "mqpkg::resolver";
 // as a reference.
 pub(in crate::resolver) struct RepositoryProvider<'r, 'c> {
     repository: &'r Repository,
+    excluded: &'r HashMap<PackageName, Vec<semver::Version>>,
+    pinned: &'r HashMap<PackageName, String>,
+    overrides: &'r HashMap<PackageName, VersionConstraint>,
     requested: HashMap<Name, Requirement>,
     callback: Box<dyn Fn() + 'c>,
+    progress: Box<dyn Fn(&ResolveProgress) + 'c>,
+    examined: Cell<usize>,
+    decisions: Cell<usize>,
+    reported: Cell<Instant>,
+    limit: Option<u64>,
+    deadline: Option<Instant>,
+    no_deps: Option<&'r HashSet<PackageName>>,
+    environment: Option<&'r HashMap<String, String>>,
+    preferred: Option<&'r HashMap<PackageName, semver::Version>>,
+    upgrading: Option<&'r HashSet<PackageName>>,
+    strategy: UpgradeStrategy,
+    // Everything that an eager upgrade has reached so far.
+    reached: RefCell<HashSet<Name>>,
+    locked: Option<&'r HashMap<PackageName, semver::Version>>,
+    prereleases: bool,
+    prerelease_packages: Option<&'r HashSet<PackageName>>,
+    contention: RefCell<HashMap<Name, usize>>,
+    chooser: Option<&'c Chooser<'c>>,
 }
 
 impl<'r, 'c> RepositoryProvider<'r, 'c> {
     pub(in crate::resolver) fn new(
         repository: &'r Repository,
+        excluded: &'r HashMap<PackageName, Vec<semver::Version>>,
+        pinned: &'r HashMap<PackageName, String>,
+        overrides: &'r HashMap<PackageName, VersionConstraint>,
         requested: HashMap<Name, Requirement>,
         callback: Box<dyn Fn() + 'c>,
+        progress: Box<dyn Fn(&ResolveProgress) + 'c>,
     ) -> RepositoryProvider<'r, 'c> {
         RepositoryProvider {
             repository,
+            excluded,
+            pinned,
+            overrides,
             requested,
             callback,
+            progress,
+            examined: Cell::new(0),
+            decisions: Cell::new(0),
+            reported: Cell::new(Instant::now()),
+            limit: None,
+            deadline: None,
+            no_deps: None,
+            environment: None,
+            preferred: None,
+            upgrading: None,
+            strategy: UpgradeStrategy::default(),
+            reached: RefCell::new(HashSet::new()),
+            locked: None,
+            prereleases: false,
+            prerelease_packages: None,
+            contention: RefCell::new(HashMap::new()),
+            chooser: None,
         }
     }
 
-    fn list_versions(&self, package: &Name) -> std::vec::IntoIter<Candidate> {
+    pub(in crate::resolver) fn with_limit(mut self, limit: Option<u64>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub(in crate::resolver) fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub(in crate::resolver) fn with_no_deps(
+        mut self,
+        packages: Option<&'r HashSet<PackageName>>,
+    ) -> Self {
+        self.no_deps = packages;
+        self
+    }
+
+    pub(in crate::resolver) fn with_environment(
+        mut self,
+        environment: Option<&'r HashMap<String, String>>,
+    ) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    pub(in crate::resolver) fn with_preferred(
+        mut self,
+        preferred: Option<&'r HashMap<PackageName, semver::Version>>,
+    ) -> Self {
+        self.preferred = preferred;
+        self
+    }
+
+    pub(in crate::resolver) fn with_upgrading(
+        mut self,
+        packages: Option<&'r HashSet<PackageName>>,
+        strategy: UpgradeStrategy,
+    ) -> Self {
+        self.upgrading = packages;
+        self.strategy = strategy;
+        self
+    }
+
+    pub(in crate::resolver) fn with_locked(
+        mut self,
+        locked: Option<&'r HashMap<PackageName, semver::Version>>,
+    ) -> Self {
+        self.locked = locked;
+        self
+    }
+
+    pub(in crate::resolver) fn with_prereleases(
+        mut self,
+        enabled: bool,
+        packages: Option<&'r HashSet<PackageName>>,
+    ) -> Self {
+        self.prereleases = enabled;
+        self.prerelease_packages = packages;
+        self
+    }
+
+    pub(in crate::resolver) fn with_chooser(mut self, chooser: Option<&'c Chooser<'c>>) -> Self {
+        self.chooser = chooser;
+        self
+    }
+
+    pub(in crate::resolver) fn report(&self) {
+        (self.progress)(&ResolveProgress {
+            examined: self.examined.get(),
+            decisions: self.decisions.get(),
+        });
+        self.reported.set(Instant::now());
+    }
+
+    fn list_versions(&self, package: &Name) -> Result<Vec<Candidate>, RepositoryError> {
         let mut candidates = if package.is_root() {
             vec![Candidate::root(self.requested.clone())]
         } else {
-            self.repository.candidates(package)
+            let environment = self
+                .environment
+                .unwrap_or_else(|| self.repository.environment());
+            let candidates = match self.pinned.get(package.as_ref()) {
+                Some(repository) => {
+                    self.repository
+                        .pinned_candidates_in(package, repository, environment)?
+                }
+                None => self.repository.candidates_in(package, environment)?,
+            };
+
+            candidates
+                .into_iter()
+                .filter(|c| {
+                    !self.is_excluded(package, c)
+                        && !self.is_overridden(package, c)
+                        && !self.is_yanked(package, c)
+                })
+                .collect()
         };
 
         candidates.sort_by(|l, r| l.cmp(r).reverse());
 
+        // A preferred version is tried before any other, so that it's kept for as
+        // long as it still works, rather than always picking the newest version,
+        // unless it's being upgraded.
+        let preferred = self
+            .preferred
+            .filter(|_| !self.is_upgrading(package))
+            .and_then(|p| p.get(package.as_ref()));
+        if let Some(version) = preferred {
+            if let Some(idx) = candidates
+                .iter()
+                .position(|c| &semver::Version::from(c) == version)
+            {
+                let candidate = candidates.remove(idx);
+                candidates.insert(0, candidate);
+            }
+        }
+
         if log_enabled!(log::Level::Trace) && !package.is_root() {
             let versions_str: Vec<String> = candidates.iter().map(|v| v.to_string()).collect();
             trace!(
@@ -64,22 +234,163 @@ impl<'r, 'c> RepositoryProvider<'r, 'c> {
             );
         }
 
-        candidates.into_iter()
+        Ok(candidates)
+    }
+
+    // The packages that we've had to decide on the most often are the ones that
+    // the resolver keeps backtracking over, so they're the best hint as to why
+    // the search got out of hand.
+    fn exhausted(&self, limit: u64) -> SolverError {
+        let mut contended: Vec<(Name, usize)> = self
+            .contention
+            .borrow()
+            .iter()
+            .map(|(name, count)| (name.clone(), *count))
+            .collect();
+        contended.sort_by(|l, r| r.1.cmp(&l.1));
+
+        SolverError::SearchExhausted {
+            limit,
+            contended: contended
+                .into_iter()
+                .take(CONTENDED_PACKAGES)
+                .map(|(name, _)| name.into())
+                .collect(),
+        }
+    }
+
+    fn is_upgrading(&self, package: &Name) -> bool {
+        self.upgrading.is_some_and(|p| p.contains(package.as_ref()))
+            || self.reached.borrow().contains(package)
+    }
+
+    fn is_excluded(&self, package: &Name, candidate: &Candidate) -> bool {
+        match self.excluded.get(package.as_ref()) {
+            Some(versions) => versions.contains(&candidate.version().into()),
+            None => false,
+        }
+    }
+
+    fn allows_prereleases(&self, package: &Name) -> bool {
+        self.prereleases
+            || self
+                .prerelease_packages
+                .is_some_and(|p| p.contains(package.as_ref()))
+    }
+
+    // Yanked releases are skipped, unless that exact version is already locked in,
+    // so that yanking a release never breaks anyone that's already using it.
+    fn is_yanked(&self, package: &Name, candidate: &Candidate) -> bool {
+        if candidate.source().yanked().is_none() {
+            return false;
+        }
+
+        let version = semver::Version::from(candidate);
+        self.locked.and_then(|l| l.get(package.as_ref())) != Some(&version)
+    }
+
+    // When more than one repository provides the version that we'd pick, which
+    // of them to use is something our chooser can decide, if we have one. It's up
+    // to the chooser to remember what it decided, so that neither backtracking nor
+    // resolving again asks again.
+    fn choose(
+        &self,
+        package: &Name,
+        chosen: Candidate,
+        versions: &[Candidate],
+        range: &VersionSet<Candidate>,
+    ) -> Candidate {
+        let chooser = match self.chooser {
+            Some(chooser) if !package.is_root() => chooser,
+            _ => return chosen,
+        };
+
+        let version = semver::Version::from(&chosen);
+        let options: Vec<&Candidate> = versions
+            .iter()
+            .filter(|c| semver::Version::from(*c) == version && range.contains(*c))
+            .collect();
+        if options.len() < 2 {
+            return chosen;
+        }
+
+        let choices: Vec<Choice> = options
+            .iter()
+            .map(|c| Choice {
+                version: version.clone(),
+                source: c.source().to_string(),
+            })
+            .collect();
+
+        let idx = chooser(package.as_ref(), &choices);
+        match options.get(idx) {
+            Some(c) => (*c).clone(),
+            None => chosen,
+        }
+    }
+
+    // An override is a hard constraint on a package, no matter what depends on it,
+    // so any candidate outside of it is never even considered.
+    fn is_overridden(&self, package: &Name, candidate: &Candidate) -> bool {
+        match self.overrides.get(package.as_ref()) {
+            Some(constraint) => {
+                !candidate.satisfies_with(constraint, self.allows_prereleases(package))
+            }
+            None => false,
+        }
     }
 }
 
 impl<'r, 'c> DependencyProvider<Name, VersionSet<Candidate>> for RepositoryProvider<'r, 'c> {
     fn should_cancel(&self) -> Result<(), Box<dyn std::error::Error>> {
         (self.callback)();
-        Ok(())
+        if self.reported.get().elapsed() >= REPORT_INTERVAL {
+            self.report();
+        }
+
+        if matches!(self.deadline, Some(deadline) if Instant::now() >= deadline) {
+            return Err(Box::new(SolverError::DeadlineExceeded));
+        }
+
+        match self.limit {
+            Some(limit) if self.decisions.get() as u64 > limit => {
+                Err(Box::new(self.exhausted(limit)))
+            }
+            _ => Ok(()),
+        }
     }
 
     fn choose_package_version<P: Borrow<Name>, U: Borrow<VersionSet<Candidate>>>(
         &self,
         potential_packages: impl Iterator<Item = (P, U)>,
     ) -> Result<(P, Option<Candidate>), Box<dyn std::error::Error>> {
-        let (package, version) =
-            choose_package_with_fewest_versions(|p| self.list_versions(p), potential_packages);
+        // Listing versions can fail, which choose_package_with_fewest_versions
+        // has no way to handle, so we list them all up front instead.
+        let potential_packages: Vec<(P, U)> = potential_packages.collect();
+        let mut versions = HashMap::<Name, Vec<Candidate>>::new();
+        let mut ranges = HashMap::<Name, VersionSet<Candidate>>::new();
+        for (package, range) in potential_packages.iter() {
+            let package = package.borrow();
+            versions.insert(package.clone(), self.list_versions(package)?);
+            ranges.insert(package.clone(), range.borrow().clone());
+        }
+
+        let (package, version) = choose_package_with_fewest_versions(
+            |p| versions[p].clone().into_iter(),
+            potential_packages.into_iter(),
+        );
+        let version = version.map(|chosen| {
+            let name = package.borrow();
+            self.choose(name, chosen, &versions[name], &ranges[name])
+        });
+        self.decisions.set(self.decisions.get() + 1);
+        if !package.borrow().is_root() {
+            *self
+                .contention
+                .borrow_mut()
+                .entry(package.borrow().clone())
+                .or_insert(0) += 1;
+        }
 
         if log_enabled!(log::Level::Trace) {
             let version = version
@@ -102,6 +413,16 @@ impl<'r, 'c> DependencyProvider<Name, VersionSet<Candidate>> for RepositoryProvi
         package: &Name,
         candidate: &Candidate,
     ) -> Result<PDependencies<Name, VersionSet<Candidate>>, Box<dyn std::error::Error>> {
+        if !package.is_root() {
+            self.examined.set(self.examined.get() + 1);
+
+            // Packages without their dependencies are resolved by themselves.
+            if self.no_deps.is_some_and(|p| p.contains(package.as_ref())) {
+                trace!(target: LOGNAME, "ignoring dependencies for {package}");
+                return Ok(PDependencies::Known(DependencyConstraints::default()));
+            }
+        }
+
         match candidate.dependencies().get() {
             None => {
                 trace!(
@@ -112,6 +433,15 @@ impl<'r, 'c> DependencyProvider<Name, VersionSet<Candidate>> for RepositoryProvi
                 Ok(PDependencies::Unknown)
             }
             Some(deps) => {
+                // An eager upgrade reaches everything that what's being upgraded
+                // depends on, and in turn everything that those depend on.
+                if self.strategy == UpgradeStrategy::Eager
+                    && !package.is_root()
+                    && self.is_upgrading(package)
+                {
+                    self.reached.borrow_mut().extend(deps.keys().cloned());
+                }
+
                 if log_enabled!(log::Level::Trace) {
                     let req_str: Vec<String> =
                         deps.iter().map(|(k, v)| format!("{}({})", k, v)).collect();
@@ -126,7 +456,19 @@ impl<'r, 'c> DependencyProvider<Name, VersionSet<Candidate>> for RepositoryProvi
 
                 let mut result = DependencyConstraints::<Name, VersionSet<Candidate>>::default();
                 for (dep, req) in deps.iter() {
-                    result.insert(dep.clone(), req.into());
+                    // Overrides replace whatever other packages have declared, but
+                    // what was actually requested still needs to be honored.
+                    let req = match self.overrides.get(dep.as_ref()) {
+                        Some(constraint) if !package.is_root() => {
+                            Requirement::from(constraint.clone())
+                        }
+                        _ => req.clone(),
+                    };
+                    let mut vs: VersionSet<Candidate> = (&req).into();
+                    if !req.is_stable() && self.allows_prereleases(dep) {
+                        vs = vs.allowing_prereleases();
+                    }
+                    result.insert(dep.clone(), vs);
                 }
                 Ok(PDependencies::Known(result))
             }
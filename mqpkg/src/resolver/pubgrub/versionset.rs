@@ -147,6 +147,20 @@ impl<C: Candidate> VersionSet<C> {
         }
     }
 
+    pub(in crate::resolver) fn union(&self, other: &VersionSet<C>) -> VersionSet<C> {
+        // We can't just union our pre ranges, because then a pre-release that
+        // was explicitly mentioned by one side could sneak in through the range
+        // of the other side, so we constrain each side's pre-releases to that
+        // side's range first.
+        VersionSet {
+            range: self.range.union(&other.range),
+            pre: self
+                .range
+                .intersection(&self.pre)
+                .union(&other.range.intersection(&other.pre)),
+        }
+    }
+
     pub(in crate::resolver) fn with_normal(&self, other: &VersionSet<C>) -> VersionSet<C> {
         VersionSet {
             range: self.range.intersection(&other.range),
@@ -154,6 +168,23 @@ impl<C: Candidate> VersionSet<C> {
         }
     }
 
+    // Any pre-release within our range, whether it was explicitly mentioned or not.
+    pub(in crate::resolver) fn allowing_prereleases(&self) -> VersionSet<C> {
+        VersionSet {
+            range: self.range.clone(),
+            pre: Range::any(),
+        }
+    }
+
+    // Only the final releases within our range, even if a pre-release was
+    // explicitly mentioned.
+    pub(in crate::resolver) fn without_prereleases(&self) -> VersionSet<C> {
+        VersionSet {
+            range: self.range.clone(),
+            pre: Range::none(),
+        }
+    }
+
     pub(in crate::resolver) fn with_pre(&self, other: &VersionSet<C>) -> VersionSet<C> {
         VersionSet {
             range: self.range.clone(),
@@ -7,6 +7,7 @@ pub(crate) use crate::resolver::types::name::Name;
 pub(crate) use crate::resolver::types::requirement::Requirement;
 
 pub(super) use crate::resolver::types::dependencies::{Dependencies, WithDependencies};
+pub(super) use crate::resolver::types::requirement::convert_req;
 pub(super) use crate::resolver::types::version::Version;
 
 mod dependencies;
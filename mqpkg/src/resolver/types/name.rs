@@ -34,7 +34,7 @@ impl Name {
 
     pub(in crate::resolver) fn root() -> Name {
         Name {
-            name: PackageName::new(ROOT_NAME),
+            name: PackageName::new_unchecked(ROOT_NAME),
             root: true,
         }
     }
@@ -9,14 +9,19 @@ use semver::{Prerelease, VersionReq};
 
 use crate::resolver::pubgrub::{Candidate, VersionSet};
 use crate::resolver::types::version::Version;
+use crate::types::VersionConstraint;
 
 #[derive(Debug, Clone)]
-pub(crate) struct Requirement(VersionReq);
+pub(crate) struct Requirement(VersionConstraint);
 
 impl Requirement {
-    pub(crate) fn new(req: VersionReq) -> Requirement {
+    pub(crate) fn new(req: VersionConstraint) -> Requirement {
         Requirement(req)
     }
+
+    pub(crate) fn is_stable(&self) -> bool {
+        self.0.is_stable()
+    }
 }
 
 impl fmt::Display for Requirement {
@@ -25,48 +30,74 @@ impl fmt::Display for Requirement {
     }
 }
 
-impl From<VersionReq> for Requirement {
-    fn from(req: VersionReq) -> Requirement {
+impl From<VersionConstraint> for Requirement {
+    fn from(req: VersionConstraint) -> Requirement {
         Requirement::new(req)
     }
 }
 
 impl From<&Requirement> for VersionSet<Candidate> {
     fn from(req: &Requirement) -> VersionSet<Candidate> {
-        // By default, we allow *any* normal version to be accepted,
-        // then we futher constrain those down.
-        // let mut range = Range::full();
-        // By default, we allow *no* pre-release versions to be accepted,
-        // then we start allowing additional pre-releases via Unions.
-        // let mut pre = Range::none();
-        let mut vs = VersionSet::default();
+        // A constraint matches if any of its alternatives match, so our set is
+        // just the union of the sets for each individual VersionReq.
+        let vs = req
+            .0
+            .alternatives()
+            .iter()
+            .fold(VersionSet::empty(), |vs, alt| vs.union(&convert_req(alt)));
 
-        // This whole thing is subtle, but our "range" here will
-        // only be used when we're trying to see if a non pre-release
-        // (aka a "final") version is contained within this set. So
-        // for that, we just compute the normal intersection of all
-        // requirements.
-        //
-        // However, for "pre", which is used when we're trying to see
-        // if a pre-release is contained within this set, we still need
-        // to apply all of the same logic of an intersection of all
-        // of the requirements. On top of that, we don't want to use
-        // a pre-release version unless a requirement has *explicitly*
-        // mentioned it, though we will accept later pre-releases for
-        // the same version.
-        //
-        // Thus, pre-releases effectively have an additional constraint,
-        // which is a union of all pre-release versions mentioned
-        // constrained so: >=I.J.K-P, <I.J.(K+1). This ensures that a
-        // pre-release version had to have been explicitly mentioned
-        // (or is a direct upgrade to it).
-        for comp in req.0.comparators.iter() {
-            vs = vs.with_normal(&convert_normal(comp));
-            vs = vs.with_pre(&convert_prerelease(comp));
+        // Then we carve out every version that has been explicitly excluded. This
+        // has to be the complement of a singleton, rather than of an exact set,
+        // since an exact pre-release would also carve out the final release that it
+        // leads up to, when it's only the pre-release that was excluded.
+        let vs = req.0.exclusions().iter().fold(vs, |vs, v| {
+            let excluded = Version::candidate(v.major, v.minor, v.patch).pre(v.pre.as_str());
+            vs.intersection(&VersionSet::singleton(Candidate::bound(excluded)).complement())
+        });
+
+        // A stable constraint never allows a pre-release, not even one that one of
+        // its alternatives mentions.
+        match req.0.is_stable() {
+            true => vs.without_prereleases(),
+            false => vs,
         }
+    }
+}
+
+pub(in crate::resolver) fn convert_req(req: &VersionReq) -> VersionSet<Candidate> {
+    // By default, we allow *any* normal version to be accepted,
+    // then we futher constrain those down.
+    // let mut range = Range::full();
+    // By default, we allow *no* pre-release versions to be accepted,
+    // then we start allowing additional pre-releases via Unions.
+    // let mut pre = Range::none();
+    let mut vs = VersionSet::default();
 
-        vs
+    // This whole thing is subtle, but our "range" here will
+    // only be used when we're trying to see if a non pre-release
+    // (aka a "final") version is contained within this set. So
+    // for that, we just compute the normal intersection of all
+    // requirements.
+    //
+    // However, for "pre", which is used when we're trying to see
+    // if a pre-release is contained within this set, we still need
+    // to apply all of the same logic of an intersection of all
+    // of the requirements. On top of that, we don't want to use
+    // a pre-release version unless a requirement has *explicitly*
+    // mentioned it, though we will accept later pre-releases for
+    // the same version.
+    //
+    // Thus, pre-releases effectively have an additional constraint,
+    // which is a union of all pre-release versions mentioned
+    // constrained so: >=I.J.K-P, <I.J.(K+1). This ensures that a
+    // pre-release version had to have been explicitly mentioned
+    // (or is a direct upgrade to it).
+    for comp in req.comparators.iter() {
+        vs = vs.with_normal(&convert_normal(comp));
+        vs = vs.with_pre(&convert_prerelease(comp));
     }
+
+    vs
 }
 
 fn bump_pre<S: AsRef<str>>(pre: S) -> String {
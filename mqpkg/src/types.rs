@@ -3,30 +3,203 @@
 // for complete details.
 
 use std::clone::Clone;
-use std::cmp::{Eq, PartialEq};
-use std::collections::BTreeMap;
+use std::cmp::{Eq, Ordering, PartialEq};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
 
 use dyn_clone::DynClone;
-use semver::{Version, VersionReq};
+use semver::{Comparator, Op, Version, VersionReq};
+use serde::de::value::StringDeserializer;
+use serde::de::{Error as _, IntoDeserializer};
 use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha512};
 
-use crate::errors::{PackageNameError, PackageSpecifierError};
+use crate::errors::{DigestError, PackageNameError, PackageSpecifierError, VersionConstraintError};
+use crate::marker::Marker;
+use crate::resolver::Candidate;
 
-#[derive(Serialize, Deserialize, Clone, Eq, Debug, Hash, PartialEq, Ord, PartialOrd)]
-pub struct PackageName(String);
+// Names are case insensitive, so we compare, hash, and order them using only
+// their canonical lowercase form, but we keep the name as it was written too, so
+// that we can display it the way the user (or repository) spelled it.
+//
+// Names get cloned constantly while resolving, so their storage is interned and
+// shared, making a clone just a reference count increment.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "String")]
+pub struct PackageName {
+    canonical: Arc<str>,
+    display: Arc<str>,
+}
 
 impl PackageName {
-    pub(crate) fn new<S: Into<String>>(s: S) -> PackageName {
-        PackageName(s.into())
+    // Names can be scoped, like @myorg/thing, so that teams sharing a repository
+    // don't collide with each other. The scope and the rest of the name are each
+    // validated on their own, but the full scoped name is the package's identity.
+    pub fn new<S: AsRef<str>>(name: S) -> Result<PackageName, PackageNameError> {
+        let value = name.as_ref();
+
+        match value.strip_prefix('@') {
+            Some(scoped) => match scoped.split_once('/') {
+                Some((scope, rest)) => {
+                    validate_name(value, scope)?;
+                    validate_name(value, rest)?;
+                }
+                None => {
+                    return Err(PackageNameError::InvalidScope {
+                        name: value.to_string(),
+                    })
+                }
+            },
+            None => validate_name(value, value)?,
+        }
+
+        Ok(PackageName {
+            canonical: intern(&value.to_ascii_lowercase()),
+            display: intern(value),
+        })
+    }
+
+    // The scope of a scoped name, without its leading @.
+    pub fn scope(&self) -> Option<&str> {
+        self.display
+            .strip_prefix('@')
+            .and_then(|scoped| scoped.split_once('/'))
+            .map(|(scope, _)| scope)
+    }
+
+    // The name without its scope, which for an unscoped name is the whole name.
+    pub fn unscoped(&self) -> &str {
+        match self.scope() {
+            Some(scope) => &self.display[scope.len() + 2..],
+            None => &self.display[..],
+        }
+    }
+
+    // This skips all validation, and should only be used for names that need
+    // to *not* be valid package names (like our root package in the resolver).
+    pub(crate) fn new_unchecked<S: Into<String>>(s: S) -> PackageName {
+        let display = intern(&s.into());
+        PackageName {
+            canonical: display.clone(),
+            display,
+        }
+    }
+}
+
+// Validates a single part of name, which is either an unscoped name, or one
+// half of a scoped name.
+fn validate_name(name: &str, part: &str) -> Result<(), PackageNameError> {
+    // Check that the first letter is only alpha, and if we don't have
+    // a first letter, then this is invalid anyways.
+    if !part.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return match part.chars().next() {
+            Some(c) => Err(PackageNameError::NoStartingAlpha {
+                name: name.to_string(),
+                character: c.to_string(),
+            }),
+            None => Err(PackageNameError::TooShort),
+        };
+    }
+
+    // Iterate over the rest of our letters, and make sure that they're alphanumeric
+    for c in part.chars() {
+        if !c.is_ascii_alphanumeric() {
+            return Err(PackageNameError::InvalidCharacter {
+                name: name.to_string(),
+                character: c.to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Smaller than this, an interner is never worth sweeping.
+const MIN_INTERNER_SWEEP: usize = 1024;
+
+fn intern(name: &str) -> Arc<str> {
+    static NAMES: OnceLock<Mutex<Interner>> = OnceLock::new();
+
+    NAMES
+        .get_or_init(|| Mutex::new(Interner::new()))
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .intern(name)
+}
+
+// Shares the storage of every name that's still alive. A name that only the
+// interner holds anymore is dead, and dead names are swept out whenever the
+// interner has doubled in size since it was last swept, so that however long
+// a process runs, it never holds much more than the names that are still in use.
+struct Interner {
+    names: HashSet<Arc<str>>,
+    sweep_at: usize,
+}
+
+impl Interner {
+    fn new() -> Interner {
+        Interner {
+            names: HashSet::new(),
+            sweep_at: MIN_INTERNER_SWEEP,
+        }
+    }
+
+    fn intern(&mut self, name: &str) -> Arc<str> {
+        if let Some(interned) = self.names.get(name) {
+            return interned.clone();
+        }
+
+        if self.names.len() >= self.sweep_at {
+            self.names.retain(|name| Arc::strong_count(name) > 1);
+            self.sweep_at = (self.names.len() * 2).max(MIN_INTERNER_SWEEP);
+        }
+
+        let interned: Arc<str> = Arc::from(name);
+        self.names.insert(interned.clone());
+        interned
+    }
+}
+
+impl PartialEq for PackageName {
+    fn eq(&self, other: &PackageName) -> bool {
+        self.canonical == other.canonical
+    }
+}
+
+impl Eq for PackageName {}
+
+impl Hash for PackageName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.canonical.hash(state);
+    }
+}
+
+impl Ord for PackageName {
+    fn cmp(&self, other: &PackageName) -> Ordering {
+        self.canonical.cmp(&other.canonical)
+    }
+}
+
+impl PartialOrd for PackageName {
+    fn partial_cmp(&self, other: &PackageName) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 impl fmt::Display for PackageName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.display)
+    }
+}
+
+impl Serialize for PackageName {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.display)
     }
 }
 
@@ -34,52 +207,927 @@ impl FromStr for PackageName {
     type Err = PackageNameError;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
-        // Check that the first letter is only alpha, and if we don't have
-        // a first letter, then this is invalid anyways.
-        if !value.starts_with(|c: char| c.is_ascii_alphabetic()) {
-            return match value.chars().next() {
-                Some(c) => Err(PackageNameError::NoStartingAlpha {
-                    name: value.to_string(),
-                    character: c.to_string(),
-                }),
-                None => Err(PackageNameError::TooShort),
-            };
+        PackageName::new(value)
+    }
+}
+
+impl TryFrom<&str> for PackageName {
+    type Error = PackageNameError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        PackageName::new(value)
+    }
+}
+
+impl TryFrom<String> for PackageName {
+    type Error = PackageNameError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        PackageName::new(value)
+    }
+}
+
+// The semver crate only understands comma separated comparators, however
+// people are used to writing requirements in the npm style, so we accept a
+// handful of alternate syntaxes and translate them into one or more VersionReq,
+// where a version is allowed if it matches any one of them.
+//
+// VersionReq also has no way to say !=, so any exclusions are pulled out and
+// kept separately, and they apply to the constraint as a whole, no matter which
+// alternative they were written in. The same goes for stable, which rules out
+// every pre-release, even when pre-releases have otherwise been allowed.
+#[derive(Clone, Eq, Debug, Hash, PartialEq)]
+pub struct VersionConstraint {
+    alternatives: Vec<VersionReq>,
+    exclusions: Vec<Version>,
+    stable: bool,
+}
+
+impl VersionConstraint {
+    pub fn any() -> VersionConstraint {
+        VersionReq::STAR.into()
+    }
+
+    // Exactly version and nothing else, including any pre-release it has.
+    pub fn exact(version: &Version) -> VersionConstraint {
+        VersionReq {
+            comparators: vec![Comparator {
+                op: Op::Exact,
+                major: version.major,
+                minor: Some(version.minor),
+                patch: Some(version.patch),
+                pre: version.pre.clone(),
+            }],
+        }
+        .into()
+    }
+
+    pub fn excluding(version: &Version) -> VersionConstraint {
+        VersionConstraint {
+            alternatives: vec![VersionReq::STAR],
+            exclusions: vec![version.clone()],
+            stable: false,
+        }
+    }
+
+    // Any final release, and never a pre-release.
+    pub fn stable() -> VersionConstraint {
+        VersionConstraint {
+            alternatives: vec![VersionReq::STAR],
+            exclusions: Vec::new(),
+            stable: true,
+        }
+    }
+
+    pub fn alternatives(&self) -> &[VersionReq] {
+        &self.alternatives
+    }
+
+    pub fn exclusions(&self) -> &[Version] {
+        &self.exclusions
+    }
+
+    pub fn is_stable(&self) -> bool {
+        self.stable
+    }
+
+    pub fn matches(&self, version: &Version) -> bool {
+        !(self.stable && !version.pre.is_empty())
+            && !self.exclusions.contains(version)
+            && self.alternatives.iter().any(|r| r.matches(version))
+    }
+
+    // A version matches the intersection if it matches both constraints, which
+    // means it has to match some alternative from each, so every pairing of
+    // alternatives becomes an alternative of its own.
+    pub fn intersection(&self, other: &VersionConstraint) -> VersionConstraint {
+        let alternatives = self
+            .alternatives
+            .iter()
+            .flat_map(|l| {
+                other.alternatives.iter().map(move |r| VersionReq {
+                    comparators: l
+                        .comparators
+                        .iter()
+                        .chain(r.comparators.iter())
+                        .cloned()
+                        .collect(),
+                })
+            })
+            .collect();
+
+        let mut exclusions = self.exclusions.clone();
+        for exclusion in other.exclusions.iter() {
+            if !exclusions.contains(exclusion) {
+                exclusions.push(exclusion.clone());
+            }
+        }
+
+        VersionConstraint {
+            alternatives,
+            exclusions,
+            stable: self.stable || other.stable,
+        }
+    }
+}
+
+impl fmt::Display for VersionConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == VersionConstraint::stable() {
+            return write!(f, "stable");
         }
 
-        // Iterate over the rest of our letters, and make sure that they're alphanumeric
-        for c in value.chars() {
-            if !c.is_ascii_alphanumeric() {
-                return Err(PackageNameError::InvalidCharacter {
-                    name: value.to_string(),
-                    character: c.to_string(),
+        let mut alternatives: Vec<String> =
+            self.alternatives.iter().map(|r| r.to_string()).collect();
+
+        // Exclusions apply to the whole constraint, so it doesn't matter which
+        // alternative we attach them to, we just need them to round trip.
+        if let Some(first) = alternatives.first_mut() {
+            for exclusion in self.exclusions.iter() {
+                first.push_str(&format!(", !={exclusion}"));
+            }
+            if self.stable {
+                first.push_str(", stable");
+            }
+        }
+
+        write!(f, "{}", alternatives.join(" || "))
+    }
+}
+
+impl From<VersionReq> for VersionConstraint {
+    fn from(req: VersionReq) -> VersionConstraint {
+        VersionConstraint {
+            alternatives: vec![req],
+            exclusions: Vec::new(),
+            stable: false,
+        }
+    }
+}
+
+impl FromStr for VersionConstraint {
+    type Err = VersionConstraintError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut alternatives = Vec::new();
+        let mut exclusions = Vec::new();
+        let mut stable = false;
+        for alternative in value.split("||") {
+            let alternative = alternative.trim();
+            if alternative.is_empty() {
+                return Err(VersionConstraintError::EmptyAlternative {
+                    constraint: value.to_string(),
                 });
             }
+
+            let mut comparators = Vec::new();
+            for comparator in translate(alternative)?.split(',').map(|c| c.trim()) {
+                if comparator == "stable" {
+                    stable = true;
+                    continue;
+                }
+                match comparator.strip_prefix("!=") {
+                    Some(version) => exclusions.push(version.trim().parse().map_err(|source| {
+                        VersionConstraintError::InvalidExclusion {
+                            constraint: value.to_string(),
+                            source,
+                        }
+                    })?),
+                    None => comparators.push(comparator),
+                }
+            }
+
+            alternatives.push(match comparators.is_empty() {
+                true => VersionReq::STAR,
+                false => comparators.join(", ").parse()?,
+            });
+        }
+
+        Ok(VersionConstraint {
+            alternatives,
+            exclusions,
+            stable,
+        })
+    }
+}
+
+impl Serialize for VersionConstraint {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionConstraint {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// Translate a single alternative (no ||) into something that VersionReq can
+// parse, anything that VersionReq already understands will pass through as is.
+fn translate(value: &str) -> Result<String, VersionConstraintError> {
+    // People coming from other ecosystems expect == to mean an exact match,
+    // which is spelled = in semver.
+    let value = value.replace("==", "=");
+    let value = value.as_str();
+
+    // Comma separated requirements are what VersionReq natively supports, so
+    // we assume they're already in the semver form.
+    if value.contains(',') {
+        return Ok(value.to_string());
+    }
+
+    let tokens: Vec<&str> = value.split_whitespace().collect();
+
+    // Hyphen ranges, "I.J.K - X.Y.Z" which are equivalent to >=I.J.K, <=X.Y.Z.
+    if tokens.contains(&"-") {
+        return match tokens.as_slice() {
+            [low, "-", high] => Ok(format!(">={low}, <={high}")),
+            _ => Err(VersionConstraintError::InvalidHyphenRange {
+                constraint: value.to_string(),
+            }),
+        };
+    }
+
+    // Otherwise we treat whitespace as separating comparators, taking care to
+    // keep a bare operator (like ">= 1.0") attached to the version after it.
+    let mut comparators = Vec::<String>::new();
+    let mut pending: Option<&str> = None;
+    for token in tokens {
+        match pending.take() {
+            Some(op) => comparators.push(format!("{op}{token}")),
+            None if is_operator(token) => pending = Some(token),
+            None => comparators.push(token.to_string()),
         }
+    }
 
-        Ok(PackageName(value.to_ascii_lowercase()))
+    if let Some(op) = pending {
+        return Err(VersionConstraintError::DanglingOperator {
+            constraint: value.to_string(),
+            operator: op.to_string(),
+        });
     }
+
+    Ok(comparators.join(", "))
+}
+
+fn is_operator(token: &str) -> bool {
+    matches!(token, "=" | "!=" | ">" | ">=" | "<" | "<=" | "~" | "^")
 }
 
 #[derive(Serialize, Deserialize, Clone, Eq, Debug, Hash, PartialEq)]
 pub struct PackageSpecifier {
     pub(crate) name: PackageName,
-    pub(crate) version: VersionReq,
+    pub(crate) version: VersionConstraint,
+    pub(crate) repository: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) metadata: BTreeMap<String, String>,
+}
+
+impl PackageSpecifier {
+    pub fn new<V: Into<VersionConstraint>>(
+        name: PackageName,
+        version: V,
+        repository: Option<String>,
+    ) -> PackageSpecifier {
+        PackageSpecifier {
+            name,
+            version: version.into(),
+            repository,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    // Metadata is opaque to us, it's just kept alongside the request in our state,
+    // for whatever purposes whoever installed the package has for it.
+    pub fn with_metadata(mut self, metadata: BTreeMap<String, String>) -> PackageSpecifier {
+        self.metadata = metadata;
+        self
+    }
 }
 
 impl FromStr for PackageSpecifier {
     type Err = PackageSpecifierError;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
-        let (name_s, version_s) = match value.find(|c: char| !c.is_ascii_alphanumeric()) {
-            Some(idx) => value.split_at(idx),
+        // A specifier can pin itself to a specific repository, with something
+        // like "foo@1.0 from main".
+        let (value, repository) = match value.split_once(" from ") {
+            Some((spec, repo)) if repo.trim().is_empty() => {
+                return Err(PackageSpecifierError::NoRepositoryName {
+                    specifier: spec.trim().to_string(),
+                })
+            }
+            Some((spec, repo)) => (spec.trim(), Some(repo.trim().to_string())),
+            None => (value, None),
+        };
+
+        // A scoped name has an @ and a / of its own, so the name only ends after
+        // the first character that's neither part of the scope nor alphanumeric.
+        let start = match value.strip_prefix('@').and_then(|rest| rest.find('/')) {
+            Some(idx) => idx + 2,
+            None => 0,
+        };
+        let (name_s, version_s) = match value[start..].find(|c: char| !c.is_ascii_alphanumeric()) {
+            Some(idx) => value.split_at(start + idx),
             None => (value, "*"),
         };
 
         let name: PackageName = name_s.parse()?;
-        let version: VersionReq = version_s.parse()?;
+        let version: VersionConstraint = match version_s.strip_prefix('@') {
+            Some(pin) => match partial_pin(pin) {
+                Some(req) => req.parse()?,
+                None => pin
+                    .parse()
+                    .map_err(|source| PackageSpecifierError::InvalidPin {
+                        pin: pin.to_string(),
+                        source,
+                    })?,
+            },
+            None => version_s.parse()?,
+        };
+
+        Ok(PackageSpecifier::new(name, version, repository))
+    }
+}
 
-        Ok(PackageSpecifier { name, version })
+// A pin like foo@1 or foo@1.2 means the latest version within that major or
+// minor version, rather than semver's default of a caret requirement, while a
+// pin with all three components is an exact match.
+fn partial_pin(value: &str) -> Option<String> {
+    // Latest is the newest release that we're allowed to pick, which is only a
+    // pre-release if they've been allowed, while stable is the newest final
+    // release no matter what. Neither is a valid version, so they can't be
+    // confused for one.
+    match value {
+        "latest" => return Some("*".to_string()),
+        "stable" => return Some("stable".to_string()),
+        _ => {}
     }
+
+    let parts = value
+        .split('.')
+        .map(|p| match p.chars().all(|c| c.is_ascii_digit()) {
+            true => p.parse::<u64>().ok(),
+            false => None,
+        })
+        .collect::<Option<Vec<u64>>>()?;
+
+    match parts.as_slice() {
+        [major] => Some(format!(">={major}.0.0, <{}.0.0", major.checked_add(1)?)),
+        [major, minor] => Some(format!(
+            ">={major}.{minor}.0, <{major}.{}.0",
+            minor.checked_add(1)?
+        )),
+        [major, minor, patch] => Some(format!("={major}.{minor}.{patch}")),
+        _ => None,
+    }
+}
+
+// Dependencies can be written either as a map of names to constraints, or as a
+// list where each entry is either a bare name (allowing any version) or an
+// object with a name and an optional version, which may carry extra keys that
+// we don't (yet) understand.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Dependencies<C> {
+    Map(HashMap<PackageName, C>),
+    List(Vec<Dependency>),
+}
+
+// Each part of a detailed dependency is only parsed once we know that's what it
+// is, since an untagged enum would replace why it failed with a vague error.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Dependency {
+    Name(String),
+    Detailed {
+        name: String,
+        #[serde(default)]
+        version: Option<String>,
+        #[serde(default)]
+        marker: Option<String>,
+    },
+}
+
+// Whatever dependencies are deserialized into has to know what a dependency that
+// doesn't specify a version means, and whether it can be conditional on a marker.
+pub(crate) trait AnyConstraint: Sized {
+    fn any() -> Self;
+
+    fn marked(self, _marker: Marker) -> Result<Self, &'static str> {
+        Err("markers are not supported here")
+    }
+}
+
+impl AnyConstraint for VersionConstraint {
+    fn any() -> Self {
+        VersionConstraint::any()
+    }
+}
+
+// Where dependencies can't have markers, a package can only be depended on once,
+// since there'd be no way to choose between several constraints on it.
+pub(crate) fn deserialize_dependencies<'de, D, C>(
+    deserializer: D,
+) -> Result<HashMap<PackageName, C>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    C: Deserialize<'de> + AnyConstraint,
+{
+    deserialize_marked_dependencies(deserializer)?
+        .into_iter()
+        .map(
+            |(name, mut constraints)| match (constraints.pop(), constraints.is_empty()) {
+                (Some(constraint), true) => Ok((name, constraint)),
+                _ => Err(serde::de::Error::custom(format!(
+                    "{name} is depended on more than once"
+                ))),
+            },
+        )
+        .collect()
+}
+
+// Where dependencies can have markers, a package can be depended on once for each
+// environment, like foo; os == "linux" and foo; os == "windows", so we keep every
+// constraint on each package, in the order they were listed.
+pub(crate) fn deserialize_marked_dependencies<'de, D, C>(
+    deserializer: D,
+) -> Result<HashMap<PackageName, Vec<C>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    C: Deserialize<'de> + AnyConstraint,
+{
+    let deps = match Dependencies::deserialize(deserializer)? {
+        Dependencies::Map(deps) => {
+            return Ok(deps.into_iter().map(|(name, c)| (name, vec![c])).collect())
+        }
+        Dependencies::List(deps) => deps,
+    };
+
+    let mut result: HashMap<PackageName, Vec<C>> = HashMap::new();
+    for dep in deps.into_iter() {
+        let (name, version, marker) = match dep {
+            Dependency::Name(name) => (name, None, None),
+            Dependency::Detailed {
+                name,
+                version,
+                marker,
+            } => (name, version, marker),
+        };
+
+        let name: PackageName = name.parse().map_err(D::Error::custom)?;
+        let constraint = match version {
+            Some(version) => {
+                let version: StringDeserializer<D::Error> = version.into_deserializer();
+                C::deserialize(version)?
+            }
+            None => C::any(),
+        };
+        let constraint = match marker {
+            Some(marker) => {
+                let marker: Marker = marker.parse().map_err(D::Error::custom)?;
+                constraint.marked(marker).map_err(D::Error::custom)?
+            }
+            None => constraint,
+        };
+        result.entry(name).or_default().push(constraint);
+    }
+
+    Ok(result)
+}
+
+// Within a repository, a dependency can be constrained relative to the version
+// of the release that depends on it, using $self, for packages that are released
+// in lockstep. So it's only a real constraint once we expand it for a release.
+// It can also have a marker, in which case it only applies to environments that
+// the marker matches.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct DependencyConstraint {
+    constraint: String,
+    marker: Option<Marker>,
+}
+
+impl DependencyConstraint {
+    pub(crate) fn expand(
+        &self,
+        version: &Version,
+    ) -> Result<VersionConstraint, VersionConstraintError> {
+        self.constraint
+            .replace("$self", &version.to_string())
+            .parse()
+    }
+
+    pub(crate) fn applies(&self, env: &HashMap<String, String>) -> bool {
+        match &self.marker {
+            Some(marker) => marker.evaluate(env),
+            None => true,
+        }
+    }
+}
+
+impl AnyConstraint for DependencyConstraint {
+    fn any() -> Self {
+        DependencyConstraint {
+            constraint: "*".to_string(),
+            marker: None,
+        }
+    }
+
+    fn marked(self, marker: Marker) -> Result<Self, &'static str> {
+        Ok(DependencyConstraint {
+            marker: Some(marker),
+            ..self
+        })
+    }
+}
+
+impl fmt::Display for DependencyConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.marker {
+            Some(marker) => write!(f, "{}; {}", self.constraint, marker),
+            None => write!(f, "{}", self.constraint),
+        }
+    }
+}
+
+impl FromStr for DependencyConstraint {
+    type Err = VersionConstraintError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        for (idx, _) in value.match_indices('$') {
+            let token: String = value[idx + 1..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .collect();
+            if token != "self" {
+                return Err(VersionConstraintError::UnknownToken {
+                    constraint: value.to_string(),
+                    token: format!("${token}"),
+                });
+            }
+        }
+
+        // We make sure that it'll be valid once it's been expanded, so that any
+        // problems are reported when we read the index, not when we resolve.
+        let constraint = DependencyConstraint {
+            constraint: value.to_string(),
+            marker: None,
+        };
+        constraint.expand(&Version::new(0, 0, 0))?;
+
+        Ok(constraint)
+    }
+}
+
+impl<'de> Deserialize<'de> for DependencyConstraint {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct AvailableVersion {
+    pub version: Version,
+    pub repository: String,
+    pub satisfies: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct RepositoryStatus {
+    pub name: String,
+    pub url: String,
+    pub reachable: Option<bool>,
+}
+
+// Everything that constrains which version of a package can be chosen. The
+// request (if any) and the override (if any) must both be satisfied, and no
+// excluded version is ever chosen, so the effective constraint is what's left
+// once all of them are combined. Packages that merely depend on this package
+// don't show up here, because when there is an override, it replaces whatever
+// they've declared.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct EffectiveConstraint {
+    pub constraint: VersionConstraint,
+    pub requested: Option<VersionConstraint>,
+    pub overridden: Option<VersionConstraint>,
+    pub excluded: Vec<Version>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ResolveProgress {
+    pub examined: usize,
+    pub decisions: usize,
+}
+
+// Anything that depends on what time it is asks a Clock, rather than asking the
+// system directly, so that the time can be pinned for reproducible results.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// A clock that's stopped at a single point in time.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct RepositoryFetch {
+    pub name: String,
+    pub url: String,
+    pub bytes: u64,
+    pub versions: usize,
+    pub cached: bool,
+    pub duration: Duration,
+    pub fetched_at: SystemTime,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct FetchSummary {
+    pub repositories: Vec<RepositoryFetch>,
+}
+
+// What pruning our cache removed, and how much space that freed.
+#[derive(Serialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CacheCleanup {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    // Strongest first, which is the order we prefer them in.
+    const ALL: [DigestAlgorithm; 2] = [DigestAlgorithm::Sha512, DigestAlgorithm::Sha256];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    fn hex_len(&self) -> usize {
+        match self {
+            DigestAlgorithm::Sha256 => 64,
+            DigestAlgorithm::Sha512 => 128,
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+#[serde(try_from = "RawDigest")]
+pub struct Digest {
+    pub algorithm: DigestAlgorithm,
+    pub hex: String,
+}
+
+// What we deserialize a Digest from, so that it gets validated the same way as
+// one that we construct ourselves.
+#[derive(Deserialize)]
+struct RawDigest {
+    algorithm: DigestAlgorithm,
+    hex: String,
+}
+
+impl TryFrom<RawDigest> for Digest {
+    type Error = DigestError;
+
+    fn try_from(raw: RawDigest) -> Result<Digest, DigestError> {
+        Digest::new(raw.algorithm, raw.hex)
+    }
+}
+
+impl Digest {
+    pub fn new<S: Into<String>>(algorithm: DigestAlgorithm, hex: S) -> Result<Digest, DigestError> {
+        let hex = hex.into();
+        if hex.len() != algorithm.hex_len() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(DigestError::InvalidDigest { algorithm, hex });
+        }
+
+        Ok(Digest {
+            algorithm,
+            hex: hex.to_ascii_lowercase(),
+        })
+    }
+
+    // Picks the strongest digest that we support from a map of algorithm names
+    // to hex digests, ignoring any algorithms that we don't know about.
+    pub fn from_map(digests: &HashMap<String, String>) -> Result<Option<Digest>, DigestError> {
+        for algorithm in DigestAlgorithm::ALL {
+            if let Some(hex) = digests.get(algorithm.name()) {
+                return Digest::new(algorithm, hex.as_str()).map(Some);
+            }
+        }
+
+        match digests.is_empty() {
+            true => Ok(None),
+            false => Err(DigestError::UnsupportedAlgorithms {
+                algorithms: digests.keys().cloned().collect(),
+            }),
+        }
+    }
+}
+
+// Without an algorithm, we infer it from the length of the digest, which is
+// unambiguous for the algorithms that we support.
+impl FromStr for Digest {
+    type Err = DigestError;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        match DigestAlgorithm::ALL
+            .into_iter()
+            .find(|a| a.hex_len() == hex.len())
+        {
+            Some(algorithm) => Digest::new(algorithm, hex),
+            None => Err(DigestError::UnrecognizedLength {
+                hex: hex.to_string(),
+            }),
+        }
+    }
+}
+
+impl Digest {
+    // Whether the contents of reader hash to this digest.
+    pub fn matches<R: Read>(&self, mut reader: R) -> io::Result<bool> {
+        let hex = match self.algorithm {
+            DigestAlgorithm::Sha256 => hash::<Sha256, R>(&mut reader)?,
+            DigestAlgorithm::Sha512 => hash::<Sha512, R>(&mut reader)?,
+        };
+
+        Ok(hex == self.hex)
+    }
+}
+
+impl DigestAlgorithm {
+    // Wraps inner, hashing everything that's written through it, so that what's
+    // written can be digested without having to read it back.
+    pub(crate) fn writer<W: Write>(self, inner: W) -> DigestWriter<W> {
+        let hasher = match self {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::default()),
+            DigestAlgorithm::Sha512 => Hasher::Sha512(Sha512::default()),
+        };
+
+        DigestWriter { inner, hasher }
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+pub(crate) struct DigestWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> DigestWriter<W> {
+    // Hashes reader without writing it, for whatever inner already has in it,
+    // such as the start of a download that's being resumed.
+    pub(crate) fn hash_existing<R: Read>(&mut self, mut reader: R) -> io::Result<()> {
+        let mut buf = [0; 8192];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(()),
+                n => self.update(&buf[..n]),
+            }
+        }
+    }
+
+    // The digest of everything that we've hashed.
+    pub(crate) fn finish(mut self) -> io::Result<Digest> {
+        self.inner.flush()?;
+        let (algorithm, hex) = match self.hasher {
+            Hasher::Sha256(hasher) => (
+                DigestAlgorithm::Sha256,
+                format!("{:x}", sha2::Digest::finalize(hasher)),
+            ),
+            Hasher::Sha512(hasher) => (
+                DigestAlgorithm::Sha512,
+                format!("{:x}", sha2::Digest::finalize(hasher)),
+            ),
+        };
+
+        Ok(Digest { algorithm, hex })
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            Hasher::Sha256(hasher) => sha2::Digest::update(hasher, data),
+            Hasher::Sha512(hasher) => sha2::Digest::update(hasher, data),
+        }
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn hash<H: sha2::Digest + io::Write, R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut hasher = H::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex)
+    }
+}
+
+// A problem with a release in one of our repositories, which is about a specific
+// version, and a specific URL of it, whenever that's known.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ReleaseIssue {
+    pub repository: String,
+    pub package: PackageName,
+    pub version: Option<Version>,
+    pub url: Option<String>,
+    pub problem: String,
+}
+
+impl fmt::Display for ReleaseIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.repository, self.package)?;
+        if let Some(version) = &self.version {
+            write!(f, " {}", version)?;
+        }
+        if let Some(url) = &self.url {
+            write!(f, " ({})", url)?;
+        }
+        write!(f, ": {}", self.problem)
+    }
+}
+
+// One of the options given when there's more than one candidate that the
+// resolver could equally well pick.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Choice {
+    pub version: Version,
+    pub source: String,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct AssertionFailure {
+    pub package: PackageName,
+    pub version: Version,
+    pub constraint: VersionConstraint,
+}
+
+impl fmt::Display for AssertionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} resolved to {}, which does not satisfy {}",
+            self.package, self.version, self.constraint
+        )
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Status {
+    pub requested: usize,
+    pub dependencies: usize,
+    pub repositories: Vec<RepositoryStatus>,
+    pub pkgdb: String,
+    pub state_version: u32,
 }
 
 pub(crate) type Packages = BTreeMap<PackageName, Package>;
@@ -88,6 +1136,31 @@ pub(crate) trait Source: fmt::Debug + fmt::Display + DynClone + Sync + Send {
     fn id(&self) -> u64;
 
     fn discriminator(&self) -> u64;
+
+    // Whether the release that this came from has been yanked, and why, if the
+    // repository said. Resolving skips yanked releases unless they're locked in.
+    fn yanked(&self) -> Option<Yanked> {
+        None
+    }
+
+    // The name of the repository that this came from, if it came from one.
+    fn repository(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Yanked {
+    pub(crate) reason: Option<String>,
+}
+
+impl fmt::Display for Yanked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.reason {
+            Some(reason) => write!(f, "yanked: {}", reason),
+            None => write!(f, "yanked"),
+        }
+    }
 }
 
 dyn_clone::clone_trait_object!(Source);
@@ -100,26 +1173,37 @@ pub(crate) trait WithSource {
 pub(crate) struct Package {
     name: PackageName,
     version: Version,
-    source: Box<dyn Source>,
+    candidate: Candidate,
 }
 
 impl Package {
-    pub(crate) fn new<P: Into<PackageName>, V: Into<Version>>(
-        name: P,
-        version: V,
-        source: Box<dyn Source>,
-    ) -> Package {
+    pub(crate) fn new<P: Into<PackageName>>(name: P, candidate: Candidate) -> Package {
         Package {
             name: name.into(),
-            version: version.into(),
-            source,
+            version: (&candidate).into(),
+            candidate,
         }
     }
+
+    pub(crate) fn version(&self) -> &Version {
+        &self.version
+    }
+
+    // The names of everything that the version we resolved to depends on.
+    pub(crate) fn dependency_names(&self) -> Vec<PackageName> {
+        self.candidate.dependency_names()
+    }
+
+    // Whether the version we resolved to satisfies constraint, by the same rules
+    // that resolving used to pick it.
+    pub(crate) fn satisfies(&self, constraint: &VersionConstraint) -> bool {
+        self.candidate.satisfies(constraint)
+    }
 }
 
 impl WithSource for Package {
     fn source(&self) -> &Box<dyn Source> {
-        &self.source
+        self.candidate.source()
     }
 }
 
@@ -134,3 +1218,543 @@ impl fmt::Display for Package {
         )
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct PackageState {
+    pub name: PackageName,
+    pub version: Version,
+    pub source: String,
+}
+
+impl From<&Package> for PackageState {
+    fn from(package: &Package) -> PackageState {
+        PackageState {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            source: package.source().to_string(),
+        }
+    }
+}
+
+pub type Resolution = BTreeMap<PackageName, PackageState>;
+
+pub(crate) fn resolution(packages: &Packages) -> Resolution {
+    packages
+        .iter()
+        .map(|(name, package)| (name.clone(), package.into()))
+        .collect()
+}
+
+// The outcome of installing on a best effort basis, which is everything that
+// did get installed, along with each package that couldn't be and why.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct PartialInstall {
+    pub resolution: Resolution,
+    pub failures: Vec<InstallFailure>,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct InstallFailure {
+    pub package: PackageSpecifier,
+    pub error: String,
+}
+
+impl fmt::Display for InstallFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.package.name, self.error)
+    }
+}
+
+// How far an upgrade reaches beyond the packages being upgraded. Eagerly, all
+// of their dependencies are upgraded too, otherwise a dependency is only
+// upgraded when the new versions of what depends on it need it to be.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum UpgradeStrategy {
+    #[default]
+    OnlyIfNeeded,
+    Eager,
+}
+
+// Something we resolve for, such as a platform or an engine version, named so
+// that results can be attributed to it, along with the values that dependency
+// markers are evaluated against for it.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Target {
+    pub name: String,
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+}
+
+// A package that was resolved for at least one target, and every target that
+// resolved to it.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct TargetedPackage {
+    pub package: PackageState,
+    pub targets: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct TargetFailure {
+    pub target: String,
+    pub error: String,
+}
+
+impl fmt::Display for TargetFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.target, self.error)
+    }
+}
+
+// The union of resolving the same requests for several targets. A package that
+// resolved to different versions for different targets has each of them listed,
+// and a target that couldn't be resolved is only reported, without stopping the
+// rest from being resolved.
+#[derive(Serialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct TargetResolution {
+    pub packages: BTreeMap<PackageName, Vec<TargetedPackage>>,
+    pub failures: Vec<TargetFailure>,
+}
+
+impl TargetResolution {
+    pub(crate) fn add(&mut self, target: &Target, resolution: Resolution) {
+        for (name, package) in resolution {
+            let packages = self.packages.entry(name).or_default();
+            match packages.iter_mut().find(|p| p.package == package) {
+                Some(existing) => existing.targets.push(target.name.clone()),
+                None => packages.push(TargetedPackage {
+                    package,
+                    targets: vec![target.name.clone()],
+                }),
+            }
+        }
+    }
+}
+
+// A request that would have to be loosened for resolving to succeed, along with
+// the version that resolving would pick for it once it was.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Relaxation {
+    pub package: PackageName,
+    pub from: VersionConstraint,
+    pub version: Version,
+}
+
+impl fmt::Display for Relaxation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "loosen {} from {} to allow {}",
+            self.package, self.from, self.version
+        )
+    }
+}
+
+// Why resolving failed, as a list of steps, each of which follows from facts
+// about our packages, or from the steps before it, until the last step, which
+// concludes that nothing can satisfy what was requested.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Explanation {
+    pub steps: Vec<ExplanationStep>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            writeln!(f, "({}) {}", i + 1, step)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ExplanationStep {
+    pub facts: Vec<ExplanationFact>,
+    // Earlier steps that this one follows from, by their index.
+    pub steps: Vec<usize>,
+    // Requirements that can't all be met at the same time, where none at all
+    // means that nothing can be resolved.
+    pub incompatible: Vec<ExplanationTerm>,
+}
+
+impl fmt::Display for ExplanationStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let because: Vec<String> = self
+            .facts
+            .iter()
+            .map(|fact| fact.to_string())
+            .chain(self.steps.iter().map(|i| format!("({})", i + 1)))
+            .collect();
+        write!(f, "Because {}, ", because.join(" and "))?;
+
+        match &self.incompatible[..] {
+            [] => write!(f, "nothing can satisfy the requested packages."),
+            [term] if term.package.is_none() && !term.negated => {
+                write!(f, "nothing can satisfy the requested packages.")
+            }
+            [term] if !term.negated => write!(f, "{} is forbidden.", term),
+            [term] => write!(f, "{} is required.", term.negate()),
+            [l, r] if l.negated != r.negated => {
+                let (positive, negative) = if l.negated { (r, l) } else { (l, r) };
+                write!(f, "{} requires {}.", positive, negative.negate())
+            }
+            terms => {
+                let terms: Vec<String> = terms.iter().map(|t| t.to_string()).collect();
+                write!(f, "{} are incompatible.", terms.join(" and "))
+            }
+        }
+    }
+}
+
+// Something that's known about our packages, that resolving has to work within.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExplanationFact {
+    Requested,
+    // Versions of None is any version at all.
+    NoVersions {
+        package: PackageName,
+        versions: Option<String>,
+    },
+    UnavailableDependencies {
+        package: PackageName,
+        versions: String,
+    },
+    // A package of None is what was requested, rather than any one package.
+    Requires {
+        package: Option<PackageName>,
+        versions: String,
+        dependency: PackageName,
+        constraint: String,
+    },
+}
+
+impl fmt::Display for ExplanationFact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExplanationFact::Requested => write!(f, "the requested packages have to be resolved"),
+            ExplanationFact::NoVersions {
+                package,
+                versions: None,
+            } => write!(f, "there are no versions of {}", package),
+            ExplanationFact::NoVersions {
+                package,
+                versions: Some(versions),
+            } => write!(
+                f,
+                "there are no versions of {} matching {}",
+                package, versions
+            ),
+            ExplanationFact::UnavailableDependencies { package, versions } => {
+                write!(
+                    f,
+                    "the dependencies of {} {} are unavailable",
+                    package, versions
+                )
+            }
+            ExplanationFact::Requires {
+                package: None,
+                dependency,
+                constraint,
+                ..
+            } => write!(
+                f,
+                "the requested packages require {} {}",
+                dependency, constraint
+            ),
+            ExplanationFact::Requires {
+                package: Some(package),
+                versions,
+                dependency,
+                constraint,
+            } => write!(
+                f,
+                "{} {} requires {} {}",
+                package, versions, dependency, constraint
+            ),
+        }
+    }
+}
+
+// A requirement for a version of package within versions, or with negated, for
+// one outside of them. A package of None is what was requested.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ExplanationTerm {
+    pub package: Option<PackageName>,
+    pub versions: String,
+    pub negated: bool,
+}
+
+impl ExplanationTerm {
+    fn negate(&self) -> ExplanationTerm {
+        ExplanationTerm {
+            negated: !self.negated,
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for ExplanationTerm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negated {
+            write!(f, "not ")?;
+        }
+        match &self.package {
+            Some(package) => write!(f, "{} {}", package, self.versions),
+            None => write!(f, "the requested packages"),
+        }
+    }
+}
+
+// A single committed operation, from our history, along with everything that
+// was requested and resolved once it had been committed, which is what rolling
+// back to it restores, and how that changed what was resolved before it.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct HistoryEntry {
+    // Entries from before we numbered them are numbered by their position.
+    #[serde(default)]
+    pub id: u64,
+    pub timestamp: SystemTime,
+    pub operation: String,
+    pub packages: Vec<PackageName>,
+    #[serde(default)]
+    pub requested: Vec<PackageSpecifier>,
+    pub resolved: Resolution,
+    #[serde(default)]
+    pub changes: ResolutionDiff,
+}
+
+// A package that more than one repository provides, with the versions that each
+// of them has, in priority order, and the repository that resolving prefers,
+// which is the one with the highest version, or the highest priority of those.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ShadowedPackage {
+    pub name: PackageName,
+    pub providers: Vec<Provider>,
+    pub preferred: String,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Provider {
+    pub repository: String,
+    pub versions: Vec<Version>,
+}
+
+// A bounded set of distinct solutions, and whether there were any more than that.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Solutions {
+    pub solutions: Vec<Resolution>,
+    pub more: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct PackageChange {
+    pub name: PackageName,
+    pub from: PackageState,
+    pub to: PackageState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct ResolutionDiff {
+    pub added: Vec<PackageState>,
+    pub removed: Vec<PackageState>,
+    pub upgraded: Vec<PackageChange>,
+    pub downgraded: Vec<PackageChange>,
+    pub moved: Vec<PackageChange>,
+}
+
+impl ResolutionDiff {
+    // Since Resolution is a BTreeMap, everything we produce here is going to be
+    // sorted by package name, which keeps our output stable for the same inputs.
+    pub fn new(before: &Resolution, after: &Resolution) -> ResolutionDiff {
+        let mut diff = ResolutionDiff::default();
+
+        for (name, old) in before.iter() {
+            match after.get(name) {
+                None => diff.removed.push(old.clone()),
+                Some(new) => {
+                    let change = PackageChange {
+                        name: name.clone(),
+                        from: old.clone(),
+                        to: new.clone(),
+                    };
+
+                    match old.version.cmp(&new.version) {
+                        Ordering::Less => diff.upgraded.push(change),
+                        Ordering::Greater => diff.downgraded.push(change),
+                        Ordering::Equal if old.source != new.source => diff.moved.push(change),
+                        Ordering::Equal => {}
+                    }
+                }
+            }
+        }
+
+        for (name, new) in after.iter() {
+            if !before.contains_key(name) {
+                diff.added.push(new.clone());
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.upgraded.is_empty()
+            && self.downgraded.is_empty()
+            && self.moved.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alternatives(specifier: &str) -> Vec<VersionReq> {
+        let specifier: PackageSpecifier = specifier.parse().unwrap();
+        specifier.version.alternatives().to_vec()
+    }
+
+    #[test]
+    fn interned_names_share_storage() {
+        let mut interner = Interner::new();
+        let first = interner.intern("foo");
+        let second = interner.intern("foo");
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &interner.intern("bar")));
+    }
+
+    #[test]
+    fn interner_sweeps_dead_names() {
+        let mut interner = Interner::new();
+        let alive = interner.intern("alive");
+        for idx in 0..MIN_INTERNER_SWEEP * 4 {
+            interner.intern(&format!("dead{}", idx));
+        }
+
+        // Only the names from after the last sweep can still be around.
+        assert!(interner.names.len() <= MIN_INTERNER_SWEEP);
+        assert!(Arc::ptr_eq(&alive, &interner.intern("alive")));
+    }
+
+    #[test]
+    fn partial_pin_major() {
+        assert_eq!(
+            alternatives("foo@1"),
+            vec![VersionReq::parse(">=1.0.0, <2.0.0").unwrap()]
+        );
+    }
+
+    #[test]
+    fn partial_pin_minor() {
+        assert_eq!(
+            alternatives("foo@1.2"),
+            vec![VersionReq::parse(">=1.2.0, <1.3.0").unwrap()]
+        );
+    }
+
+    #[test]
+    fn full_pin_is_exact() {
+        assert_eq!(
+            alternatives("foo@1.2.3"),
+            vec![VersionReq::parse("=1.2.3").unwrap()]
+        );
+    }
+
+    #[test]
+    fn double_equals_is_exact() {
+        assert_eq!(
+            alternatives("foo==1.2.3"),
+            vec![VersionReq::parse("=1.2.3").unwrap()]
+        );
+    }
+
+    #[test]
+    fn partial_pin_matches_latest_within() {
+        let specifier: PackageSpecifier = "foo@1".parse().unwrap();
+        assert!(specifier.version.matches(&Version::new(1, 9, 3)));
+        assert!(!specifier.version.matches(&Version::new(2, 0, 0)));
+
+        let specifier: PackageSpecifier = "foo@1.2".parse().unwrap();
+        assert!(specifier.version.matches(&Version::new(1, 2, 7)));
+        assert!(!specifier.version.matches(&Version::new(1, 3, 0)));
+    }
+
+    #[test]
+    fn stable_pin_never_matches_prereleases() {
+        let specifier: PackageSpecifier = "foo@stable".parse().unwrap();
+        assert_eq!(specifier.version, VersionConstraint::stable());
+        assert_eq!(specifier.version.to_string(), "stable");
+        assert!(specifier.version.matches(&Version::new(2, 0, 0)));
+        assert!(!specifier
+            .version
+            .matches(&Version::parse("2.0.0-beta.1").unwrap()));
+
+        let constraint: VersionConstraint = ">=1.0.0-alpha, stable".parse().unwrap();
+        assert!(constraint.is_stable());
+        assert!(!constraint.matches(&Version::parse("1.0.0-alpha").unwrap()));
+        assert_eq!(
+            constraint.to_string().parse::<VersionConstraint>().unwrap(),
+            constraint
+        );
+
+        let specifier: PackageSpecifier = "foo@latest".parse().unwrap();
+        assert!(!specifier.version.is_stable());
+    }
+
+    #[derive(Deserialize)]
+    struct Dependent {
+        #[serde(deserialize_with = "deserialize_dependencies")]
+        dependencies: HashMap<PackageName, VersionConstraint>,
+    }
+
+    #[derive(Deserialize)]
+    struct MarkedDependent {
+        #[serde(deserialize_with = "deserialize_marked_dependencies")]
+        dependencies: HashMap<PackageName, Vec<DependencyConstraint>>,
+    }
+
+    #[test]
+    fn marked_dependencies_keep_every_constraint() {
+        let dependent: MarkedDependent = serde_json::from_str(
+            r#"{"dependencies": [
+                {"name": "foo", "version": "^1", "marker": "os == 'linux'"},
+                {"name": "foo", "version": "^2", "marker": "os == 'windows'"},
+                "bar"
+            ]}"#,
+        )
+        .unwrap();
+
+        let foo = &dependent.dependencies[&PackageName::new("foo").unwrap()];
+        let described: Vec<String> = foo.iter().map(|c| c.to_string()).collect();
+        assert_eq!(described, vec!["^1; os == 'linux'", "^2; os == 'windows'"]);
+
+        let windows = HashMap::from([("os".to_string(), "windows".to_string())]);
+        assert!(!foo[0].applies(&windows));
+        assert!(foo[1].applies(&windows));
+    }
+
+    #[test]
+    fn unmarked_dependencies_reject_duplicates() {
+        let result: Result<Dependent, _> =
+            serde_json::from_str(r#"{"dependencies": [{"name": "foo", "version": "^1"}, "foo"]}"#);
+        let err = result.err().unwrap().to_string();
+        assert!(err.contains("foo is depended on more than once"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_markers_are_reported() {
+        let result: Result<MarkedDependent, _> = serde_json::from_str(
+            r#"{"dependencies": [{"name": "foo", "marker": "os == 'linux"}]}"#,
+        );
+        let err = result.err().unwrap().to_string();
+        assert!(err.contains("unterminated string in marker"), "{}", err);
+
+        let result: Result<Dependent, _> = serde_json::from_str(
+            r#"{"dependencies": [{"name": "foo", "marker": "os == 'linux'"}]}"#,
+        );
+        let err = result.err().unwrap().to_string();
+        assert!(err.contains("markers are not supported here"), "{}", err);
+    }
+}
@@ -0,0 +1,100 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::collections::HashMap;
+
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+// Counts how many nodes the aliases in a YAML document would expand to, once
+// every alias has been replaced by the node it refers to. serde_yaml expands
+// aliases as it deserializes, so a small document built out of nested aliases
+// (the "billion laughs" attack) can expand into something enormous, and we
+// want to be able to refuse those before we hand them to serde.
+//
+// This only scans the document, it doesn't build anything, so it's cheap even
+// when the expansion isn't. If the document can't be scanned we return 0, and
+// leave it to serde_yaml to report the error properly.
+pub(crate) fn alias_expansion(input: &str) -> u64 {
+    let mut counter = AliasCounter::default();
+    let mut parser = Parser::new(input.chars());
+
+    match parser.load(&mut counter, true) {
+        Ok(_) => counter.expanded,
+        Err(_) => 0,
+    }
+}
+
+#[derive(Default)]
+struct AliasCounter {
+    // The fully expanded size of each anchored node, by anchor id.
+    anchors: HashMap<usize, u64>,
+    // The anchor id and running size of each collection we're currently in.
+    stack: Vec<(usize, u64)>,
+    expanded: u64,
+}
+
+impl AliasCounter {
+    fn add(&mut self, size: u64) {
+        if let Some((_, count)) = self.stack.last_mut() {
+            *count = count.saturating_add(size);
+        }
+    }
+
+    fn finish(&mut self, anchor: usize, size: u64) {
+        if anchor > 0 {
+            self.anchors.insert(anchor, size);
+        }
+        self.add(size);
+    }
+}
+
+impl MarkedEventReceiver for AliasCounter {
+    fn on_event(&mut self, event: Event, _mark: Marker) {
+        match event {
+            Event::Scalar(_, _, anchor, _) => self.finish(anchor, 1),
+            Event::SequenceStart(anchor) | Event::MappingStart(anchor) => {
+                self.stack.push((anchor, 1))
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some((anchor, size)) = self.stack.pop() {
+                    self.finish(anchor, size);
+                }
+            }
+            Event::Alias(anchor) => {
+                let size = self.anchors.get(&anchor).copied().unwrap_or(1);
+                self.expanded = self.expanded.saturating_add(size);
+                self.add(size);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_aliases() {
+        assert_eq!(alias_expansion("a: [x, y]\nb: {c: d}\n"), 0);
+    }
+
+    #[test]
+    fn aliases_count_their_whole_node() {
+        assert_eq!(alias_expansion("a: &a [x, y]\nb: *a\n"), 3);
+        assert_eq!(alias_expansion("a: &a x\nb: *a\nc: *a\n"), 2);
+    }
+
+    #[test]
+    fn nested_aliases_multiply() {
+        let doc = "a: &a [x, x]\nb: &b [*a, *a]\nc: [*b, *b]\n";
+        assert_eq!(alias_expansion(doc), 20);
+    }
+
+    #[test]
+    fn invalid_documents_are_left_to_serde() {
+        assert_eq!(alias_expansion("a: [x, y"), 0);
+    }
+}